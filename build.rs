@@ -0,0 +1,21 @@
+// build.rs
+
+use std::process::Command;
+
+/// Embed the git commit for GET /api/version, when built from a checkout
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=APPMANAGER_GIT_HASH");
+    // Set by the caller (e.g. CI building from a tarball)
+    if std::env::var_os("APPMANAGER_GIT_HASH").is_some() {
+        return;
+    }
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output();
+    if let Ok(output) = output
+        && output.status.success()
+    {
+        let hash = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=APPMANAGER_GIT_HASH={}", hash.trim());
+    }
+}
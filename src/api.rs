@@ -1,17 +1,38 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json},
-    http::header,
+    extract::{
+        Path, Query, Request, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        Html, IntoResponse, Json, Redirect, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    http::{HeaderValue, Method, header},
     routing::{get, post, put},
     Router,
 };
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, atomic::Ordering},
+    time::Instant,
+};
 use tokio::sync::Mutex;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
 
-use crate::{manager::ServiceManager, service::{ServiceConfig, WindowsOptions}};
+use crate::{
+    auth::{self, Auth},
+    health::HealthReport,
+    secrets,
+    manager::{ManagerStats, ProcessInfo, ServiceError, ServiceManager, ServiceStatusSnapshot},
+    service::{CorsConfig, DesiredState, ServiceConfig, ServiceKind, WindowsOptions},
+};
 
 /// Constan source of Web
 /// Index pages
@@ -19,8 +40,14 @@ use crate::{manager::ServiceManager, service::{ServiceConfig, WindowsOptions}};
 /// Favicon
 const INDEX_HTML: &str = include_str!("../web/index.html");
 const ARIANG_HTML: &str = include_str!("../web/ariang.html");
+const LOGIN_HTML: &str = include_str!("../web/login.html");
 const FAVICON_SVG: &str = "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 24 24' fill='none' stroke='#2e7d32' stroke-width='2' stroke-linecap='round' stroke-linejoin='round'><rect x='2' y='2' width='20' height='8' rx='2' ry='2'></rect><rect x='2' y='14' width='20' height='8' rx='2' ry='2'></rect><line x1='6' y1='6' x2='6.01' y2='6'></line><line x1='6' y1='18' x2='6.01' y2='18'></line></svg>";
 
+/// Output lines returned by GET .../logs without ?tail
+const DEFAULT_LOG_TAIL: usize = 100;
+/// Password checks running at once, more logins wait so a flood can't take every core
+static LOGIN_SLOTS: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(2);
+
 pub type SharedManager = Arc<Mutex<ServiceManager>>;
 
 /// Service state structure
@@ -28,6 +55,17 @@ pub type SharedManager = Arc<Mutex<ServiceManager>>;
 pub struct AppState {
     pub manager: SharedManager,
     pub shutdown_tx: mpsc::Sender<()>,
+    // lock-free counts and start time for /api/health
+    pub stats: Arc<ManagerStats>,
+    pub started_at: Instant,
+    /// auth.token and logins, read at start
+    pub auth: Arc<Auth>,
+    /// Other web pages allowed to use the API, read at start
+    pub cors: Arc<CorsConfig>,
+    /// Served over HTTPS (tls)
+    pub https: bool,
+    /// The TCP listen address, None on a unix socket or named pipe
+    pub tcp_listen: Option<Arc<str>>,
 }
 
 /// Process yaml importe parsing
@@ -40,6 +78,9 @@ struct ImportRequest {
 #[derive(Serialize)]
 pub struct ApiResponse<T> {
     success: bool,
+    // machine readable error code, e.g. "not_found"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
     msg: Option<String>,
     data: Option<T>,
 }
@@ -51,15 +92,167 @@ pub struct ServiceDto {
     id: String,
     name: String,
     exec: String,
+    kind: ServiceKind,
     args: Vec<String>,
     working_dir: Option<String>,
     autorun: bool,
+    // exec runs through cmd /C or sh -c
+    shell: bool,
     env: Option<HashMap<String, String>>,
     windows: Option<WindowsOptions>,
     url: Option<String>,
+    // url with ${VAR} / %VAR% / {KEY} expanded, the link to open
+    url_resolved: Option<String>,
     // status values
     status: String,
     pid: Option<u32>,
+    // true when the process was found running, not started by AppManager
+    adopted: bool,
+    // stopped after a manual stop, skipped by keep-alive until started or resumed
+    desired_state: DesiredState,
+    // false: kept in the config but never started
+    enabled: bool,
+    // output can be followed at /logs/stream
+    capture_output: bool,
+    tags: Vec<String>,
+    group: Option<String>,
+    // how the last process ended (cleared on start)
+    // reason: exited (code 0), crashed, stopped (by AppManager) or killed (by AppManager's stop)
+    last_exit_code: Option<i32>,
+    last_signal: Option<i32>,
+    last_exit_reason: Option<&'static str>,
+    last_exit_time: Option<u64>,
+    // result of the health_check probes, None without one
+    health: Option<HealthReport>,
+    // resource usage while running: CPU in percent of one core, memory in bytes
+    // tree_*: the main process and all its descendants
+    cpu_percent: Option<f32>,
+    memory_rss: Option<u64>,
+    tree_cpu_percent: Option<f32>,
+    tree_memory_rss: Option<u64>,
+    process_count: Option<usize>,
+    // since the process started, None when not running
+    uptime_secs: Option<u64>,
+    // since AppManager started: starts after the first one, exits other than with code 0
+    restarts: u32,
+    crashes: u32,
+    // kind: task only: start of the last run (unix seconds), its duration once finished
+    last_run_at: Option<u64>,
+    last_run_duration_ms: Option<u64>,
+}
+
+impl ServiceDto {
+    /// Build from a status snapshot, masking secret env values
+    fn new(snapshot: &ServiceStatusSnapshot) -> Self {
+        let config = &snapshot.config;
+        Self {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            exec: config.exec.clone(),
+            kind: config.kind.unwrap_or_default(),
+            args: config.args.clone(),
+            env: config.masked_env(),
+            working_dir: config.working_dir.clone(),
+            windows: config.windows.clone(),
+            autorun: config.autorun.unwrap_or(false),
+            shell: config.shell.unwrap_or(false),
+            url: config.url.clone(),
+            url_resolved: config.resolved_url(),
+            status: match (snapshot.running, config.is_enabled()) {
+                // Up, but ready_when hasn't passed yet
+                (true, _) if !snapshot.ready => "Starting".into(),
+                (true, _) => "Running".into(),
+                (false, false) => "Disabled".into(),
+                // Died within startup_grace_secs, the start was reported as failed
+                (false, true) if snapshot.start_failed => "Failed".into(),
+                (false, true) => "Stopped".into(),
+            },
+            pid: snapshot.pid,
+            adopted: snapshot.adopted,
+            desired_state: config.desired_state.unwrap_or_default(),
+            enabled: config.is_enabled(),
+            capture_output: config.capture_output.unwrap_or(false),
+            tags: config.tags.clone().unwrap_or_default(),
+            group: config.group.clone(),
+            last_exit_code: snapshot.last_exit.and_then(|e| e.code),
+            last_signal: snapshot.last_exit.and_then(|e| e.signal),
+            last_exit_reason: snapshot.last_exit.map(|e| e.reason.as_str()),
+            last_exit_time: snapshot.last_exit.map(|e| e.at),
+            health: snapshot.health.clone(),
+            cpu_percent: snapshot.usage.map(|u| u.cpu_percent),
+            memory_rss: snapshot.usage.map(|u| u.memory_rss),
+            tree_cpu_percent: snapshot.usage.map(|u| u.tree_cpu_percent),
+            tree_memory_rss: snapshot.usage.map(|u| u.tree_memory_rss),
+            process_count: snapshot.usage.map(|u| u.processes),
+            uptime_secs: snapshot.uptime_secs,
+            restarts: snapshot.restarts,
+            crashes: snapshot.crashes,
+            last_run_at: snapshot.last_run.map(|r| r.started_at),
+            last_run_duration_ms: snapshot.last_run.and_then(|r| r.duration_ms),
+        }
+    }
+}
+
+/// Process of a service tree, main process first
+#[derive(Serialize)]
+struct ProcessDto {
+    pid: u32,
+    parent: Option<u32>,
+    name: String,
+    memory: u64,
+}
+impl From<ProcessInfo> for ProcessDto {
+    fn from(p: ProcessInfo) -> Self {
+        Self { pid: p.pid, parent: p.parent, name: p.name, memory: p.memory }
+    }
+}
+
+/// Result of start / restart
+#[derive(Serialize)]
+struct StartedDto {
+    status: &'static str,
+    pid: Option<u32>,
+}
+
+/// Outcome for one service of a group / bulk action
+#[derive(Serialize)]
+struct BulkResultDto {
+    id: String,
+    success: bool,
+    msg: Option<String>,
+}
+impl BulkResultDto {
+    fn list(results: Vec<(String, anyhow::Result<()>)>) -> Vec<Self> {
+        results
+            .into_iter()
+            .map(|(id, result)| Self { id, success: result.is_ok(), msg: result.err().map(|e| format!("{:#}", e)) })
+            .collect()
+    }
+}
+
+/// Outcome of an import that didn't save every service
+#[derive(Serialize)]
+struct ImportResultDto {
+    imported: usize,
+    failed: Vec<BulkResultDto>,
+}
+
+/// Health of the manager itself (not of the services)
+#[derive(Serialize)]
+struct HealthDto {
+    status: &'static str,
+    uptime_secs: u64,
+    services: usize,
+    running: usize,
+}
+
+/// Build of the running manager
+#[derive(Serialize)]
+struct VersionDto {
+    version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    git_hash: Option<&'static str>,
 }
 
 /// Keep alive config
@@ -68,10 +261,91 @@ struct GlobalConfigDto {
     keep_alive: u64,
 }
 
+/// Clone target
+#[derive(Deserialize)]
+struct CloneRequest {
+    id: String,
+    name: Option<String>,
+}
+
+/// Service to create from a template
+#[derive(Deserialize)]
+struct InstantiateRequest {
+    id: String,
+    #[serde(default)]
+    params: HashMap<String, String>,
+}
+
+/// Secret value to store, it is never sent back
+#[derive(Deserialize)]
+struct SecretRequest {
+    value: String,
+}
+
 /// Reorder structure
+/// With a tag, only the services carrying it are reordered
 #[derive(Deserialize)]
 struct ReorderRequest {
     ids: Vec<String>,
+    tag: Option<String>,
+}
+
+/// Only what changed since the token of the previous call
+#[derive(Serialize)]
+struct ChangesDto {
+    token: String,
+    full: bool,
+    services: Vec<ServiceDto>,
+    removed: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<String>,
+}
+
+/// Update options
+#[derive(Deserialize)]
+struct UpdateQuery {
+    /// Restart the service if it runs and a process-relevant field changed
+    restart: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct StopQuery {
+    /// Stop even if running services depend on it
+    force: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct BulkQuery {
+    /// Only services with this autorun setting
+    autorun: Option<bool>,
+    /// Only members of this group
+    group: Option<String>,
+    /// Stop even if running services outside the selection depend on them
+    force: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Delete even if the process is still running after the stop
+    force: Option<bool>,
+}
+
+/// Output options
+#[derive(Deserialize)]
+struct LogsQuery {
+    /// Last lines to return (default: 100)
+    tail: Option<usize>,
+    /// Keep streaming new lines as Server-Sent Events
+    follow: Option<bool>,
+}
+
+/// Service list filter
+#[derive(Deserialize)]
+struct ListQuery {
+    tag: Option<String>,
 }
 
 /// API response
@@ -79,44 +353,265 @@ struct ReorderRequest {
 fn resp_ok<T: Serialize>(data: T) -> Json<ApiResponse<T>> {
     Json(ApiResponse { 
         success: true, 
+        code: None,
         msg: None, 
         data: Some(data) 
     })
 }
-fn resp_err(msg: impl ToString) -> (StatusCode, Json<ApiResponse<()>>) {
+/// Status and code come from ServiceError, anything else is a 500
+fn resp_err(err: impl Into<anyhow::Error>) -> (StatusCode, Json<ApiResponse<()>>) {
+    let err = err.into();
+    let (status, code) = match err.downcast_ref::<ServiceError>() {
+        Some(e @ ServiceError::NotFound(_)) => (StatusCode::NOT_FOUND, e.code()),
+        Some(e @ (ServiceError::AlreadyExists(_) | ServiceError::Conflict(_))) => {
+            (StatusCode::CONFLICT, e.code())
+        }
+        Some(e @ ServiceError::Invalid(_)) => (StatusCode::BAD_REQUEST, e.code()),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+    };
     (
-        StatusCode::INTERNAL_SERVER_ERROR,
+        status,
         Json(ApiResponse { 
             success: false, 
-            msg: Some(msg.to_string()), 
+            code: Some(code),
+            msg: Some(format!("{:#}", err)), 
             data: None 
         }),
     )
 }
 
 /// Api router
+/// The /api/ routes need the token or a login when auth is set, login and the pages stay open
+/// Web pages of other origins can only use the /api/ routes as far as cors allows
 pub fn create_router(state: AppState) -> Router {
     Router::new()
-        .route("/", get(index_page))
-        .route("/favicon.svg", get(favicon_handler))
-        .route("/ariang", get(ariang_page)) 
         .route("/api/shutdown", post(shutdown_handler))
+        .route("/api/health", get(health_handler))
+        .route("/api/version", get(version_handler))
         .route("/api/config", get(get_config).post(update_config))
         .route("/api/services", get(list_services).post(add_service))
         .route("/api/services/reorder", post(reorder_services))
+        .route("/api/services/changes", get(service_changes))
         .route("/api/services/import", post(import_services))
+        .route("/api/services/start-all", post(start_all))
+        .route("/api/services/stop-all", post(stop_all))
+        .route("/api/services/restart-all", post(restart_all))
         .route("/api/services/{id}", put(update_service).delete(delete_service))
         .route("/api/services/{id}/start", post(start_service))
         .route("/api/services/{id}/stop", post(stop_service))
         .route("/api/services/{id}/restart", post(restart_service))
+        .route("/api/services/{id}/resume", post(resume_service))
+        .route("/api/services/{id}/enable", post(enable_service))
+        .route("/api/services/{id}/clone", post(clone_service))
         .route("/api/services/{id}/status", get(get_service_status)) 
+        .route("/api/services/{id}/processes", get(get_service_processes))
+        .route("/api/services/{id}/logs", get(get_service_logs))
+        .route("/api/services/{id}/logs/stream", get(stream_service_logs))
+        .route("/api/services/{id}/logs/ws", get(ws_service_logs))
+        .route("/api/groups/{name}/start", post(start_group))
+        .route("/api/groups/{name}/stop", post(stop_group))
+        .route("/api/groups/{name}/restart", post(restart_group))
+        .route("/api/templates/{name}/instantiate", post(instantiate_template))
+        .route("/api/secrets/{name}", put(set_secret).delete(delete_secret))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth))
+        .route("/api/login", post(login))
+        .route("/api/logout", post(logout))
+        .route_layer(middleware::from_fn_with_state(state.clone(), check_origin))
+        .route_layer(middleware::from_fn_with_state(state.clone(), check_host))
+        .route("/login", get(login_page))
+        .route("/", get(index_page))
+        .route("/favicon.svg", get(favicon_handler))
+        .route("/ariang", get(ariang_page))
         .with_state(state)
 }
 
+/// Reject requests without the auth.token or a login session
+/// The token comes as `Authorization: Bearer <token>` or ?token=<token> (EventSource and WebSocket can't set headers)
+async fn require_auth(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.auth.is_open() || session_user(&state.auth, request.headers()).is_some() {
+        return next.run(request).await;
+    }
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim().to_string());
+    let presented = bearer.or_else(|| {
+        Query::<HashMap<String, String>>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(mut query)| query.remove("token"))
+    });
+    if presented.is_some_and(|presented| state.auth.check_token(&presented)) {
+        return next.run(request).await;
+    }
+    // The web UI goes to the login page when there are users, else asks for the token
+    let (code, msg) = match state.auth.has_users() {
+        true => ("login_required", "Login required"),
+        false => ("unauthorized", "Missing or invalid API token"),
+    };
+    let body = ApiResponse::<()> { success: false, code: Some(code), msg: Some(msg.to_string()), data: None };
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response()
+}
+
+/// Reject requests that web pages of other origins make without cors allowing them
+/// CORS only hides the answer from such a page, simple POSTs and WebSockets still get through.
+/// Requests without an Origin (curl, scripts) are not from a web page
+async fn check_origin(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(origin) = request.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok())
+        && !is_same_origin(origin, &request)
+        && !state.cors.allows(origin, request.method().as_str())
+    {
+        warn!(origin, method = %request.method(), path = %request.uri().path(), "Rejected a request from another web page");
+        let msg = format!("Requests from {} are not allowed, see cors in the config", origin);
+        let body = ApiResponse::<()> { success: false, code: Some("forbidden_origin"), msg: Some(msg), data: None };
+        return (StatusCode::FORBIDDEN, Json(body)).into_response();
+    }
+    next.run(request).await
+}
+/// Without auth, reject requests for host names other than the listen address and loopback
+/// A web page can point its own domain at 127.0.0.1 (DNS rebinding), it is then same-origin to the browser
+async fn check_host(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(listen) = &state.tcp_listen
+        && state.auth.is_open()
+        && !request_host(&request).is_some_and(|host| is_allowed_host(host_name(host), host_name(listen)))
+    {
+        let host = request_host(&request).unwrap_or_default();
+        warn!(host, path = %request.uri().path(), "Rejected a request for another host name");
+        let msg = format!("Requests for host {} are not allowed without auth, see auth in the config", host);
+        let body = ApiResponse::<()> { success: false, code: Some("forbidden_host"), msg: Some(msg), data: None };
+        return (StatusCode::FORBIDDEN, Json(body)).into_response();
+    }
+    next.run(request).await
+}
+/// Host and port the request was sent to
+/// HTTP/2 has the host in the URI instead of a Host header
+fn request_host(request: &Request) -> Option<&str> {
+    match request.headers().get(header::HOST) {
+        Some(value) => value.to_str().ok(),
+        None => request.uri().authority().map(|authority| authority.as_str()),
+    }
+}
+/// "[::1]:3000" -> "::1", "localhost:3000" -> "localhost"
+fn host_name(authority: &str) -> &str {
+    match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => authority.rsplit_once(':').map_or(authority, |(host, _)| host),
+    }
+}
+/// localhost, a loopback address or the listen host
+/// Listening on all addresses, any IP address is the machine itself, only names can be rebound
+fn is_allowed_host(host: &str, listen_host: &str) -> bool {
+    let ip = host.parse::<std::net::IpAddr>().ok();
+    host.eq_ignore_ascii_case("localhost")
+        || host.eq_ignore_ascii_case(listen_host)
+        || ip.is_some_and(|ip| ip.is_loopback())
+        || (ip.is_some() && listen_host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_unspecified()))
+}
+/// Whether the Origin is the host the request was sent to, i.e. the web UI itself
+fn is_same_origin(origin: &str, request: &Request) -> bool {
+    let host = request_host(request);
+    host.is_some() && origin.split_once("://").map(|(_, host)| host) == host
+}
+
+/// Session id from the cookie
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(auth::SESSION_COOKIE)?.strip_prefix('='))
+}
+
+/// Logged in user of a request
+fn session_user(auth: &Auth, headers: &HeaderMap) -> Option<String> {
+    session_cookie(headers).and_then(|id| auth.session_user(id))
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// Handle: log in with user name and password, the session goes into an HttpOnly cookie
+async fn login(State(state): State<AppState>, Json(payload): Json<LoginRequest>) -> impl IntoResponse {
+    let auth = state.auth.clone();
+    let username = payload.username.clone();
+    // PBKDF2 takes a while, keep it off the runtime thread
+    let slot = LOGIN_SLOTS.acquire().await.expect("LOGIN_SLOTS is never closed");
+    let session = tokio::task::spawn_blocking(move || auth.login(&payload.username, &payload.password))
+        .await
+        .ok()
+        .flatten();
+    drop(slot);
+    let Some(session) = session else {
+        warn!(user = %username, event = "login_failed", "Failed login");
+        // Slows down guessing
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let body = ApiResponse::<()> {
+            success: false,
+            code: Some("invalid_login"),
+            msg: Some("Wrong user name or password".to_string()),
+            data: None,
+        };
+        return (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+    };
+    info!(user = %username, event = "login", "Logged in");
+    let cookie = set_session_cookie(&state, &session, state.auth.session_ttl().as_secs());
+    ([(header::SET_COOKIE, cookie)], resp_ok("Logged in")).into_response()
+}
+
+/// Handle: end the session and drop the cookie
+async fn logout(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(id) = session_cookie(&headers) {
+        state.auth.logout(id);
+    }
+    ([(header::SET_COOKIE, set_session_cookie(&state, "", 0))], resp_ok("Logged out"))
+}
+
+/// Set-Cookie value for the session, Secure over HTTPS so it never goes out in plain text
+fn set_session_cookie(state: &AppState, session: &str, max_age: u64) -> String {
+    let secure = if state.https { "; Secure" } else { "" };
+    format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}", auth::SESSION_COOKIE, session, max_age, secure)
+}
+
+/// CORS policy
+/// Only the configured origins ("*" for any) and methods, same origin only when there are none
+pub fn cors_layer(cors: &CorsConfig) -> anyhow::Result<CorsLayer> {
+    let origins = cors.origins.as_deref().unwrap_or_default();
+    let allow_origin = match origins.iter().any(|o| o == "*") {
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(
+            origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o).with_context(|| format!("Invalid CORS origin '{}'", o)))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        ),
+    };
+    let methods = cors
+        .allowed_methods()
+        .iter()
+        .map(|m| Method::from_bytes(m.as_bytes()).with_context(|| format!("Invalid CORS method '{}'", m)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]))
+}
+
 /// Embed static resource
-/// Index page
-async fn index_page() -> impl IntoResponse {
-    Html(INDEX_HTML)
+/// Index page, the login form first when there are users and no session
+async fn index_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if state.auth.has_users() && session_user(&state.auth, &headers).is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    Html(INDEX_HTML).into_response()
+}
+/// Login page
+async fn login_page() -> impl IntoResponse {
+    Html(LOGIN_HTML)
 }
 /// Aria2 NG page
 async fn ariang_page() -> impl IntoResponse {
@@ -138,24 +633,66 @@ async fn shutdown_handler(
     let _ = state.shutdown_tx.try_send(());
     resp_ok("Server is shutting down...")
 }
+/// Handle: manager health
+/// Cheap: no manager lock and no process refresh, counts are from the last status check
+async fn health_handler(
+    State(state): State<AppState>
+) -> impl IntoResponse {
+    resp_ok(HealthDto {
+        status: "ok",
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        services: state.stats.services.load(Ordering::Relaxed),
+        running: state.stats.running.load(Ordering::Relaxed),
+    })
+}
+/// Handle: build version, for frontend/backend compatibility checks
+async fn version_handler() -> impl IntoResponse {
+    resp_ok(VersionDto {
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        git_hash: option_env!("APPMANAGER_GIT_HASH"),
+    })
+}
 /// Handle: start
 async fn start_service(
     State(state): State<AppState>, 
     Path(id): Path<String>
 ) -> impl IntoResponse {
-    let mut mgr = state.manager.lock().await;
-    match mgr.start(&id).await {
-        Ok(_) => resp_ok("Started").into_response(),
+    // Starting by hand lifts a manual stop
+    if let Err(e) = state.manager.lock().await.set_desired_state(&id, DesiredState::Running) {
+        return resp_err(e).into_response();
+    }
+    match ServiceManager::start_shared(&state.manager, &id).await {
+        Ok(pid) => resp_ok(StartedDto { status: "Started", pid }).into_response(),
         Err(e) => resp_err(e).into_response(),
     }
 }
 /// Handle: stop
 async fn stop_service(
     State(state): State<AppState>, 
-    Path(id): Path<String>
+    Path(id): Path<String>,
+    Query(query): Query<StopQuery>,
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
-    match mgr.stop(&id).await {
+    // Don't pull the rug from under running dependents
+    if !query.force.unwrap_or(false) {
+        let dependents = mgr.running_dependents(&id);
+        if !dependents.is_empty() {
+            let msg = format!(
+                "Service '{}' is needed by running service(s): {}, pass force=true to stop it anyway",
+                id,
+                dependents.join(", ")
+            );
+            return resp_err(ServiceError::Conflict(msg)).into_response();
+        }
+    }
+    // Manual stop: keep-alive must not bring it back, also not while it goes down
+    if let Err(e) = mgr.set_desired_state(&id, DesiredState::Stopped) {
+        return resp_err(e).into_response();
+    }
+    drop(mgr);
+    match ServiceManager::stop_shared(&state.manager, &id).await {
         Ok(_) => resp_ok("Stopped").into_response(),
         Err(e) => resp_err(e).into_response(),
     }
@@ -164,10 +701,132 @@ async fn stop_service(
 async fn restart_service(
     State(state): State<AppState>, 
     Path(id): Path<String>
+) -> impl IntoResponse {
+    if let Err(e) = state.manager.lock().await.set_desired_state(&id, DesiredState::Running) {
+        return resp_err(e).into_response();
+    }
+    match ServiceManager::restart_shared(&state.manager, &id).await {
+        Ok(pid) => resp_ok(StartedDto { status: "Restarted", pid }).into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Start services like the start button: manual stops are lifted, dependencies start first
+async fn bulk_start(manager: &Mutex<ServiceManager>, ids: &[String]) -> Vec<(String, anyhow::Result<()>)> {
+    let mut results = Vec::new();
+    let mut startable = Vec::new();
+    let mut mgr = manager.lock().await;
+    for id in ids {
+        match mgr.set_desired_state(id, DesiredState::Running) {
+            Ok(()) => startable.push(id.clone()),
+            Err(e) => results.push((id.clone(), Err(e))),
+        }
+    }
+    drop(mgr);
+    results.extend(ServiceManager::start_many_shared(manager, &startable).await);
+    results
+}
+/// Stop services like the stop button: marked as manually stopped, then stopped dependents first
+/// Without force, a service still needed by a running service outside `ids` is left running
+async fn bulk_stop(manager: &Mutex<ServiceManager>, ids: &[String], force: bool) -> Vec<(String, anyhow::Result<()>)> {
+    let mut results = Vec::new();
+    let mut stoppable = Vec::new();
+    let mut mgr = manager.lock().await;
+    for id in ids {
+        let outside: Vec<String> = match force {
+            true => Vec::new(),
+            false => mgr.running_dependents(id).into_iter().filter(|d| !ids.contains(d)).collect(),
+        };
+        if outside.is_empty() {
+            match mgr.set_desired_state(id, DesiredState::Stopped) {
+                Ok(()) => stoppable.push(id.clone()),
+                Err(e) => results.push((id.clone(), Err(e))),
+            }
+        } else {
+            let msg = format!("Service '{}' is needed by running service(s): {}", id, outside.join(", "));
+            results.push((id.clone(), Err(ServiceError::Conflict(msg).into())));
+        }
+    }
+    drop(mgr);
+    results.extend(ServiceManager::stop_many_shared(manager, &stoppable).await);
+    results
+}
+/// Stop, then start again in dependency order
+async fn bulk_restart(manager: &Mutex<ServiceManager>, ids: &[String]) -> Vec<(String, anyhow::Result<()>)> {
+    let mut results = Vec::new();
+    let mut stopped = Vec::new();
+    for (id, result) in ServiceManager::stop_many_shared(manager, ids).await {
+        match result {
+            Ok(()) => stopped.push(id),
+            Err(e) => results.push((id, Err(e))),
+        }
+    }
+    results.extend(bulk_start(manager, &stopped).await);
+    results
+}
+/// Handle: start every member of a group
+async fn start_group(State(state): State<AppState>, Path(name): Path<String>) -> impl IntoResponse {
+    let members = state.manager.lock().await.group_members(&name);
+    match members {
+        Ok(ids) => resp_ok(BulkResultDto::list(bulk_start(&state.manager, &ids).await)).into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: stop every member of a group
+async fn stop_group(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<StopQuery>,
+) -> impl IntoResponse {
+    let members = state.manager.lock().await.group_members(&name);
+    let force = query.force.unwrap_or(false);
+    match members {
+        Ok(ids) => resp_ok(BulkResultDto::list(bulk_stop(&state.manager, &ids, force).await)).into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: restart every member of a group
+async fn restart_group(State(state): State<AppState>, Path(name): Path<String>) -> impl IntoResponse {
+    let members = state.manager.lock().await.group_members(&name);
+    match members {
+        Ok(ids) => resp_ok(BulkResultDto::list(bulk_restart(&state.manager, &ids).await)).into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: start all services (or those matching the query), one result per service
+async fn start_all(State(state): State<AppState>, Query(query): Query<BulkQuery>) -> impl IntoResponse {
+    let ids = state.manager.lock().await.select(query.autorun, query.group.as_deref());
+    resp_ok(BulkResultDto::list(bulk_start(&state.manager, &ids).await)).into_response()
+}
+/// Handle: stop all services (or those matching the query), one result per service
+async fn stop_all(State(state): State<AppState>, Query(query): Query<BulkQuery>) -> impl IntoResponse {
+    let ids = state.manager.lock().await.select(query.autorun, query.group.as_deref());
+    let force = query.force.unwrap_or(false);
+    resp_ok(BulkResultDto::list(bulk_stop(&state.manager, &ids, force).await)).into_response()
+}
+/// Handle: restart all services (or those matching the query), one result per service
+async fn restart_all(State(state): State<AppState>, Query(query): Query<BulkQuery>) -> impl IntoResponse {
+    let ids = state.manager.lock().await.select(query.autorun, query.group.as_deref());
+    resp_ok(BulkResultDto::list(bulk_restart(&state.manager, &ids).await)).into_response()
+}
+/// Handle: lift a manual stop without starting, keep-alive and autorun take the service back
+async fn resume_service(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
-    match mgr.restart(&id).await {
-        Ok(_) => resp_ok("Restarted").into_response(),
+    match mgr.set_desired_state(&id, DesiredState::Running) {
+        Ok(_) => resp_ok("Resumed").into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: set enabled back to true without starting
+async fn enable_service(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let mut mgr = state.manager.lock().await;
+    match mgr.set_enabled(&id, true) {
+        Ok(_) => resp_ok("Enabled").into_response(),
         Err(e) => resp_err(e).into_response(),
     }
 }
@@ -177,60 +836,146 @@ async fn get_service_status(
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
+    match mgr.snapshot(&id) {
+        Some(snapshot) => resp_ok(ServiceDto::new(&snapshot)).into_response(),
+        None => resp_err(ServiceError::NotFound(id)).into_response(),
+    }
+}
 
-    // 1. check if service existing
-    if !mgr.services.contains_key(&id) {
-        return resp_err("msg").into_response();
-    }
-    let is_running = mgr.is_running(&id);
-    if let Some(svc) = mgr.services.get(&id) {
-        let dto = ServiceDto {
-            id: svc.config.id.clone(),
-            name: svc.config.name.clone(),
-            exec: svc.config.exec.clone(),
-            args: svc.config.args.clone(),
-            env: svc.config.env.clone(),
-            working_dir: svc.config.working_dir.clone(),
-            windows: svc.config.windows.clone(),
-            autorun: svc.config.autorun.unwrap_or(false),
-            url: svc.config.url.clone(),
-            status: if is_running { "Running".into() } else { "Stopped".into() },
-            pid: svc.last_known_pid,
-        };
+/// Handle: main process and descendants of a service
+async fn get_service_processes(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let mut mgr = state.manager.lock().await;
+    match mgr.process_tree(&id) {
+        Ok(tree) => {
+            let dtos: Vec<ProcessDto> = tree.into_iter().map(ProcessDto::from).collect();
+            resp_ok(dtos).into_response()
+        }
+        Err(e) => resp_err(e).into_response(),
+    }
+}
 
-        resp_ok(dto).into_response()
+/// Handle: last lines of a service's output, streamed on with follow=true
+async fn get_service_logs(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> impl IntoResponse {
+    let (lines, rx) = match state.manager.lock().await.output(&id) {
+        Ok(output) => output.tail(query.tail.unwrap_or(DEFAULT_LOG_TAIL)),
+        Err(e) => return resp_err(e).into_response(),
+    };
+    if query.follow.unwrap_or(false) {
+        log_events(lines, rx).into_response()
     } else {
-        resp_err("Service missing").into_response()
+        resp_ok(lines).into_response()
+    }
+}
+/// Handle: live output of a service as Server-Sent Events
+async fn stream_service_logs(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.manager.lock().await.subscribe_output(&id) {
+        Ok(rx) => log_events(Vec::new(), rx).into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: output of a service over WebSocket, as it is produced
+/// Every connection has its own receiver, so any number of tabs can follow the same service
+/// Messages are JSON: {"line": "..."} or {"lagged": <skipped lines>}
+async fn ws_service_logs(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> impl IntoResponse {
+    let (lines, rx) = match state.manager.lock().await.output(&id) {
+        Ok(output) => output.tail(query.tail.unwrap_or(0)),
+        Err(e) => return resp_err(e).into_response(),
+    };
+    ws.on_upgrade(move |socket| forward_logs(socket, lines, rx)).into_response()
+}
+async fn forward_logs(mut socket: WebSocket, lines: Vec<String>, mut rx: broadcast::Receiver<String>) {
+    let text = |value: serde_json::Value| Message::Text(value.to_string().into());
+    for line in lines {
+        if socket.send(text(serde_json::json!({ "line": line }))).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        let message = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(line) => text(serde_json::json!({ "line": line })),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => text(serde_json::json!({ "lagged": skipped })),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Client messages are ignored, a close or error ends the stream
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket.send(message).await.is_err() {
+            return;
+        }
     }
+    let _ = socket.send(Message::Close(None)).await;
 }
+/// Output lines as Server-Sent Events: the given ones, then live ones
+/// A slow client skips lines and gets a "lagged" event with the count
+fn log_events(lines: Vec<String>, rx: broadcast::Receiver<String>) -> impl IntoResponse {
+    use futures_util::StreamExt;
 
+    let backlog = futures_util::stream::iter(lines).map(|line| Ok::<_, Infallible>(Event::default().data(line)));
+    // The receiver is dropped together with the stream when the client goes away
+    let live = futures_util::stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(line) => Event::default().data(line),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                Event::default().event("lagged").data(skipped.to_string())
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, Infallible>(event), rx))
+    });
+    Sse::new(backlog.chain(live)).keep_alive(KeepAlive::default())
+}
 
 /// Handle: list all services
 async fn list_services(
-    State(state): State<AppState>
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
     
     let snapshots = mgr.list();
 
-    let dtos: Vec<ServiceDto> = snapshots.into_iter().map(|s| {
-        ServiceDto {
-            id: s.config.id,
-            name: s.config.name,
-            exec: s.config.exec,
-            args: s.config.args,
-            env: s.config.env,
-            working_dir: s.config.working_dir,
-            windows: s.config.windows,
-            autorun: s.config.autorun.unwrap_or(false),
-            url: s.config.url,
-            status: if s.running { "Running".into() } else { "Stopped".into() },
-            pid: s.pid,
-        }
-    }).collect();
+    let dtos: Vec<ServiceDto> = snapshots
+        .iter()
+        .filter(|s| query.tag.as_deref().is_none_or(|tag| s.config.has_tag(tag)))
+        .map(ServiceDto::new)
+        .collect();
 
     resp_ok(dtos).into_response()
 }
+/// Handle: services changed since a token
+async fn service_changes(
+    State(state): State<AppState>,
+    Query(query): Query<ChangesQuery>,
+) -> impl IntoResponse {
+    let mut mgr = state.manager.lock().await;
+    let changes = mgr.changes(query.since.as_deref());
+    resp_ok(ChangesDto {
+        token: changes.token,
+        full: changes.full,
+        services: changes.changed.iter().map(ServiceDto::new).collect(),
+        removed: changes.removed,
+    })
+    .into_response()
+}
 /// Handle: add serive
 async fn add_service(
     State(state): State<AppState>,
@@ -238,7 +983,7 @@ async fn add_service(
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
     if mgr.services.contains_key(&payload.id) {
-        return resp_err("Service ID already exists").into_response();
+        return resp_err(ServiceError::AlreadyExists(payload.id)).into_response();
     }
 
     match mgr.upsert_service(payload) {
@@ -250,14 +995,86 @@ async fn add_service(
 async fn update_service(
     State(state): State<AppState>,
     Path(id): Path<String>,
-    Json(mut payload): Json<ServiceConfig>,
+    Query(query): Query<UpdateQuery>,
+    Json(changes): Json<serde_json::Map<String, serde_json::Value>>,
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
 
-    payload.id = id;
+    let payload = match merge_update(mgr.services.get(&id).map(|svc| &svc.config), changes) {
+        Ok(payload) => ServiceConfig { id, ..payload },
+        Err(e) => return resp_err(e).into_response(),
+    };
+    let id = payload.id.clone();
+    let relaunch = query.restart.unwrap_or(false)
+        && mgr.services.get(&id).is_some_and(|svc| svc.config.process_differs(&payload));
 
-    match mgr.upsert_service(payload) {
-        Ok(_) => resp_ok("Service updated").into_response(),
+    if let Err(e) = mgr.upsert_service(payload) {
+        return resp_err(e).into_response();
+    }
+    // Only a running process needs to pick up the change
+    if relaunch && mgr.is_running(&id) {
+        drop(mgr);
+        return match ServiceManager::restart_shared(&state.manager, &id).await {
+            Ok(_) => resp_ok("Service updated and restarted").into_response(),
+            Err(e) => resp_err(e.context("Service updated, restart failed")).into_response(),
+        };
+    }
+    resp_ok("Service updated").into_response()
+}
+/// The stored config with the keys of an update on top: an absent key keeps its value, null clears it
+/// The web UI only sends what its editor shows, everything else stays as it was
+fn merge_update(
+    stored: Option<&ServiceConfig>,
+    changes: serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<ServiceConfig> {
+    let mut merged = match stored.map(serde_json::to_value).transpose()? {
+        Some(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    // cmd stays only while exec and args are the ones it gave
+    let command_changed = ["exec", "args"]
+        .iter()
+        .any(|key| changes.get(*key).is_some_and(|value| merged.get(*key) != Some(value)));
+    if command_changed && !changes.contains_key("cmd") {
+        merged.remove("cmd");
+    }
+    for (key, value) in changes {
+        match value {
+            serde_json::Value::Null => merged.remove(&key),
+            value => merged.insert(key, value),
+        };
+    }
+    let mut config: ServiceConfig = serde_json::from_value(serde_json::Value::Object(merged))
+        .map_err(|e| ServiceError::Invalid(format!("Invalid service config: {}", e)))?;
+    if let Some(stored) = stored {
+        // Secret values come back masked from the UI, keep the stored ones
+        config.restore_masked_env(stored);
+        // Not part of the API, compared by process_differs
+        config.inherited_env = stored.inherited_env.clone();
+    }
+    Ok(config)
+}
+/// Handle: clone service under a new id
+async fn clone_service(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<CloneRequest>,
+) -> impl IntoResponse {
+    let mut mgr = state.manager.lock().await;
+    match mgr.clone_service(&id, &payload.id, payload.name) {
+        Ok(_) => resp_ok("Service cloned").into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: add a service made from a template
+async fn instantiate_template(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(payload): Json<InstantiateRequest>,
+) -> impl IntoResponse {
+    let mut mgr = state.manager.lock().await;
+    match mgr.instantiate_template(&name, &payload.id, &payload.params) {
+        Ok(_) => resp_ok("Service created").into_response(),
         Err(e) => resp_err(e).into_response(),
     }
 }
@@ -265,9 +1082,9 @@ async fn update_service(
 async fn delete_service(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    Query(query): Query<DeleteQuery>,
 ) -> impl IntoResponse {
-    let mut mgr = state.manager.lock().await;
-    match mgr.remove_service(&id).await {
+    match ServiceManager::remove_service_shared(&state.manager, &id, query.force.unwrap_or(false)).await {
         Ok(_) => resp_ok("Service deleted").into_response(),
         Err(e) => resp_err(e).into_response(),
     }
@@ -280,24 +1097,55 @@ async fn import_services(
     let mut mgr = state.manager.lock().await;
     let yaml_str = payload.yaml.trim();
 
-    let configs: Vec<ServiceConfig> = match serde_yaml::from_str(yaml_str) {
+    let mut doc: serde_yaml::Value = match serde_yaml::from_str(yaml_str) {
+        Ok(doc) => doc,
+        Err(e) => return resp_err(ServiceError::Invalid(format!("Parse YAML failed: {}", e))).into_response(),
+    };
+    crate::service::untag_secrets(&mut doc);
+    let configs: Vec<ServiceConfig> = match serde_yaml::from_value(doc.clone()) {
         Ok(list) => list,
         Err(_) => {
-            match serde_yaml::from_str::<ServiceConfig>(yaml_str) {
+            match serde_yaml::from_value::<ServiceConfig>(doc) {
                 Ok(single) => vec![single],
-                Err(e) => return resp_err(format!("Parse YAML failed: {}", e)).into_response(),
+                Err(e) => {
+                    return resp_err(ServiceError::Invalid(format!("Parse YAML failed: {}", e)))
+                        .into_response();
+                }
             }
         }
     };
-    let mut count = 0;
-    for config in configs {
-        if let Err(e) = mgr.upsert_service(config) {
-            let _ = resp_err(format!("Save service failed: {}", e)).into_response();
-        }
-        count += 1;
+    let results: Vec<(String, anyhow::Result<()>)> = configs
+        .into_iter()
+        .map(|config| (config.id.clone(), mgr.upsert_service(config).context("Save service failed")))
+        .collect();
+    let saved = results.iter().filter(|(_, result)| result.is_ok()).count();
+    if saved == results.len() {
+        return resp_ok(format!("Success import {} services", saved)).into_response();
+    }
+    let total = results.len();
+    let failed: Vec<BulkResultDto> = BulkResultDto::list(results).into_iter().filter(|r| !r.success).collect();
+    let reasons: Vec<String> = failed.iter().map(|r| format!("{}: {}", r.id, r.msg.as_deref().unwrap_or_default())).collect();
+    let msg = format!("Imported {} of {} services, failed: {}", saved, total, reasons.join("; "));
+    // The others are saved: 207, only an import that saved nothing is a bad request
+    let (status, code) = if saved > 0 { (StatusCode::MULTI_STATUS, None) } else { (StatusCode::BAD_REQUEST, Some("invalid")) };
+    let body = ApiResponse { success: false, code, msg: Some(msg), data: Some(ImportResultDto { imported: saved, failed }) };
+    (status, Json(body)).into_response()
+}
+/// Handle: store a secret referenced as `!secret name` (the credential store may block)
+async fn set_secret(Path(name): Path<String>, Json(payload): Json<SecretRequest>) -> impl IntoResponse {
+    let result = tokio::task::spawn_blocking(move || secrets::set(&name, &payload.value)).await;
+    match result.map_err(anyhow::Error::from).and_then(|r| r) {
+        Ok(_) => resp_ok("Secret stored").into_response(),
+        Err(e) => resp_err(e).into_response(),
+    }
+}
+/// Handle: delete a secret
+async fn delete_secret(Path(name): Path<String>) -> impl IntoResponse {
+    let result = tokio::task::spawn_blocking(move || secrets::delete(&name)).await;
+    match result.map_err(anyhow::Error::from).and_then(|r| r) {
+        Ok(_) => resp_ok("Secret deleted").into_response(),
+        Err(e) => resp_err(e).into_response(),
     }
-
-    resp_ok(format!("Success import {} services", count)).into_response()
 }
 /// Handle: get keep alive interval
 async fn get_config(
@@ -315,7 +1163,7 @@ async fn update_config(
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
     match mgr.set_global_config(payload.keep_alive) {
-        Ok(_) => resp_ok("Config updated. Restart required to apply change to Keep-Alive loop").into_response(),
+        Ok(_) => resp_ok("Config updated, the Keep-Alive loop picks up the new interval within a second").into_response(),
         Err(e) => resp_err(e).into_response()
     }
 }
@@ -325,8 +1173,137 @@ async fn reorder_services(
     Json(payload): Json<ReorderRequest>
 ) -> impl IntoResponse {
     let mut mgr = state.manager.lock().await;
-    match mgr.reorder_services(payload.ids) {
+    match mgr.reorder_services(payload.ids, payload.tag.as_deref()) {
         Ok(_) => resp_ok("Order saved").into_response(),
         Err(e) => resp_err(e).into_response()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServicesFile;
+
+    fn state(services: serde_json::Value) -> AppState {
+        let file = ServicesFile { services: serde_json::from_value(services).unwrap(), ..Default::default() };
+        let manager = ServiceManager::from_config(file, None).unwrap();
+        AppState {
+            stats: manager.stats.clone(),
+            manager: Arc::new(Mutex::new(manager)),
+            shutdown_tx: mpsc::channel(1).0,
+            started_at: Instant::now(),
+            auth: Arc::default(),
+            cors: Arc::default(),
+            https: false,
+            tcp_listen: Some("127.0.0.1:3000".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn editing_in_the_ui_keeps_the_fields_it_does_not_show() {
+        let state = state(serde_json::json!([{
+            "id": "web", "name": "web", "exec": "web", "args": [],
+            "startup_grace_secs": 5, "strict_env": true, "aggressive_kill": true, "port": 8080,
+            "pid_file": "web.pid",
+        }]));
+        // What the editor sends
+        let edit = serde_json::json!({
+            "id": "web", "name": "Web", "exec": "web2", "working_dir": null, "url": null,
+            "autorun": false, "enabled": true, "shell": false, "args": ["-v"], "env": null,
+            "windows": { "creation_flags": 0 },
+        });
+        let response = update_service(
+            State(state.clone()),
+            Path("web".to_string()),
+            Query(UpdateQuery { restart: None }),
+            Json(serde_json::from_value(edit).unwrap()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mgr = state.manager.lock().await;
+        let config = &mgr.services["web"].config;
+        assert_eq!((config.name.as_str(), config.exec.as_str()), ("Web", "web2"));
+        assert_eq!(config.startup_grace_secs, Some(5));
+        assert_eq!(config.strict_env, Some(true));
+        assert_eq!(config.aggressive_kill, Some(true));
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.pid_file.as_deref(), Some("web.pid"));
+        drop(mgr);
+
+        // null clears a field
+        let clear = serde_json::json!({ "pid_file": null });
+        let response = update_service(
+            State(state.clone()),
+            Path("web".to_string()),
+            Query(UpdateQuery { restart: None }),
+            Json(serde_json::from_value(clear).unwrap()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mgr = state.manager.lock().await;
+        assert_eq!(mgr.services["web"].config.pid_file, None);
+        assert_eq!(mgr.services["web"].config.exec, "web2");
+    }
+
+    #[test]
+    fn cmd_is_kept_until_exec_or_args_change() {
+        let mut stored: ServiceConfig =
+            serde_json::from_value(serde_json::json!({"id": "a", "name": "a", "cmd": "app --port 1"})).unwrap();
+        stored.apply_cmd().unwrap();
+        let changes = |value: serde_json::Value| serde_json::from_value(value).unwrap();
+        let renamed = merge_update(Some(&stored), changes(serde_json::json!({"name": "b", "exec": "app"}))).unwrap();
+        assert_eq!(renamed.cmd.as_deref(), Some("app --port 1"));
+        let edited = merge_update(Some(&stored), changes(serde_json::json!({"args": ["--port", "2"]}))).unwrap();
+        assert_eq!((edited.cmd, edited.args), (None, vec!["--port".to_string(), "2".to_string()]));
+        assert!(merge_update(Some(&stored), changes(serde_json::json!({"name": null}))).is_err());
+    }
+
+    #[tokio::test]
+    async fn import_reports_the_configs_it_could_not_save() {
+        let state = state(serde_json::json!([]));
+        let import = |yaml: &str| {
+            let state = state.clone();
+            let yaml = yaml.to_string();
+            async move {
+                let response = import_services(State(state), Json(ImportRequest { yaml })).await.into_response();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+        let (status, body) = import("- {id: a, name: a, exec: app}\n- {id: b, name: b, exec: app, schedule: {start: 'not cron'}}\n").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body["msg"].as_str().unwrap().starts_with("Imported 1 of 2 services, failed: b: "), "{body}");
+        assert_eq!(body["data"]["imported"], 1);
+        assert_eq!(body["data"]["failed"][0]["id"], "b");
+        assert!(state.manager.lock().await.services.contains_key("a"));
+
+        let (status, body) = import("{id: c, name: c, exec: app, schedule: {start: 'not cron'}}").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!((&body["code"], &body["data"]["imported"]), (&"invalid".into(), &0.into()));
+    }
+
+    #[test]
+    fn session_cookie_is_secure_over_https() {
+        let mut state = state(serde_json::json!([]));
+        assert!(!set_session_cookie(&state, "abc", 60).contains("Secure"));
+        state.https = true;
+        assert!(set_session_cookie(&state, "abc", 60).ends_with("; Max-Age=60; Secure"));
+    }
+
+    #[test]
+    fn only_local_host_names_reach_an_open_api() {
+        let allowed = |host: &str, listen: &str| is_allowed_host(host_name(host), host_name(listen));
+        for host in ["localhost:3000", "127.0.0.1:3000", "[::1]:3000", "LOCALHOST"] {
+            assert!(allowed(host, "127.0.0.1:3000"), "{host}");
+        }
+        // A rebound name
+        assert!(!allowed("evil.example:3000", "127.0.0.1:3000"));
+        assert!(!allowed("192.168.1.5:3000", "127.0.0.1:3000"));
+        assert!(allowed("nas.lan:3000", "nas.lan:3000"));
+        assert!(allowed("192.168.1.5:3000", "0.0.0.0:3000") && allowed("[fe80::1]:3000", "[::]:3000"));
+        assert!(!allowed("nas.lan:3000", "0.0.0.0:3000"));
+    }
+}
@@ -0,0 +1,805 @@
+// src/app.rs
+
+//! The AppManager program: command line, background tasks and the web server around ServiceManager
+
+use crate::api::{self, AppState, SharedManager};
+use crate::auth::{self, Auth};
+use crate::manager::{CheckSchedule, LaunchPlan, ServiceManager};
+use crate::scheduler::{self, LocalTime, Scheduler};
+use crate::service::{self, TlsConfig, drop_in_files, load_services_file};
+use crate::watch::{self, PathWatcher};
+
+use anyhow::Context;
+use clap::Parser;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Notify, mpsc};
+use tracing::{error, info, warn};
+
+/// Config changes within this window are written together
+const SAVE_DELAY: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+/// How often keep-alive looks for added services and changed intervals
+const KEEP_ALIVE_RESCAN: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// How often the health checker looks for due probes
+const HEALTH_TICK: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// How often services that aren't ready yet are probed
+const READY_TICK: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+/// How often running tasks are checked for having finished
+const TASK_TICK: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// How often the exec files of restart_on_update services are looked at
+const UPDATE_TICK: tokio::time::Duration = tokio::time::Duration::from_secs(2);
+/// How often the watch paths are compared with the config and settled changes acted on
+const WATCH_TICK: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// How long HTTPS connections (log streams) may keep the exit waiting
+#[cfg(feature = "tls")]
+const TLS_SHUTDOWN_GRACE: tokio::time::Duration = tokio::time::Duration::from_secs(3);
+
+/// Derive for clap
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    debug: bool,
+    #[arg(long)]
+    listen: Option<String>,
+    /// Serve HTTPS with this PEM certificate, in place of tls in services.yaml
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// Private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// Emit logs as JSON lines
+    #[arg(long)]
+    log_json: bool,
+    /// Print the resolved command of each service and exit
+    #[arg(long)]
+    dry_run: bool,
+    /// Read a password from stdin and print its hash for auth.users
+    #[arg(long)]
+    hash_password: bool,
+}
+/// Run AppManager the way the binary does: parse the command line, load services.yaml
+/// from the working directory and serve the web UI until shutdown
+pub async fn run() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // process "--debug" command and open debug window
+    // "--dry-run" and "--hash-password" also need a console
+    if args.debug || args.dry_run || args.hash_password {
+        #[cfg(windows)]
+        unsafe {
+            use windows_sys::Win32::System::Console::{
+                ATTACH_PARENT_PROCESS, AllocConsole, AttachConsole,
+            };
+            if AttachConsole(ATTACH_PARENT_PROCESS) == 0 {
+                AllocConsole();
+            }
+        }
+    }
+    // Logging: pretty console by default, JSON lines with "--log-json"
+    let level = if args.debug { tracing::Level::DEBUG } else { tracing::Level::INFO };
+    let subscriber = tracing_subscriber::fmt().with_max_level(level);
+    if args.log_json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    if args.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            anyhow::bail!("Type the password, then Enter");
+        }
+        println!("{}", auth::hash_password(password));
+        return Ok(());
+    }
+    // Locate and initial config
+    let config_path = "services.yaml";
+    // Dry run: only load config and print, no spawn and no web server
+    if args.dry_run {
+        return dry_run(config_path);
+    }
+    let mut manager = ServiceManager::new(config_path)?;
+
+    // Autorun processing
+    let auto_start_ids = manager.autorun_ids();
+    // get keep alive interval
+    let keep_alive_seconds = manager.keep_alive_interval();
+    // get listen address, default: 127.0.0.1:3000
+    let listen_addr = args
+        .listen
+        .or(manager.listen().map(str::to_string))
+        .unwrap_or_else(|| "127.0.0.1:3000".to_string());
+    let listen = Listen::parse(&listen_addr)?;
+    // Create mpsc channel to process state and exit
+    let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    let stats = manager.stats.clone();
+    let cors_config = Arc::new(manager.cors.clone().unwrap_or_default());
+    let cors = api::cors_layer(&cors_config)?;
+    let auth = Arc::new(Auth::from_config(manager.auth.as_ref())?);
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig { cert, key, self_signed: None }),
+        _ => manager.tls.clone(),
+    };
+    if tls.is_some() && !matches!(listen, Listen::Tcp) {
+        anyhow::bail!("tls needs a TCP listen address, not {}", listen_addr);
+    }
+    #[cfg(feature = "tls")]
+    let tls_config = match &tls {
+        Some(tls) => Some(crate::tls::server_config(tls, &listen_addr).await?),
+        None => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    if tls.is_some() {
+        anyhow::bail!("tls is set, but AppManager was built without the \"tls\" feature");
+    }
+    if auth.is_open() && !listen.is_local(&listen_addr) {
+        warn!(listen = %listen_addr, "The API is reachable from the network without auth, anyone there can control the services");
+    }
+    let watch_config = manager.watch_config;
+    let save_notify = manager.defer_saves();
+    let shared_manager = Arc::new(Mutex::new(manager));
+    spawn_save_flusher(save_notify, shared_manager.clone());
+    spawn_health_checker(shared_manager.clone());
+    spawn_scheduler(shared_manager.clone());
+    spawn_task_watcher(shared_manager.clone());
+    spawn_readiness_checker(shared_manager.clone());
+    spawn_update_watcher(shared_manager.clone());
+    spawn_path_watcher(shared_manager.clone());
+    if watch_config {
+        spawn_config_watcher(config_path, shared_manager.clone())?;
+    }
+    let monitor_manager = shared_manager.clone();
+    let autorun_manager = shared_manager.clone();
+    let teardown_manager = shared_manager.clone();
+    let app_state = AppState {
+        stats,
+        started_at: Instant::now(),
+        auth,
+        cors: cors_config,
+        https: tls.is_some(),
+        tcp_listen: matches!(listen, Listen::Tcp).then(|| listen_addr.as_str().into()),
+        manager: shared_manager,
+        shutdown_tx, // Send to sender
+    };
+    // Keep-Alive Loop at background
+    // Each service is checked at its own check_interval_secs, falling back to keep_alive
+    if keep_alive_seconds > 0 {
+        info!(interval_secs = keep_alive_seconds, "Keep-Alive system enabled");
+    }
+    tokio::spawn(async move {
+        let mut schedule = CheckSchedule::default();
+        loop {
+            let now = tokio::time::Instant::now();
+            let mut dead_services = Vec::new();
+            {
+                let mut mgr = monitor_manager.lock().await;
+                schedule.sync(&mgr, now);
+                // find dead services among the ones due, as far as their restart policy allows
+                for id in schedule.take_due(&mgr, now) {
+                    if mgr.needs_restart(&id) {
+                        let delay = mgr.services[&id].config.start_delay.unwrap_or(0);
+                        dead_services.push((id, delay));
+                    }
+                }
+            }
+            if !dead_services.is_empty() {
+                warn!(
+                    count = dead_services.len(),
+                    "Keep-Alive Check: Found stopped or unhealthy services. Restarting...",
+                );
+            }
+            // keep alive processing
+            // start_delay is waited out without holding the manager
+            for (id, delay) in dead_services {
+                if delay > 0 {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
+                }
+                let mut mgr = monitor_manager.lock().await;
+                // Started or stopped by hand in the meantime
+                if delay > 0 && !mgr.needs_restart(&id) {
+                    continue;
+                }
+                // Still running: failing its health check or over its memory limit
+                let running = mgr.is_running(&id);
+                drop(mgr);
+                let result = if running {
+                    warn!(service = %id, event = "health_restart", "Restarting unhealthy service");
+                    ServiceManager::restart_shared(&monitor_manager, &id).await
+                } else {
+                    info!(service = %id, event = "keep_alive_restart", "Auto-restarting service");
+                    ServiceManager::start_shared(&monitor_manager, &id).await
+                };
+                monitor_manager.lock().await.record_restart(&id);
+                if let Err(e) = result {
+                    error!(
+                        service = %id,
+                        error = %e,
+                        event = "restart_failed",
+                        "Failed to restart service",
+                    );
+                }
+            }
+            // Wake for the next due check, or to pick up added services and changed intervals
+            let rescan = tokio::time::Instant::now() + KEEP_ALIVE_RESCAN;
+            let wake = schedule.next_due().map_or(rescan, |at| at.min(rescan));
+            tokio::time::sleep_until(wake).await;
+        }
+    });
+    // create api router and listening
+    let app = api::create_router(app_state).layer(cors);
+    let scheme = match listen {
+        Listen::Tcp if tls.is_some() => "https://",
+        Listen::Tcp => "http://",
+        _ => "",
+    };
+    info!(listen = %listen_addr, "Server running on {}{}", scheme, listen_addr);
+    let shutdown = shutdown_signal(shutdown_rx, teardown_manager);
+    // Autorun only once the dashboard and API are reachable, in the background
+    #[cfg(unix)]
+    if let Listen::Unix(path) = &listen {
+        let listener = bind_unix(path)?;
+        spawn_autorun(autorun_manager, auto_start_ids);
+        return serve_unix(path, listener, app, shutdown).await;
+    }
+    #[cfg(windows)]
+    if let Listen::Pipe(name) = &listen {
+        let listener = PipeListener::bind(name).with_context(|| format!("Failed to create the named pipe {}", name))?;
+        spawn_autorun(autorun_manager, auto_start_ids);
+        return serve_pipe(listener, app, shutdown).await;
+    }
+    let listener = TcpListener::bind(&listen_addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen_addr))?;
+    // Decouple app manager and apps
+    // The port is released when app manager exit
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawSocket;
+        use windows_sys::Win32::Foundation::{HANDLE_FLAG_INHERIT, SetHandleInformation};
+        unsafe {
+            SetHandleInformation(listener.as_raw_socket() as _, HANDLE_FLAG_INHERIT, 0);
+        }
+    }
+    spawn_autorun(autorun_manager, auto_start_ids);
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls_config {
+        return serve_tls(listener, app, config, shutdown).await;
+    }
+    // Web frame: axum
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+/// Where the web server listens, from `listen` or --listen
+enum Listen {
+    /// "127.0.0.1:3000"
+    Tcp,
+    /// "unix:/run/appmanager.sock"
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    /// "pipe:\\.\pipe\appmanager"
+    #[cfg(windows)]
+    Pipe(String),
+}
+impl Listen {
+    fn parse(listen: &str) -> anyhow::Result<Self> {
+        match listen.split_once(':') {
+            #[cfg(unix)]
+            Some(("unix", path)) => Ok(Self::Unix(path.into())),
+            #[cfg(not(unix))]
+            Some(("unix", _)) => anyhow::bail!("unix: sockets are not supported on Windows, listen on pipe:\\\\.\\pipe\\<name>"),
+            #[cfg(windows)]
+            Some(("pipe", name)) => Ok(Self::Pipe(name.to_string())),
+            #[cfg(not(windows))]
+            Some(("pipe", _)) => anyhow::bail!("Named pipes are Windows only, listen on unix:<path>"),
+            _ => Ok(Self::Tcp),
+        }
+    }
+    /// Whether only this machine can connect
+    fn is_local(&self, listen: &str) -> bool {
+        match self {
+            Self::Tcp => is_loopback(listen),
+            _ => true,
+        }
+    }
+}
+/// Listen on a Unix domain socket, owner and group only
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // Left behind by a run that didn't exit cleanly, unless that one is still serving
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{} is in use by another AppManager", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+/// Serve on a Unix domain socket, removed again on exit
+#[cfg(unix)]
+async fn serve_unix(
+    path: &std::path::Path,
+    listener: tokio::net::UnixListener,
+    app: axum::Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let result = axum::serve(listener, app).with_graceful_shutdown(shutdown).await;
+    let _ = std::fs::remove_file(path);
+    Ok(result?)
+}
+/// Serve on a named pipe, local clients only
+#[cfg(windows)]
+async fn serve_pipe(
+    listener: PipeListener,
+    app: axum::Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    axum::serve(listener, app).with_graceful_shutdown(shutdown).await?;
+    Ok(())
+}
+/// Named pipe server, a new pipe instance waits for the next client while one is served
+#[cfg(windows)]
+struct PipeListener {
+    name: String,
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+#[cfg(windows)]
+impl PipeListener {
+    fn bind(name: &str) -> std::io::Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+        // Fails when another process owns the name; remote clients are rejected by default
+        let next = ServerOptions::new().first_pipe_instance(true).create(name)?;
+        Ok(Self { name: name.to_string(), next })
+    }
+}
+#[cfg(windows)]
+impl axum::serve::Listener for PipeListener {
+    type Io = tokio::net::windows::named_pipe::NamedPipeServer;
+    type Addr = String;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        use tokio::net::windows::named_pipe::ServerOptions;
+        loop {
+            let result = self.next.connect().await.and_then(|()| ServerOptions::new().create(&self.name));
+            match result {
+                Ok(next) => return (std::mem::replace(&mut self.next, next), self.name.clone()),
+                Err(e) => {
+                    warn!(pipe = %self.name, error = %e, "Named pipe accept failed");
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.name.clone())
+    }
+}
+/// Serve HTTPS until the shutdown future (with its teardown) is done
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    listener: TcpListener,
+    app: axum::Router,
+    config: axum_server::tls_rustls::RustlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(Some(TLS_SHUTDOWN_GRACE));
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, config)?
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+/// Print what start would run for every service
+fn dry_run(config_path: &str) -> anyhow::Result<()> {
+    let mut paths = vec![std::path::PathBuf::from(config_path)];
+    paths.extend(drop_in_files(config_path));
+    for path in paths {
+        dry_run_file(&path.to_string_lossy())?;
+    }
+    Ok(())
+}
+/// Whether a listen address only accepts connections from this machine
+fn is_loopback(listen: &str) -> bool {
+    match listen.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().is_loopback(),
+        Err(_) => listen.starts_with("localhost:"),
+    }
+}
+
+/// Dry run of a single config file
+fn dry_run_file(path: &str) -> anyhow::Result<()> {
+    println!("# {}", path);
+    let (file, migrated) = load_services_file(path)?;
+    if migrated {
+        println!("📝 Config would be migrated to version {}", service::CONFIG_VERSION);
+    }
+    for cfg in &file.services {
+        let cfg = &service::ServiceConfig { inherited_env: file.env.clone().unwrap_or_default(), ..cfg.clone() };
+        println!("[{}] {}", cfg.id, cfg.name);
+        match LaunchPlan::resolve(cfg) {
+            Ok(plan) => {
+                let mode = if plan.shell { " (shell)" } else { "" };
+                println!("  exec:        {}{}", plan.exec, mode);
+                if !plan.interpreter.is_empty() {
+                    println!("  interpreter: {}", plan.interpreter.join(" "));
+                }
+                for candidate in cfg.exec_candidates().iter().skip(1) {
+                    match LaunchPlan::resolve(candidate) {
+                        Ok(fallback) => println!("  fallback:    {}", fallback.exec),
+                        Err(e) => println!("  fallback:    ❌ {}", e),
+                    }
+                }
+                println!("  working_dir: {}", plan.working_dir.as_deref().unwrap_or("-"));
+                if plan.args.is_empty() {
+                    println!("  args:        -");
+                }
+                for (i, arg) in plan.args.iter().enumerate() {
+                    let label = if i == 0 { "args:" } else { "" };
+                    println!("  {:<12} {}", label, arg);
+                }
+                if plan.env_clear {
+                    println!("  inherit:     nothing (env_clear)");
+                } else if !plan.env_remove.is_empty() {
+                    println!("  inherit:     all but {}", plan.env_remove.join(", "));
+                }
+                let mut env: Vec<_> = plan.env.iter().collect();
+                env.sort();
+                for (i, (key, value)) in env.into_iter().enumerate() {
+                    let label = if i == 0 { "env:" } else { "" };
+                    // Values from env_file are as secret as the file is
+                    let from_file = !cfg.env.as_ref().is_some_and(|env| env.contains_key(key.as_str()))
+                        && !cfg.inherited_env.contains_key(key.as_str());
+                    let value = if from_file || cfg.is_secret_env(key) { service::SECRET_MASK } else { value };
+                    println!("  {:<12} {}={}", label, key, value);
+                }
+                println!("  flags:       {:#010x}", plan.creation_flags);
+                if plan.uid.is_some() || plan.gid.is_some() {
+                    let id = |id: Option<u32>| id.map_or("-".to_string(), |id| id.to_string());
+                    println!("  run as:      uid {} gid {}", id(plan.uid), id(plan.gid));
+                }
+                #[cfg(windows)]
+                if let Some(user) = cfg.run_as.as_ref().and_then(|r| r.user.as_ref()) {
+                    println!("  run as:      {}", user);
+                }
+                if let Some(nice) = plan.nice {
+                    println!("  nice:        {}", nice);
+                }
+                if let Some(mask) = plan.affinity_mask {
+                    println!("  affinity:    {:#x}", mask);
+                }
+                for warning in plan.flag_warnings() {
+                    println!("  ⚠️ {}", warning);
+                }
+                println!("  autorun:     {}", cfg.autorun.unwrap_or(false));
+                if !cfg.is_enabled() {
+                    println!("  enabled:     false");
+                }
+                if let Some(schedule) = &cfg.schedule {
+                    let cron = |expr: &Option<String>| expr.as_deref().unwrap_or("-").to_string();
+                    println!("  schedule:    start {} stop {}", cron(&schedule.start), cron(&schedule.stop));
+                    if let Err(e) = scheduler::check(schedule) {
+                        println!("  ❌ {}", e);
+                    }
+                }
+            }
+            Err(e) => println!("  ❌ {}", e),
+        }
+        println!();
+    }
+    Ok(())
+}
+/// Start the autorun services without holding the manager during their delays and waits
+fn spawn_autorun(manager: SharedManager, ids: Vec<String>) {
+    tokio::spawn(async move {
+        for (id, result) in ServiceManager::start_many_shared(&manager, &ids).await {
+            if let Err(e) = result {
+                error!(service = %id, error = %e, event = "start_failed", "Failed to autorun service");
+            }
+        }
+    });
+}
+/// Write coalesced config changes at most once per SAVE_DELAY
+fn spawn_save_flusher(notify: Arc<Notify>, manager: SharedManager) {
+    tokio::spawn(async move {
+        loop {
+            notify.notified().await;
+            // Let a burst of changes (drag and drop, import) settle into one write
+            tokio::time::sleep(SAVE_DELAY).await;
+            if let Err(e) = manager.lock().await.flush() {
+                error!(error = %format!("{:#}", e), event = "save_failed", "Failed to save config");
+            }
+        }
+    });
+}
+/// Probe services with a health_check, keep-alive acts on the results
+fn spawn_health_checker(manager: SharedManager) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(HEALTH_TICK).await;
+            let due = manager.lock().await.due_health_checks();
+            if due.is_empty() {
+                continue;
+            }
+            // Probe without holding the manager, a slow endpoint must not block the API
+            let probes = due.into_iter().map(|probe| async move {
+                let (id, pid) = (probe.id.clone(), probe.pid);
+                (id, pid, probe.run().await)
+            });
+            let results = futures_util::future::join_all(probes).await;
+            let mut mgr = manager.lock().await;
+            for (id, pid, result) in results {
+                mgr.record_health(&id, pid, result);
+            }
+        }
+    });
+}
+/// Probe ready_when of started services, their status is Starting until it passes
+fn spawn_readiness_checker(manager: SharedManager) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(READY_TICK).await;
+            ServiceManager::poll_readiness(&manager).await;
+        }
+    });
+}
+/// Notice finished tasks (kind: task), keep-alive doesn't look at them
+fn spawn_task_watcher(manager: SharedManager) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(TASK_TICK).await;
+            manager.lock().await.poll_tasks();
+        }
+    });
+}
+/// Restart services with restart_on_update once a new version of their exec is in place
+fn spawn_update_watcher(manager: SharedManager) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(UPDATE_TICK).await;
+            let updated = manager.lock().await.updated_services();
+            for id in updated {
+                info!(service = %id, event = "exec_updated", "Exec file changed, restarting service");
+                if let Err(e) = ServiceManager::restart_shared(&manager, &id).await {
+                    error!(service = %id, error = %format!("{:#}", e), event = "restart_failed", "Failed to restart updated service");
+                }
+            }
+        }
+    });
+}
+/// Restart running services when files in their watch list change
+fn spawn_path_watcher(manager: SharedManager) {
+    tokio::spawn(async move {
+        let mut watcher = PathWatcher::default();
+        // An interval keeps ticking while unrelated files in a watched directory change all the time
+        let mut tick = tokio::time::interval(WATCH_TICK);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = watcher.recv() => continue,
+            }
+            let mut mgr = manager.lock().await;
+            // Services and their watch lists may have been edited
+            watcher.sync(watch::watched_paths(&mgr));
+            let changed: Vec<String> = watcher.take_settled().into_iter().filter(|id| mgr.is_running(id)).collect();
+            drop(mgr);
+            for id in changed {
+                info!(service = %id, event = "watch_changed", "Watched files changed, restarting service");
+                if let Err(e) = ServiceManager::restart_shared(&manager, &id).await {
+                    error!(service = %id, error = %format!("{:#}", e), event = "restart_failed", "Failed to restart service after a change");
+                }
+            }
+        }
+    });
+}
+/// Start and stop services with a schedule, checked at the start of every minute
+fn spawn_scheduler(manager: SharedManager) {
+    tokio::spawn(async move {
+        let mut scheduler = Scheduler::default();
+        loop {
+            // Sleep into the next minute, a little late rather than early
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let into_minute = std::time::Duration::from_nanos((now.as_nanos() % 60_000_000_000) as u64);
+            let wait = std::time::Duration::from_secs(60) - into_minute + std::time::Duration::from_millis(100);
+            tokio::time::sleep(wait).await;
+
+            let time = LocalTime::now();
+            let due = scheduler.due(&*manager.lock().await, &time);
+            // A slow start or stop must not hold up the other services or the next minute,
+            // the actions of one service still run in order
+            for actions in due.chunk_by(|a, b| a.0 == b.0) {
+                let manager = manager.clone();
+                let actions = actions.to_vec();
+                tokio::spawn(async move {
+                    for (id, action) in actions {
+                        scheduler::apply(&manager, &id, action).await;
+                    }
+                });
+            }
+        }
+    });
+}
+/// Reload the config file when it is edited outside AppManager
+fn spawn_config_watcher(config_path: &str, manager: SharedManager) -> anyhow::Result<()> {
+    use notify::{RecursiveMode, Watcher};
+
+    let path = std::path::absolute(config_path)?;
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        // Reads (including our own) are not edits
+        if let Ok(event) = res
+            && (event.kind.is_create() || event.kind.is_modify())
+            && event.paths.iter().any(|p| p.file_name() == file_name.as_deref())
+        {
+            let _ = tx.try_send(());
+        }
+    })?;
+    // Watch the directory, editors often replace the file instead of writing to it
+    let dir = path.parent().unwrap_or(std::path::Path::new("."));
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!(path = %path.display(), "Watching config file for changes");
+    tokio::spawn(async move {
+        // The watcher stops when dropped
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // Let the editor finish writing, then handle the burst of events once
+            tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+            while rx.try_recv().is_ok() {}
+            // Only the reconciling holds the manager, stops and starts let it go
+            let reload = manager.lock().await.reload_config();
+            match reload {
+                Ok(Some(actions)) => {
+                    info!(event = "config_reloaded", "Reloaded config file");
+                    ServiceManager::apply_reload(&manager, actions).await;
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    error = %format!("{:#}", e),
+                    "Config reload failed, keeping the current config",
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+/// Process shutdown signal and exit
+/// Returns only after the stop-on-exit teardown is finished
+async fn shutdown_signal(mut api_rx: mpsc::Receiver<()>, manager: SharedManager) {
+    // Stop by "Ctrl+C"
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    // For Windows shutdown signal
+    #[cfg(windows)]
+    let ctrl_close = async {
+        let mut stream = tokio::signal::windows::ctrl_close().unwrap();
+        stream.recv().await;
+    };
+    // For non-Windows platform
+    #[cfg(not(windows))]
+    let ctrl_close = std::future::pending::<()>();
+    // api stop signal
+    let api_signal = async {
+        api_rx.recv().await;
+    };
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C, shutting down..."),
+        _ = ctrl_close => info!("Received Close Event, shutting down..."),
+        _ = api_signal => info!("Received API Shutdown signal, shutting down..."),
+    }
+    teardown(&manager).await;
+}
+/// Stop services before exit if configured
+async fn teardown(manager: &SharedManager) {
+    let mut mgr = manager.lock().await;
+    // Changes still waiting for the flusher
+    if let Err(e) = mgr.flush() {
+        error!(error = %format!("{:#}", e), event = "save_failed", "Failed to save config");
+    }
+    let results = mgr.stop_for_exit().await;
+    if !results.is_empty() {
+        info!("Stopped services before exit");
+    }
+    for (id, result) in results {
+        if let Err(e) = result {
+            error!(service = %id, error = %e, event = "stop_failed", "Failed to stop service");
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::{TestDir, sleeper};
+
+    #[tokio::test]
+    async fn shutdown_signal_stops_all_services() {
+        let (exec, _, dir) = sleeper("amsd_sleep");
+        let config_path = dir.join("services.yaml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "version: 4\nstop_on_exit: true\nservices:\n\
+                 - {{ id: one, name: one, exec: {exec}, args: ['30'] }}\n\
+                 - {{ id: two, name: two, exec: {exec}, args: ['30'] }}\n\
+                 - {{ id: kept, name: kept, exec: {exec}, args: ['31'], stop_on_exit: false }}\n"
+            ),
+        )
+        .unwrap();
+
+        let mut manager = ServiceManager::new(config_path.to_str().unwrap()).unwrap();
+        manager.start("one").await.unwrap();
+        manager.start("two").await.unwrap();
+        manager.start("kept").await.unwrap();
+        assert!(manager.is_running("one") && manager.is_running("two"));
+
+        let shared = Arc::new(Mutex::new(manager));
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(()).await.unwrap();
+        shutdown_signal(rx, shared.clone()).await;
+
+        let mut mgr = shared.lock().await;
+        assert!(!mgr.is_running("one"));
+        assert!(!mgr.is_running("two"));
+        // Opted out of the top-level stop_on_exit
+        assert!(mgr.is_running("kept"));
+        mgr.stop("kept").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unix_socket_serves_and_is_removed_on_exit() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        assert!(matches!(Listen::parse("127.0.0.1:3000").unwrap(), Listen::Tcp));
+        assert!(Listen::parse("pipe:\\\\.\\pipe\\appmanager").is_err());
+        let dir = TestDir::new("socket");
+        let path = dir.join("appmanager.sock");
+        let Listen::Unix(parsed) = Listen::parse(&format!("unix:{}", path.display())).unwrap() else { panic!() };
+        assert_eq!(parsed, path);
+        // A stale socket file from an earlier run
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let app = axum::Router::new().route("/ping", axum::routing::get(|| async { "pong" }));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let socket = path.clone();
+        let listener = bind_unix(&path).unwrap();
+        let server = tokio::spawn(async move {
+            serve_unix(&socket, listener, app, async move {
+                let _ = rx.await;
+            })
+            .await
+        });
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(20)).await,
+            }
+        };
+        stream.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("pong"), "{response}");
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
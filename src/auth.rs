@@ -0,0 +1,175 @@
+// src/auth.rs
+
+//! API access: the auth.token and web UI logins (PBKDF2-HMAC-SHA256 password hashes, cookie sessions)
+
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::service::AuthConfig;
+
+/// Iterations of new password hashes, each hash records its own count
+const HASH_ITERATIONS: u32 = 100_000;
+/// Prefix of the password hashes in auth.users
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// Sessions last this long without session_hours
+const DEFAULT_SESSION_HOURS: u64 = 12;
+/// Cookie holding the session id
+pub const SESSION_COOKIE: &str = "appmanager_session";
+
+/// PBKDF2 with HMAC-SHA256, one block of output
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<sha2::Sha256, 32>(password, salt, iterations)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Random bytes from the OS as hex (salts, session ids)
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).expect("the OS random number generator failed");
+    to_hex(&bytes)
+}
+
+/// Hash for auth.users: pbkdf2-sha256$<iterations>$<salt>$<hash>
+pub fn hash_password(password: &str) -> String {
+    hash_with(password, &random_hex(16), HASH_ITERATIONS)
+}
+
+fn hash_with(password: &str, salt: &str, iterations: u32) -> String {
+    let hash = pbkdf2_sha256(password.as_bytes(), salt.as_bytes(), iterations);
+    format!("{}${}${}${}", HASH_SCHEME, iterations, salt, to_hex(&hash))
+}
+
+/// Parts of a hash made by hash_password, None when it isn't one
+fn parse_hash(encoded: &str) -> Option<(u32, &str, Vec<u8>)> {
+    let mut parts = encoded.split('$');
+    if parts.next()? != HASH_SCHEME {
+        return None;
+    }
+    let iterations = parts.next()?.parse().ok().filter(|n| *n > 0)?;
+    let salt = parts.next()?;
+    let hash = from_hex(parts.next()?).filter(|hash| hash.len() == 32)?;
+    parts.next().is_none().then_some((iterations, salt, hash))
+}
+
+pub fn verify_password(password: &str, encoded: &str) -> bool {
+    let Some((iterations, salt, expected)) = parse_hash(encoded) else { return false };
+    let hash = pbkdf2_sha256(password.as_bytes(), salt.as_bytes(), iterations);
+    same_secret(&hash, &expected)
+}
+
+/// Compare secrets in time independent of where they differ
+pub fn same_secret(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len() && presented.iter().zip(expected).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Who may use the API, and the logged in web UI sessions
+#[derive(Debug, Default)]
+pub struct Auth {
+    token: Option<String>,
+    /// User name to password hash
+    users: HashMap<String, String>,
+    session_ttl: Duration,
+    /// Session id to user and expiry
+    sessions: Mutex<HashMap<String, (String, Instant)>>,
+}
+impl Auth {
+    /// From the auth section, the password hashes are checked here so a typo fails the start
+    pub fn from_config(config: Option<&AuthConfig>) -> Result<Self> {
+        let Some(config) = config else { return Ok(Self::default()) };
+        let users = config.users.clone().unwrap_or_default();
+        for (user, hash) in &users {
+            if parse_hash(hash).is_none() {
+                bail!("auth.users.{} is not a password hash, create one with --hash-password", user);
+            }
+        }
+        Ok(Self {
+            token: config.resolved_token()?,
+            users,
+            session_ttl: Duration::from_secs(config.session_hours.unwrap_or(DEFAULT_SESSION_HOURS) * 3600),
+            sessions: Mutex::default(),
+        })
+    }
+    /// Neither a token nor users: every request is let through
+    pub fn is_open(&self) -> bool {
+        self.token.is_none() && self.users.is_empty()
+    }
+    /// The web UI logs in with a user name and password
+    pub fn has_users(&self) -> bool {
+        !self.users.is_empty()
+    }
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+    pub fn check_token(&self, presented: &str) -> bool {
+        self.token.as_ref().is_some_and(|token| same_secret(presented.as_bytes(), token.as_bytes()))
+    }
+    /// Check the password and open a session, its id goes into the cookie
+    /// Slow on purpose (PBKDF2), run it off the async threads
+    pub fn login(&self, user: &str, password: &str) -> Option<String> {
+        // Unknown users cost the same time, so names can't be probed
+        let hash = self.users.get(user);
+        let dummy = format!("{}${}$00${}", HASH_SCHEME, HASH_ITERATIONS, "00".repeat(32));
+        if !verify_password(password, hash.unwrap_or(&dummy)) || hash.is_none() {
+            return None;
+        }
+        let id = random_hex(32);
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(id.clone(), (user.to_string(), now + self.session_ttl));
+        Some(id)
+    }
+    /// User of a session that hasn't expired
+    pub fn session_user(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.get(id).filter(|(_, expires)| *expires > Instant::now()).map(|(user, _)| user.clone())
+    }
+    pub fn logout(&self, id: &str) {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner()).remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_the_published_vectors() {
+        assert_eq!(
+            hash_with("password", "salt", 2),
+            "pbkdf2-sha256$2$salt$ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+    }
+
+    #[test]
+    fn logins_open_sessions_for_the_right_password() {
+        let hash = hash_with("hunter2", "0011", 3);
+        assert!(verify_password("hunter2", &hash) && !verify_password("hunter3", &hash));
+        assert!(!verify_password("hunter2", "hunter2"));
+
+        let config = AuthConfig { users: Some(HashMap::from([("admin".to_string(), hash)])), ..Default::default() };
+        let auth = Auth::from_config(Some(&config)).unwrap();
+        assert!(!auth.is_open() && !auth.check_token(""));
+        assert_eq!(auth.login("admin", "wrong"), None);
+        assert_eq!(auth.login("nobody", "hunter2"), None);
+        let session = auth.login("admin", "hunter2").unwrap();
+        assert_eq!(auth.session_user(&session).as_deref(), Some("admin"));
+        auth.logout(&session);
+        assert_eq!(auth.session_user(&session), None);
+
+        let config = AuthConfig { users: Some(HashMap::from([("admin".to_string(), "plain".to_string())])), ..Default::default() };
+        assert!(Auth::from_config(Some(&config)).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+// src/health.rs
+
+//! Health checks of running services: HTTP, TCP or a probe command
+
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use crate::service::HealthCheck;
+
+pub const DEFAULT_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 3;
+pub const DEFAULT_FAILURES: u32 = 3;
+/// Longest status line read from an answer
+const MAX_STATUS_LINE: usize = 1024;
+
+/// Outcome of the probes since the last start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// Not probed yet
+    #[default]
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+/// Health of a service as reported by the API
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// Failed probes in a row
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+impl HealthReport {
+    /// Count a probe, unhealthy once `threshold` probes failed in a row
+    pub fn record(&mut self, result: Result<()>, threshold: u32) {
+        match result {
+            Ok(()) => {
+                self.status = HealthStatus::Healthy;
+                self.failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.last_error = Some(format!("{:#}", e));
+                if self.failures >= threshold.max(1) {
+                    self.status = HealthStatus::Unhealthy;
+                }
+            }
+        }
+    }
+}
+
+/// What a probe does
+pub enum ProbeKind {
+    Http(String),
+    Tcp(String),
+    Cmd { command: Command, expect_exit: i32 },
+    /// Can't be run, reported as a failed probe
+    Invalid(String),
+}
+
+/// A probe of one process, prepared under the manager lock and run without it
+pub struct Probe {
+    pub id: String,
+    pub pid: u32,
+    pub timeout: Duration,
+    pub kind: ProbeKind,
+}
+impl Probe {
+    /// `command` builds the probe command line the way the service's hooks are run
+    pub fn new(id: &str, pid: u32, check: &HealthCheck, command: impl FnOnce(&str) -> Result<Command>) -> Self {
+        let kind = match (&check.http, &check.tcp, &check.cmd) {
+            (Some(url), None, None) => ProbeKind::Http(url.clone()),
+            (None, Some(addr), None) => ProbeKind::Tcp(addr.clone()),
+            (None, None, Some(line)) => match command(line) {
+                Ok(command) => ProbeKind::Cmd { command, expect_exit: check.expect_exit.unwrap_or(0) },
+                Err(e) => ProbeKind::Invalid(format!("{:#}", e)),
+            },
+            _ => ProbeKind::Invalid("health_check needs exactly one of http, tcp or cmd".into()),
+        };
+        Self {
+            id: id.to_string(),
+            pid,
+            timeout: Duration::from_secs(check.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            kind,
+        }
+    }
+    pub async fn run(self) -> Result<()> {
+        match self.kind {
+            ProbeKind::Http(url) => probe_http(&url, self.timeout).await,
+            ProbeKind::Tcp(addr) => probe_tcp(&addr, self.timeout).await,
+            ProbeKind::Cmd { command, expect_exit } => probe_cmd(command, expect_exit, self.timeout).await,
+            ProbeKind::Invalid(reason) => Err(anyhow!(reason)),
+        }
+    }
+}
+
+/// Healthy when the command exits with the expected code within the timeout
+/// The command is killed when the timeout hits (kill_on_drop)
+pub async fn probe_cmd(mut command: Command, expect_exit: i32, timeout: Duration) -> Result<()> {
+    let output = tokio::time::timeout(timeout, command.output())
+        .await
+        .map_err(|_| anyhow!("Killed after {}s", timeout.as_secs()))??;
+    match output.status.code() {
+        Some(code) if code == expect_exit => Ok(()),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!("{}, expected exit code {} {}", output.status, expect_exit, stderr.trim())
+        }
+    }
+}
+
+/// Healthy when the address accepts a connection within the timeout
+pub async fn probe_tcp(addr: &str, timeout: Duration) -> Result<()> {
+    tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow!("No connection within {}s", timeout.as_secs()))??;
+    Ok(())
+}
+
+/// GET the url, healthy on a 2xx/3xx answer within the timeout
+pub async fn probe_http(url: &str, timeout: Duration) -> Result<()> {
+    let status = tokio::time::timeout(timeout, get_status(url))
+        .await
+        .map_err(|_| anyhow!("No answer within {}s", timeout.as_secs()))??;
+    if !(200..400).contains(&status) {
+        bail!("HTTP {}", status);
+    }
+    Ok(())
+}
+
+/// Minimal HTTP/1.1 GET, only the status code of the answer is read
+async fn get_status(url: &str) -> Result<u16> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("Only http:// health check URLs are supported: {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        bail!("No host in health check URL: {}", url);
+    }
+    // Port defaults to 80, IPv6 hosts come bracketed: [::1]:8080
+    let addr = match authority.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => authority.to_string(),
+        _ => format!("{}:80", authority),
+    };
+    let mut stream = TcpStream::connect(&addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: AppManager\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 256];
+    while !head.windows(2).any(|w| w == b"\r\n") && head.len() < MAX_STATUS_LINE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let line = head.lines().next().unwrap_or_default();
+    line.split_whitespace()
+        .nth(1)
+        .filter(|_| line.starts_with("HTTP/"))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("Not an HTTP answer: {:?}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Answer one request with the given status line
+    async fn server(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let answer = format!("{}\r\nContent-Length: 0\r\n\r\n", status);
+            let _ = socket.write_all(answer.as_bytes()).await;
+        });
+        format!("http://{}/health", addr)
+    }
+
+    #[tokio::test]
+    async fn probe_checks_the_status_code() {
+        let timeout = Duration::from_secs(2);
+        probe_http(&server("HTTP/1.1 200 OK").await, timeout).await.unwrap();
+        let err = probe_http(&server("HTTP/1.1 503 Service Unavailable").await, timeout).await;
+        assert_eq!(err.unwrap_err().to_string(), "HTTP 503");
+        assert!(probe_http("https://127.0.0.1/", timeout).await.is_err());
+    }
+
+    fn check() -> HealthCheck {
+        HealthCheck { http: None, tcp: None, cmd: None, expect_exit: None, interval: None, timeout: Some(2), failures: None }
+    }
+    fn shell(line: &str) -> Result<Command> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(line).kill_on_drop(true);
+        Ok(command)
+    }
+
+    #[tokio::test]
+    async fn tcp_probe_needs_a_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = HealthCheck { tcp: Some(listener.local_addr().unwrap().to_string()), ..check() };
+        Probe::new("svc", 1, &tcp, shell).run().await.unwrap();
+        drop(listener);
+        assert!(Probe::new("svc", 1, &tcp, shell).run().await.is_err());
+        assert!(Probe::new("svc", 1, &check(), shell).run().await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cmd_probe_compares_the_exit_code() {
+        let ok = HealthCheck { cmd: Some("exit 0".into()), ..check() };
+        Probe::new("svc", 1, &ok, shell).run().await.unwrap();
+        let expected = HealthCheck { cmd: Some("exit 3".into()), expect_exit: Some(3), ..check() };
+        Probe::new("svc", 1, &expected, shell).run().await.unwrap();
+        let failing = HealthCheck { cmd: Some("echo down >&2; exit 1".into()), ..check() };
+        let err = Probe::new("svc", 1, &failing, shell).run().await.unwrap_err();
+        assert!(err.to_string().ends_with("expected exit code 0 down"), "{err}");
+        let slow = HealthCheck { cmd: Some("sleep 5".into()), timeout: Some(1), ..check() };
+        assert!(Probe::new("svc", 1, &slow, shell).run().await.is_err());
+    }
+
+    #[test]
+    fn unhealthy_after_failures_in_a_row() {
+        let mut report = HealthReport::default();
+        report.record(Err(anyhow!("refused")), 2);
+        assert_eq!(report.status, HealthStatus::Starting);
+        report.record(Err(anyhow!("refused")), 2);
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        report.record(Ok(()), 2);
+        assert_eq!(report, HealthReport { status: HealthStatus::Healthy, ..Default::default() });
+    }
+}
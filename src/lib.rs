@@ -0,0 +1,25 @@
+// src/lib.rs
+
+//! AppManager core, for embedding the service manager in another program
+//!
+//! `ServiceManager` loads the services, starts/stops them and reports their status.
+//! The web API and the rest of the program stay internal, `run` starts all of it like the binary.
+
+mod api;
+mod app;
+mod auth;
+mod health;
+mod log_file;
+pub mod manager;
+mod scheduler;
+mod secrets;
+pub mod service;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "tls")]
+mod tls;
+mod watch;
+
+pub use app::run;
+pub use manager::{ServiceError, ServiceManager, ServiceStatusSnapshot};
+pub use service::ServiceConfig;
@@ -0,0 +1,137 @@
+// src/log_file.rs
+
+//! Service log files written by AppManager, rotated by size and/or time
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::service::LogRotate;
+
+/// Rotated files kept, unless log_max_files is set
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// When and how a log file is rotated
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub every: Option<LogRotate>,
+    pub max_files: usize,
+    pub compress: bool,
+}
+impl Rotation {
+    /// Time slot a moment falls in, a new slot starts a new file
+    fn period(&self, time: SystemTime) -> Option<u64> {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        match self.every? {
+            LogRotate::Hourly => Some(secs / 3600),
+            LogRotate::Daily => Some(secs / 86400),
+        }
+    }
+}
+
+/// Append-only log file: `app.log`, rotated to `app.log.1`, `app.log.2`... (`.gz` when compressed)
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: Option<u64>,
+    rotation: Rotation,
+}
+impl RotatingLog {
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        // An existing file belongs to the slot it was last written in
+        let period = rotation.period(meta.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(Self { path: path.to_path_buf(), file, size: meta.len(), period, rotation })
+    }
+    /// Append a line, rotating first when the file is full or its time slot is over
+    /// Rotation (and compression) runs on a blocking thread
+    pub async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let period = self.rotation.period(SystemTime::now());
+        let full = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 + 1 > max);
+        if full || period != self.period {
+            let (path, rotation) = (self.path.clone(), self.rotation.clone());
+            tokio::task::spawn_blocking(move || rotate(&path, &rotation))
+                .await
+                .map_err(io::Error::other)??;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = 0;
+            self.period = period;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Name of the n-th rotated file
+fn rotated(path: &Path, n: usize, compress: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    if compress {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+/// Shift app.log.N to app.log.N+1, dropping the oldest, and move app.log to app.log.1
+fn rotate(path: &Path, rotation: &Rotation) -> io::Result<()> {
+    let max = rotation.max_files;
+    // Files of both kinds, compress may have been switched on or off
+    for compress in [false, true] {
+        let _ = fs::remove_file(rotated(path, max, compress));
+        for n in (1..max).rev() {
+            let from = rotated(path, n, compress);
+            if from.exists() {
+                fs::rename(&from, rotated(path, n + 1, compress))?;
+            }
+        }
+    }
+    if max == 0 {
+        return fs::remove_file(path);
+    }
+    if !rotation.compress {
+        return fs::rename(path, rotated(path, 1, false));
+    }
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(rotated(path, 1, true))?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDir;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn full_files_are_rotated_and_compressed() {
+        let dir = TestDir::new("log");
+        let path = dir.join("app.log");
+        let rotation = Rotation { max_size: Some(10), max_files: 2, compress: true, ..Default::default() };
+        let mut log = RotatingLog::open(&path, rotation).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            log.write_line(line).await.unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(dir.join("app.log.1.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "third\n");
+        assert!(dir.join("app.log.2.gz").exists());
+        // Only log_max_files rotated files are kept
+        assert!(!dir.join("app.log.3.gz").exists());
+    }
+}
@@ -1,36 +1,366 @@
 // src/manager.rs
 
-#[cfg(windows)]
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use anyhow::{Context, Result, anyhow};
-use sysinfo::{Pid, ProcessesToUpdate, System};
+use regex::Regex;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::{Child, Command};
+use tokio::sync::{Mutex, MutexGuard, Notify, broadcast};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::health::{self, HealthReport, HealthStatus, Probe};
+use crate::log_file::{self, RotatingLog, Rotation};
+use crate::scheduler;
+use crate::secrets;
+use crate::service::{
+    AuthConfig, CONFIG_VERSION, ChangeThreshold, CorsConfig, DesiredState, HookFailure, LimitAction, MatchStrategy, ProcessMatch, RestartPolicy, ServiceConfig, ServicesFile, StdioMode, TlsConfig, UnixSignal, drop_in_files, exec_file_name,
+    expand_system_env, interpolate, load_services_file, parse_services_file, split_command_line, unquote,
+};
+
+/// Errors with a meaning for API clients
+/// Carried inside anyhow::Error, the API maps them to status codes
+#[derive(Debug)]
+pub enum ServiceError {
+    NotFound(String),
+    AlreadyExists(String),
+    Conflict(String),
+    Invalid(String),
+}
+impl ServiceError {
+    /// Machine readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::AlreadyExists(_) => "already_exists",
+            Self::Conflict(_) => "conflict",
+            Self::Invalid(_) => "invalid",
+        }
+    }
+}
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "Service '{}' not found", id),
+            Self::AlreadyExists(id) => write!(f, "Service '{}' already exists", id),
+            Self::Conflict(msg) | Self::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+impl std::error::Error for ServiceError {}
 
-use crate::service::{ServiceConfig, ServicesFile, build_args, exec_file_name};
+/// Service counts readable without locking the manager (health endpoint)
+/// Updated whenever the manager checks or changes service state
+#[derive(Debug, Default)]
+pub(crate) struct ManagerStats {
+    pub(crate) services: AtomicUsize,
+    pub(crate) running: AtomicUsize,
+}
 
 /// Snashot of service status
 /// To porcessing list of services
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ServiceStatusSnapshot {
     pub config: ServiceConfig,
     pub running: bool,
     pub pid: Option<u32>,
+    pub adopted: bool,
+    pub last_exit: Option<ExitInfo>,
+    pub health: Option<HealthReport>,   // None without a health_check
+    pub usage: Option<ResourceUsage>,   // None when not running
+    pub last_run: Option<TaskRun>,      // kind: task only
+    pub uptime_secs: Option<u64>,       // None when not running
+    pub restarts: u32,                  // starts after the first one since AppManager started
+    pub crashes: u32,                   // exits on its own other than with code 0
+    pub start_failed: bool,             // the last start exited within startup_grace_secs
+    pub ready: bool,                    // ready_when passed since the start (always true without one)
+}
+/// Last run of a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskRun {
+    pub started_at: u64,            // unix seconds
+    pub duration_ms: Option<u64>,   // None while running
+}
+/// CPU and memory of a running service
+/// CPU in percent of one core, averaged since the previous sample
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceUsage {
+    pub cpu_percent: f32,
+    pub memory_rss: u64,        // bytes
+    /// Main process and all its descendants
+    pub tree_cpu_percent: f32,
+    pub tree_memory_rss: u64,
+    pub processes: usize,
+}
+/// CPU time of a service at one moment, to turn the next one into a percentage
+#[derive(Debug, Clone, Copy)]
+struct CpuSample {
+    pid: u32,
+    at: Instant,
+    cpu_ms: u64,
+    tree_cpu_ms: u64,
+    cpu_percent: f32,
+    tree_cpu_percent: f32,
+}
+/// Shorter gaps between samples give noisy percentages, the last one is reported instead
+const CPU_SAMPLE_MIN: Duration = Duration::from_secs(1);
+/// How the last process started by the manager ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitInfo {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,    // Unix only: the terminating signal
+    pub reason: ExitReason,
+    pub at: u64,                // unix seconds
+}
+/// Crash or clean shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Ended on its own with exit code 0
+    Exited,
+    /// Ended on its own with another code or by a signal
+    Crashed,
+    /// Stopped by AppManager, exited when asked to
+    Stopped,
+    /// Stopped by AppManager, had to be killed
+    Killed,
+}
+impl ExitReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitReason::Exited => "exited",
+            ExitReason::Crashed => "crashed",
+            ExitReason::Stopped => "stopped",
+            ExitReason::Killed => "killed",
+        }
+    }
+}
+impl ExitInfo {
+    /// Exit of a process stopped by AppManager, the status is unknown without a handle
+    fn stopped(status: Option<std::process::ExitStatus>, on_request: bool) -> Self {
+        let reason = if on_request { ExitReason::Stopped } else { ExitReason::Killed };
+        match status {
+            Some(status) => Self { reason, ..status.into() },
+            None => Self { code: None, signal: None, reason, at: unix_now() },
+        }
+    }
+}
+impl From<std::process::ExitStatus> for ExitInfo {
+    /// A process that ended on its own
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        let reason = if status.success() { ExitReason::Exited } else { ExitReason::Crashed };
+        Self { code: status.code(), signal, reason, at: unix_now() }
+    }
+}
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+/// Services changed since a token, see ServiceManager::changes
+#[derive(Debug)]
+pub struct ChangeSet {
+    pub token: String,
+    /// The token was unknown or too old: all services are listed
+    pub full: bool,
+    pub changed: Vec<ServiceStatusSnapshot>,
+    pub removed: Vec<String>,
+}
+/// What a config reload leaves to do with the processes, see ServiceManager::apply_reload
+#[derive(Debug, Default)]
+pub struct ReloadActions {
+    /// Removed from the config while running, forgotten once stopped
+    pub stop: Vec<String>,
+    /// Changed command, restarted if running
+    pub restart: Vec<String>,
+    /// Added with autorun
+    pub start: Vec<String>,
+}
+/// Last reported state per service, with the revision it changed at
+#[derive(Default)]
+struct ChangeTracker {
+    epoch: u64,     // tokens of another manager run are not comparable
+    revision: u64,
+    seen: HashMap<String, (u64, ServiceStatusSnapshot)>,
+    removed: Vec<(String, u64)>,
+    removed_floor: u64, // older removals are forgotten
+}
+/// Removals remembered for delta clients
+const MAX_REMOVED_CHANGES: usize = 100;
+/// CPU (percentage points) and memory moves below these are no change, see change_threshold
+const DEFAULT_CHANGE_CPU_PERCENT: f32 = 5.0;
+const DEFAULT_CHANGE_MEMORY: u64 = 10 << 20;
+
+/// Whether a service changed for delta clients: any status field, or usage beyond the threshold
+/// Uptime moves all the time, it alone is not a change
+fn snapshot_changed(seen: &ServiceStatusSnapshot, now: &ServiceStatusSnapshot, threshold: ChangeThreshold) -> bool {
+    let status = |s: &ServiceStatusSnapshot| ServiceStatusSnapshot { usage: None, uptime_secs: None, ..s.clone() };
+    if status(seen) != status(now) {
+        return true;
+    }
+    let (seen, now) = match (seen.usage, now.usage) {
+        (Some(seen), Some(now)) => (seen, now),
+        (seen, now) => return seen.is_some() != now.is_some(),
+    };
+    let cpu = threshold.cpu_percent.unwrap_or(DEFAULT_CHANGE_CPU_PERCENT);
+    let memory = threshold.memory.map_or(DEFAULT_CHANGE_MEMORY, |m| m.0);
+    (seen.cpu_percent - now.cpu_percent).abs() > cpu
+        || (seen.tree_cpu_percent - now.tree_cpu_percent).abs() > cpu
+        || seen.memory_rss.abs_diff(now.memory_rss) > memory
+        || seen.tree_memory_rss.abs_diff(now.tree_memory_rss) > memory
+        || seen.processes != now.processes
+}
+
+/// One process of a service tree
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub parent: Option<u32>,
+    pub name: String,
+    pub memory: u64,    // bytes
 }
 /// Structure of services
 /// Include config, process and pid
-pub struct ManagedService {
-    pub config: ServiceConfig,
-    pub process: Option<Child>,
-    pub last_known_pid: Option<u32>,    // to catch pid who not started by app manager  
+pub(crate) struct ManagedService {
+    pub(crate) config: ServiceConfig,
+    pub(crate) source: Option<PathBuf>,    // drop-in file the config came from, None = main config
+    pub(crate) process: Option<Child>,
+    pub(crate) last_known_pid: Option<u32>,    // to catch pid who not started by app manager  
+    pub(crate) output: ServiceOutput,          // captured output lines, kept across restarts
+    pub(crate) last_exit: Option<ExitInfo>,    // set when the process ended, cleared by the next spawn
+    started_at: Option<Instant>,        // last spawn, to tell a stable run from a crash loop
+    restart_attempts: u32,              // keep-alive restarts since the last stable run
+    next_restart: Option<Instant>,      // backoff: no keep-alive restart before this
+    health: HealthReport,               // probes since the last spawn
+    next_probe: Option<Instant>,        // health check schedule, None = due
+    cpu_sample: Option<CpuSample>,      // last CPU reading, see usage
+    over_memory: bool,                  // above limits.max_memory_mb at the last check
+    last_run: Option<TaskRun>,          // kind: task only
+    starts: u32,                        // spawns since AppManager started
+    start_failed: bool,                 // the last spawn exited during its startup grace
+    ready: bool,                        // ready_when passed since the last spawn
+    ready_overdue: bool,                // not ready within ready_when.timeout, reported once
+    ready_line: Option<ReadyLine>,      // ready_when.log_matches of the last spawn
+    exec_file: Option<PathBuf>,         // restart_on_update: the program the last spawn ran
+    exec_stamp: Option<FileStamp>,      // its version at that spawn
+    exec_change: Option<(FileStamp, Instant)>, // a newer version, waiting for it to settle
+    crashes: u32,                       // exits counted as crashes since AppManager started
+    stopping: bool,                     // stop_shared is waiting for it to go down
+    removed: bool,                      // taken out of the config file, kept until its process is stopped
+    matcher: ProcessMatcher,            // resolved from the config, pid_file is read at each check
+    #[cfg(windows)]
+    job: Option<JobObject>,             // process tree of the last spawn
 }
 impl ManagedService {
     fn new(config: ServiceConfig) -> Self {
         Self {
+            matcher: ProcessMatcher::new(&config),
             config,
+            source: None,
             process: None,
             last_known_pid: None,
+            output: ServiceOutput::new(),
+            last_exit: None,
+            started_at: None,
+            restart_attempts: 0,
+            next_restart: None,
+            health: HealthReport::default(),
+            next_probe: None,
+            cpu_sample: None,
+            over_memory: false,
+            last_run: None,
+            starts: 0,
+            crashes: 0,
+            stopping: false,
+            removed: false,
+            start_failed: false,
+            ready: false,
+            ready_overdue: false,
+            ready_line: None,
+            exec_file: None,
+            exec_stamp: None,
+            exec_change: None,
+            #[cfg(windows)]
+            job: None,
+        }
+    }
+    /// Replace the config, the process matcher is resolved again
+    fn set_config(&mut self, config: ServiceConfig) {
+        self.matcher = ProcessMatcher::new(&config);
+        self.config = config;
+        self.removed = false;
+    }
+    /// Matcher for its processes, with the pid in pid_file as it is now
+    fn process_matcher(&self) -> ProcessMatcher {
+        let pid_file = self.config.pid_file.as_ref().map(|_| read_pid_file(&self.config));
+        ProcessMatcher { pid_file, ..self.matcher.clone() }
+    }
+    /// Started and past its ready_when check
+    fn is_ready(&self) -> bool {
+        self.ready || self.config.ready_when.is_none()
+    }
+    /// How long ready_when may take after the start
+    fn ready_timeout(&self) -> Duration {
+        let secs = self.config.ready_when.as_ref().and_then(|r| r.timeout);
+        Duration::from_secs(secs.unwrap_or(DEFAULT_READY_TIMEOUT_SECS))
+    }
+    /// Remember which version of its program a spawn ran, for restart_on_update
+    /// Shell commands have no single program to watch
+    fn watch_exec(&mut self, exec: Option<&str>) {
+        let watched = exec.filter(|_| self.config.restart_on_update.unwrap_or(false));
+        self.exec_file = watched.and_then(|exec| match PathBuf::from(exec) {
+            path if path.components().count() > 1 => Some(path),
+            _ => find_on_path(exec),
+        });
+        self.exec_stamp = self.exec_file.as_deref().and_then(file_stamp);
+        self.exec_change = None;
+    }
+    /// Record a freshly spawned process, None = watched by pid only
+    fn spawned(&mut self, pid: u32, process: Option<Child>) {
+        self.process = process;
+        self.last_known_pid = Some(pid);
+        self.last_exit = None;
+        self.start_failed = false;
+        self.ready = false;
+        self.ready_overdue = false;
+        self.ready_line = None;
+        self.started_at = Some(Instant::now());
+        self.starts += 1;
+        self.over_memory = false;
+        // First probe one interval after spawn, the process needs time to come up
+        self.health = HealthReport::default();
+        self.next_probe = self.config.health_check.as_ref().map(|check| {
+            Instant::now() + Duration::from_secs(check.interval.unwrap_or(health::DEFAULT_INTERVAL_SECS))
+        });
+        if self.config.is_task() {
+            self.last_run = Some(TaskRun { started_at: unix_now(), duration_ms: None });
+        }
+    }
+    /// How the last process ended, crashes are counted
+    fn record_exit(&mut self, exit: ExitInfo) {
+        if exit.reason == ExitReason::Crashed {
+            self.crashes += 1;
+        }
+        self.last_exit = Some(exit);
+    }
+    /// The process is gone: a running task records how long it took
+    fn finish_run(&mut self) {
+        if let (Some(run), Some(started)) = (self.last_run.as_mut(), self.started_at)
+            && run.duration_ms.is_none()
+        {
+            let duration_ms = started.elapsed().as_millis() as u64;
+            run.duration_ms = Some(duration_ms);
+            let code = self.last_exit.and_then(|e| e.code);
+            info!(service = %self.config.id, duration_ms, code = ?code, event = "task_finished", "Task finished");
         }
     }
 }
@@ -38,339 +368,5151 @@ impl ManagedService {
 /// Include services, order, process related and config path
 /// Global parameter: listen address and keep alive interval
 pub struct ServiceManager {
-    pub services: HashMap<String, ManagedService>,
-    pub service_order: Vec<String>,
+    pub(crate) services: HashMap<String, ManagedService>,
+    pub(crate) service_order: Vec<String>,
     sys: System,
-    config_path: String,
-    pub config_listen: Option<String>,
-    pub keep_alive_interval: u64,
+    config_path: Option<String>,    // None = in-memory, nothing is saved
+    config_content: Option<String>, // main config as last read or written, to spot external edits
+    drop_in_paths: Vec<PathBuf>,
+    pub(crate) config_listen: Option<String>,
+    pub(crate) keep_alive_interval: u64,
+    pub(crate) stop_on_exit: bool,
+    pub(crate) cors: Option<CorsConfig>,
+    pub(crate) auth: Option<AuthConfig>,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) watch_config: bool,
+    pub(crate) change_threshold: Option<ChangeThreshold>,
+    /// Top-level env, inherited by every service
+    pub(crate) global_env: HashMap<String, String>,
+    /// Templates of the main config, by name
+    pub(crate) templates: HashMap<String, serde_yaml::Value>,
+    pub(crate) stats: Arc<ManagerStats>,
+    changes: ChangeTracker,
+    save_notify: Option<Arc<Notify>>,   // Some = saves are coalesced, see defer_saves
+    dirty: bool,                        // config changes not written yet
 }
-impl ServiceManager {
-    pub fn new(config_file: &str) -> Result<Self> {
-        // Read and parse YAML config file
-        let content = std::fs::read_to_string(config_file)
-            .context("Failed to read config file")?;
-        let service_file: ServicesFile = serde_yaml::from_str(&content)
-            .context("Failed to parse YAML")?;
-        // Storage services and their order
-        let mut services = HashMap::new();
-        let mut service_order = Vec::new();
-        // Help to deduplicate of service order
-        let mut seen_ids = HashSet::new();
-        // Detect processes and pids
-        // use System::new() to save memory usage instead of System::new_all()
-        let mut sys = System::new();
-        sys.refresh_processes(ProcessesToUpdate::All, true);
-        // Read services from config
-        for cfg in service_file.services {
-            let id = cfg.id.clone();
-            // Avoid duplication service in order (which is not hash)
-            if seen_ids.contains(&id) {
-                eprintln!("⚠️ Warning: Duplicate service ID '{}' found in config. Skipping duplicate.", id);
-                continue;
-            }
-            // Push service into order to show
-            seen_ids.insert(id.clone());
-            service_order.push(cfg.id.clone());
-
-            let mut svc = ManagedService::new(cfg);
-
-            let exec_name = exec_file_name(&svc.config.exec);
-            // Find if process is already existing
-            let found_proc = sys.processes().values().find(|p| {
-                let _p_name = p.name();
-                p.name().eq_ignore_ascii_case(exec_name)
-            });
-            // If existing, get PIDs
-            if let Some(proc) = found_proc {
-                let pid = proc.pid().as_u32();
-                println!(
-                    "🔗 Adopted existing service: {} (PID: {})",
-                    svc.config.name, pid
-                );
-                svc.last_known_pid = Some(pid); // Catch pid who not started by app manager
-            }
-            services.insert(svc.config.id.clone(), svc);
+/// Resolved command line of a service
+/// Shared by start and the --dry-run output
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LaunchPlan {
+    pub(crate) exec: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) working_dir: Option<String>,
+    /// Variables added to the inherited environment
+    pub(crate) env: HashMap<String, String>,
+    /// Nothing is inherited (env_clear), or not these variables
+    pub(crate) env_clear: bool,
+    pub(crate) env_remove: Vec<String>,
+    pub(crate) shell: bool,
+    /// Program and arguments the script in exec is run with, empty runs exec itself
+    pub(crate) interpreter: Vec<String>,
+    pub(crate) creation_flags: u32,
+    /// Unix only: ids to switch to before exec
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    /// Unix only: nice value set before exec
+    pub(crate) nice: Option<i32>,
+    /// Unix only: own process group / new session
+    pub(crate) process_group: bool,
+    pub(crate) setsid: bool,
+    /// Windows only: CPUs the process may run on
+    pub(crate) affinity_mask: Option<u64>,
+}
+impl LaunchPlan {
+    /// Expand variables and combine paths the same way start does
+    pub fn resolve(config: &ServiceConfig) -> Result<Self> {
+        // Expand OS environment variables (${VAR} / %VAR%) first, then {KEY} from env
+        let strict = config.strict_env.unwrap_or(false);
+        let env = config.resolved_env()?;
+        let expand = |value: &str| expand_system_env(value, strict).map(|value| interpolate(&value, &env));
+        let exec = expand(&config.exec)?;
+        let working_dir = config
+            .working_dir
+            .as_deref()
+            .map(|dir| expand(dir).map(|dir| unquote(&dir).to_string()))
+            .transpose()?;
+        let args = config.args.iter().map(|arg| expand(arg)).collect::<Result<Vec<_>>>()?;
+        let shell = config.shell.unwrap_or(false);
+        // Combine binary path
+        // Paths copied from Explorer come quoted, Command does the quoting itself
+        let exec = match &working_dir {
+            _ if shell => exec,
+            Some(dir) => resolve_exec_path(dir, unquote(&exec)),
+            None => unquote(&exec).to_string(),
+        };
+        let interpreter = match &config.interpreter {
+            _ if shell => Vec::new(),
+            Some(line) => split_command_line(&expand(line)?).map_err(ServiceError::Invalid)?,
+            None => default_interpreter(&exec),
+        };
+        // For windows to process creation flags
+        // Add extra flags 0x00000008 to avoid blocking
+        // Shell mode defaults to 0x08000000 so cmd.exe and its children stay hidden
+        let mut creation_flags = config
+            .windows
+            .as_ref()
+            .and_then(|w| w.creation_flags)
+            .unwrap_or(if shell { 0x08000000 } else { 0x00000008 });
+        // Graceful stop needs its own process group: 0x00000200 (CREATE_NEW_PROCESS_GROUP)
+        if config.windows.as_ref().and_then(|w| w.graceful).unwrap_or(false) {
+            creation_flags |= 0x00000200;
+        }
+        // A priority class replaces the one in creation_flags
+        if let Some(priority) = config.windows.as_ref().and_then(|w| w.priority) {
+            creation_flags = creation_flags & !PRIORITY_CLASSES | priority.creation_flag();
+        }
+        let affinity_mask = config.windows.as_ref().and_then(|w| w.affinity_mask);
+        if affinity_mask == Some(0) {
+            return Err(ServiceError::Invalid("affinity_mask must select at least one CPU".into()).into());
         }
+        if let Some(nice) = config.nice
+            && !(-20..=19).contains(&nice)
+        {
+            return Err(ServiceError::Invalid(format!("nice must be between -20 and 19, got {}", nice)).into());
+        }
+        #[cfg(unix)]
+        let (uid, gid) = resolve_run_as(config)?;
+        #[cfg(not(unix))]
+        let (uid, gid) = (None, None);
         Ok(Self {
-            services,
-            service_order,
-            sys,
-            config_path: config_file.to_string(),
-            config_listen: service_file.listen,
-            keep_alive_interval: service_file.keep_alive.unwrap_or(0),
+            exec,
+            args,
+            working_dir,
+            env,
+            env_clear: config.env_clear.unwrap_or(false),
+            env_remove: config.env_remove.clone().unwrap_or_default(),
+            shell,
+            interpreter,
+            creation_flags,
+            uid,
+            gid,
+            nice: config.nice,
+            process_group: config.unix.as_ref().and_then(|u| u.process_group).unwrap_or(true),
+            setsid: config.unix.as_ref().and_then(|u| u.setsid).unwrap_or(false),
+            affinity_mask,
         })
     }
-    // Check if serivce is already running
-    pub fn is_running(&mut self, id: &str) -> bool {
-        // Check by ID
-        if let Some(svc) = self.services.get_mut(id) {
-            if let Some(child) = &mut svc.process {
-                match child.try_wait() {
-                    Ok(None) => return true,
-                    Ok(Some(_)) | Err(_) => {
-                        svc.process = None;
-                    }
-                }
+    /// Look up the `!secret name` env values, only done right before something is run
+    fn with_secrets(mut self) -> Result<Self> {
+        secrets::resolve_env(&mut self.env)?;
+        Ok(self)
+    }
+    /// Problems with the creation flags that are clear without starting anything
+    pub fn flag_warnings(&self) -> Vec<String> {
+        creation_flag_warnings(self.creation_flags)
+    }
+    /// Program that is started and its arguments, the interpreter when exec is a script
+    pub fn argv(&self) -> (&str, Vec<&str>) {
+        match self.interpreter.split_first() {
+            Some((program, pre)) => {
+                let args = pre.iter().chain([&self.exec]).chain(&self.args).map(String::as_str).collect();
+                (program, args)
             }
+            None => (&self.exec, self.args.iter().map(String::as_str).collect()),
         }
-        // Check already running service by processes PIDs 
-        self.sys.refresh_processes(ProcessesToUpdate::All, true);
-        let (last_pid, exec_name) = match self.services.get(id) {
-            Some(s) => (s.last_known_pid, s.config.exec.clone()),
-            None => return false,
-        };
-
-        if let Some(pid) = last_pid {
-            if self.sys.process(Pid::from_u32(pid)).is_some() {
-                return true;
-            }
+    }
+    /// Command of exec and args, or of the interpreter running exec
+    fn program_command(&self) -> Command {
+        #[cfg(windows)]
+        if let Some((program, pre)) = self.interpreter.split_first()
+            && is_cmd(program)
+        {
+            // cmd.exe parses the rest of its line itself, see cmd_script_line
+            let mut cmd = Command::new(program);
+            cmd.args(pre).raw_arg(cmd_script_line(&self.exec, &self.args));
+            return cmd;
         }
-        // Check already running service by processes names
-        let target = exec_file_name(&exec_name);
-        self.sys.processes().values().any(|p| {
-            let n = p.name();
-            n.eq_ignore_ascii_case(target) || n.eq_ignore_ascii_case(&format!("{}.exe", target))
-        })
+        let (program, args) = self.argv();
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        cmd
     }
-    /// Start
-    pub async fn start(&mut self, id: &str) -> Result<()> {
-        // Check if already running
-        if self.is_running(id) {
-            println!("Service {} is already running.", id);
-            return Ok(());
+    /// Environment of the process: the inherited one (less env_clear / env_remove) plus env
+    fn apply_env(&self, cmd: &mut Command) {
+        if self.env_clear {
+            cmd.env_clear();
+            // Windows programs can't even load some system DLLs without it
+            #[cfg(windows)]
+            if let Some(root) = std::env::var_os("SystemRoot") {
+                cmd.env("SystemRoot", root);
+            }
         }
-
-        let svc = self
-            .services
-            .get_mut(id)
-            .ok_or_else(|| anyhow!("Service id not found"))?;
-        // Combine command args
-        let args = build_args(&svc.config.args, &svc.config.env);
-        // Combine binary path
-        let exec_path = if let Some(dir) = &svc.config.working_dir {
-            Path::new(dir).join(&svc.config.exec)
+        for key in &self.env_remove {
+            cmd.env_remove(key);
+        }
+        cmd.envs(&self.env);
+    }
+    /// Build the command to spawn
+    fn command(&self) -> Command {
+        let mut cmd = if self.shell {
+            shell_command(&self.exec, &self.args)
         } else {
-            Path::new(&svc.config.exec).to_path_buf()
+            self.program_command()
         };
-        // Combine command
-        let mut cmd = Command::new(&exec_path);
-        cmd.args(args);
-
-        if let Some(dir) = &svc.config.working_dir {
+        if let Some(dir) = &self.working_dir {
             cmd.current_dir(dir);
         }
-        // For windows to process creation flags
-        // Add extra flags 0x00000008 to avoid blocking
+        self.apply_env(&mut cmd);
         #[cfg(windows)]
+        cmd.creation_flags(self.creation_flags);
+        #[cfg(unix)]
         {
-            let flags = svc
-                .config
-                .windows
-                .as_ref()
-                .and_then(|w| w.creation_flags)
-                .unwrap_or(0x00000008);
-            cmd.creation_flags(flags);
-        }
-        // Avoid blocking by main process
-        cmd.stdout(Stdio::null()).stderr(Stdio::null()).stdin(Stdio::null());
-        // Run command
-        let child = cmd
-            .spawn()
-            .context(format!("Failed to spawn {}", svc.config.name))?;
-        let pid = child.id().unwrap_or(0);
-        // record process and its pid
-        svc.process = Some(child);
-        svc.last_known_pid = Some(pid);
-
-        println!("Started service \"{}\" (PID: {})", id, pid);
-        Ok(())
-    }
-    /// Stop
-    pub async fn stop(&mut self, id: &str) -> Result<()> {
-        // Stop process
-        let svc = self
-            .services
-            .get_mut(id)
-            .ok_or_else(|| anyhow!("Service id not found"))?;
-
-        // Get the parent process PID
-        // Use last_known_pid, it is same as process handle id
-        let target_pid_u32 = svc.last_known_pid.or_else(|| {
-            svc.process.as_ref().map(|p| p.id().unwrap_or(0))
-        });
-        // Try to clear the process tree (some apps has more than one process)
-        if let Some(pid_val) = target_pid_u32 {
-            if pid_val > 0 {
-                self.sys.refresh_processes(ProcessesToUpdate::All, true);
-                let parent_pid = Pid::from_u32(pid_val);
-
-                // Find all child process of parent process
-                let children: Vec<Pid> = self.sys.processes()
-                    .iter().
-                    filter(|(_, p)| p.parent() == Some(parent_pid))
-                    .map(|(pid, _)| * pid)
-                    .collect();
-
-                // Kill child process first (e.g. Worker)
-                for child_pid in children {
-                    if let Some(proc) = self.sys.process(child_pid) {
-                        if proc.kill() {
-                            println!("Killed child process {}: {}", id, child_pid);
+            if let Some(uid) = self.uid {
+                cmd.uid(uid);
+            }
+            if let Some(gid) = self.gid {
+                cmd.gid(gid);
+            }
+            if let Some(nice) = self.nice {
+                // Runs in the child after fork, only async-signal-safe calls
+                unsafe {
+                    cmd.pre_exec(move || {
+                        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                            return Err(std::io::Error::last_os_error());
                         }
-                    }
+                        Ok(())
+                    });
+                }
+            }
+            // setsid fails for a group leader, so a new session replaces process_group
+            if self.setsid {
+                unsafe {
+                    cmd.pre_exec(|| {
+                        if libc::setsid() == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
                 }
+            } else if self.process_group {
+                cmd.process_group(0);
             }
         }
-        // Kill main process handle (e.g. Monitor)
-        if let Some(mut child) = svc.process.take() {
-            // Try to kill process
-            let _ = child.kill().await;
-            let _ = child.wait().await;
-            println!("Stopped service \"{}\" via handle", id);
-        } else if let Some(pid_val) = target_pid_u32 {
-            // If lose handle (e.g. restart apps), try to use sysinfo to kill main process
-            if let Some(proc) = self.sys.process(Pid::from_u32(pid_val)) {
-                proc.kill();
-                println!("Killed orphaned main process of {}: {}", id, pid_val);
+        cmd
+    }
+    /// Build a lifecycle hook: a shell line run like the service (directory, env, user)
+    fn hook_command(&self, line: &str) -> Command {
+        let mut cmd = shell_command(line, &[]);
+        if let Some(dir) = &self.working_dir {
+            cmd.current_dir(dir);
+        }
+        self.apply_env(&mut cmd);
+        // 0x08000000 (CREATE_NO_WINDOW): no console flashing up for the hook
+        #[cfg(windows)]
+        cmd.creation_flags(0x08000000);
+        #[cfg(unix)]
+        {
+            if let Some(uid) = self.uid {
+                cmd.uid(uid);
+            }
+            if let Some(gid) = self.gid {
+                cmd.gid(gid);
             }
         }
-        // Kill by process name
-        // If still survival under PID killer, use process name to kill
-        // Only use when process is running to prevent kill wrong one
-        let target_exec = svc.config.exec.clone();
-        let target_name = exec_file_name(&target_exec);
-
-        self.sys.refresh_processes(ProcessesToUpdate::All, true);
+        cmd.stdin(Stdio::null()).kill_on_drop(true);
+        cmd
+    }
+}
+/// uid and gid for run_as user / group, names or numeric ids
+/// Without a group the user's primary group is used
+#[cfg(unix)]
+fn resolve_run_as(config: &ServiceConfig) -> Result<(Option<u32>, Option<u32>)> {
+    let run_as = config.run_as.clone().unwrap_or_default();
+    let uid = run_as
+        .user
+        .as_deref()
+        .map(str::trim)
+        .map(|user| {
+            user.parse::<u32>()
+                .ok()
+                .or_else(|| users::get_user_by_name(user).map(|u| u.uid()))
+                .ok_or_else(|| ServiceError::Invalid(format!("Unknown user \"{}\"", user)))
+        })
+        .transpose()?;
+    let gid = match run_as.group.as_deref().map(str::trim) {
+        Some(group) => Some(
+            group
+                .parse::<u32>()
+                .ok()
+                .or_else(|| users::get_group_by_name(group).map(|g| g.gid()))
+                .ok_or_else(|| ServiceError::Invalid(format!("Unknown group \"{}\"", group)))?,
+        ),
+        None => uid.and_then(users::get_user_by_uid).map(|u| u.primary_group_id()),
+    };
+    Ok((uid, gid))
+}
 
-        // Only when escape from PID killer
-        let remining_pids: Vec<Pid> = self.sys.processes().values()
-            .filter(|p| p.name().eq_ignore_ascii_case(target_name))
-            .map(|p| p.pid())
-            .collect();
+// Windows process creation flags checked by creation_flag_warnings
+const DEBUG_PROCESS: u32 = 0x00000001;
+const DEBUG_ONLY_THIS_PROCESS: u32 = 0x00000002;
+const CREATE_SUSPENDED: u32 = 0x00000004;
+const DETACHED_PROCESS: u32 = 0x00000008;
+const CREATE_NEW_CONSOLE: u32 = 0x00000010;
+const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+const EXTENDED_STARTUPINFO_PRESENT: u32 = 0x00080000;
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+#[cfg(windows)]
+const CREATE_DEFAULT_ERROR_MODE: u32 = 0x04000000;
+/// NORMAL, IDLE, HIGH, REALTIME, BELOW_NORMAL, ABOVE_NORMAL
+const PRIORITY_CLASSES: u32 = 0x20 | 0x40 | 0x80 | 0x100 | 0x4000 | 0x8000;
+/// Every flag documented for CreateProcess
+const KNOWN_FLAGS: u32 = 0xFF7D_FFFF;
 
-        if !remining_pids.is_empty() {
-            println!("⚠️ Warning: Found lingering processes for {}, cleaning up by name...", id);
-            for pid in remining_pids {
-                if let Some(proc) = self.sys.process(pid) {
-                    proc.kill();
-                    println!("Killed lingering process {} (PID: {})", target_name, pid);
-                }
-            }
-        }
+/// Contradictory or harmful creation flag combinations
+fn creation_flag_warnings(flags: u32) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let has = |flag: u32| flags & flag != 0;
+    if has(DETACHED_PROCESS) && has(CREATE_NEW_CONSOLE) {
+        warnings.push("DETACHED_PROCESS and CREATE_NEW_CONSOLE together make the start fail".into());
+    }
+    if has(CREATE_NO_WINDOW) && (has(DETACHED_PROCESS) || has(CREATE_NEW_CONSOLE)) {
+        warnings.push(
+            "CREATE_NO_WINDOW is ignored together with DETACHED_PROCESS or CREATE_NEW_CONSOLE".into(),
+        );
+    }
+    if (flags & PRIORITY_CLASSES).count_ones() > 1 {
+        warnings.push("More than one priority class is set".into());
+    }
+    if has(CREATE_SUSPENDED) {
+        warnings.push("CREATE_SUSPENDED: the process is never resumed and won't run".into());
+    }
+    if has(DEBUG_PROCESS | DEBUG_ONLY_THIS_PROCESS) {
+        warnings.push("DEBUG_PROCESS: AppManager is no debugger, the process will hang".into());
+    }
+    if has(EXTENDED_STARTUPINFO_PRESENT) {
+        warnings.push("EXTENDED_STARTUPINFO_PRESENT is managed by the runtime, don't set it".into());
+    }
+    if has(CREATE_NEW_PROCESS_GROUP) && has(DETACHED_PROCESS) {
+        warnings.push(
+            "A DETACHED_PROCESS has no console, CTRL_BREAK (windows.graceful) can't reach it".into(),
+        );
+    }
+    if flags & !KNOWN_FLAGS != 0 {
+        warnings.push(format!("Unknown flag bits {:#010x}", flags & !KNOWN_FLAGS));
+    }
+    warnings
+}
 
-        // clear PID state
-        svc.last_known_pid = None;
+/// Combine working_dir and exec
+/// - exec with a path separator: relative to working_dir (absolute paths stay as-is)
+/// - bare name: always left to the OS PATH lookup, "./app" runs the one in working_dir
+fn resolve_exec_path(working_dir: &str, exec: &str) -> String {
+    if exec.contains(['/', '\\']) {
+        Path::new(working_dir).join(exec).to_string_lossy().into_owned()
+    } else {
+        exec.to_string()
+    }
+}
 
+/// How long a graceful stop (windows.graceful without stop_timeout) may take before the kill
+const DEFAULT_GRACEFUL_STOP_SECS: u64 = 5;
 
-        Ok(())
+/// Post WM_CLOSE to the top-level windows of a process (Windows)
+/// GUI apps treat it like the user closing the window, false if it has none
+#[cfg(windows)]
+fn post_close(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{HWND, LPARAM};
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE,
+    };
+    struct Search {
+        pid: u32,
+        found: bool,
     }
-    /// Restart
-    pub async fn restart(&mut self, id: &str) -> Result<()> {
-        self.stop(id).await?;
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        self.start(id).await
+    unsafe extern "system" fn visit(hwnd: HWND, lparam: LPARAM) -> windows_sys::core::BOOL {
+        let search = unsafe { &mut *(lparam as *mut Search) };
+        let mut owner = 0u32;
+        unsafe { GetWindowThreadProcessId(hwnd, &mut owner) };
+        if owner == search.pid && unsafe { PostMessageW(hwnd, WM_CLOSE, 0, 0) } != 0 {
+            search.found = true;
+        }
+        1 // keep enumerating, a process may own several windows
     }
-    /// List
-    pub fn list(&mut self) -> Vec<ServiceStatusSnapshot> {
-        let mut results = Vec::new();
-
-        let order = self.service_order.clone();
+    let mut search = Search { pid, found: false };
+    unsafe { EnumWindows(Some(visit), &mut search as *mut Search as LPARAM) };
+    search.found
+}
 
-        for id in order {
-            if self.services.contains_key(&id) {
-                
-                let running = self.is_running(&id);
-                
-                if let Some(svc) = self.services.get(&id) {
-                     results.push(ServiceStatusSnapshot {
-                        config: svc.config.clone(),
-                        running,
-                        pid: svc.last_known_pid,
-                    });
-                }
-            }
+/// Send CTRL_BREAK to a console process group (Windows)
+/// The service must be spawned with CREATE_NEW_PROCESS_GROUP (windows.graceful)
+/// Only processes sharing our console can be signaled, so we borrow the child's console
+#[cfg(windows)]
+fn send_ctrl_break(pid: u32) -> bool {
+    use windows_sys::Win32::System::Console::{
+        ATTACH_PARENT_PROCESS, AttachConsole, CTRL_BREAK_EVENT, FreeConsole,
+        GenerateConsoleCtrlEvent,
+    };
+    unsafe {
+        FreeConsole();
+        if AttachConsole(pid) == 0 {
+            AttachConsole(ATTACH_PARENT_PROCESS);
+            return false;
         }
-        results
+        // The group id is the PID of the group leader, our own process is not in it
+        let sent = GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) != 0;
+        FreeConsole();
+        AttachConsole(ATTACH_PARENT_PROCESS);
+        sent
     }
+}
 
-    pub fn save_to_disk(&self) -> Result<()> {
-        let mut configs = Vec::new();
-        let mut saved_ids = HashSet::new();
+/// Process group a service leads, None when it shares one (adopted, or AppManager's own) (Unix)
+#[cfg(unix)]
+fn own_process_group(pid: u32) -> Option<i32> {
+    let pid = i32::try_from(pid).ok().filter(|pid| *pid > 0)?;
+    let group = unsafe { libc::getpgid(pid) };
+    (group == pid && group != unsafe { libc::getpgrp() }).then_some(group)
+}
 
-        for id in &self.service_order {
-            if saved_ids.contains(id) { continue; }
+/// Send a signal to the process group a service leads, otherwise to the process alone (Unix)
+#[cfg(unix)]
+fn signal_service(pid: u32, signal: i32) -> bool {
+    // kill(0) would signal AppManager's own group
+    let Some(pid) = i32::try_from(pid).ok().filter(|pid| *pid > 0) else { return false };
+    let sent = match own_process_group(pid as u32) {
+        Some(group) => unsafe { libc::killpg(group, signal) },
+        None => unsafe { libc::kill(pid, signal) },
+    };
+    sent == 0
+}
 
-            if let Some(svc) = self.services.get(id) {
-                configs.push(svc.config.clone());
-                saved_ids.insert(id.clone());
+/// Job object holding a service's process tree (Windows)
+/// Children and grandchildren join it automatically, so stop can end them all at once
+/// No KILL_ON_JOB_CLOSE: closing the handle (e.g. AppManager exits) leaves the processes running
+#[cfg(windows)]
+pub struct JobObject(windows_sys::Win32::Foundation::HANDLE);
+// A job handle is a process-wide kernel handle, usable from any thread
+#[cfg(windows)]
+unsafe impl Send for JobObject {}
+#[cfg(windows)]
+unsafe impl Sync for JobObject {}
+#[cfg(windows)]
+impl JobObject {
+    /// Put a process into a new job, None if Windows refuses
+    fn assign(process: std::os::windows::io::RawHandle) -> Option<Self> {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+            if job.is_null() {
+                return None;
             }
+            if AssignProcessToJobObject(job, process as _) == 0 {
+                CloseHandle(job);
+                return None;
+            }
+            Some(Self(job))
         }
-        let wrapper = ServicesFile {
-            services: configs,
-            listen: self.config_listen.clone(),
-            keep_alive: if self.keep_alive_interval > 0 { Some(self.keep_alive_interval) } else { None },
-        };
+    }
+    /// Kill every process in the job
+    fn terminate(&self) -> bool {
+        unsafe { windows_sys::Win32::System::JobObjects::TerminateJobObject(self.0, 1) != 0 }
+    }
+}
+#[cfg(windows)]
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.0) };
+    }
+}
 
-        let yaml = serde_yaml::to_string(&wrapper)?;
+/// Error of a start where no exec candidate could be spawned
+fn spawn_error(name: &str, failures: &[(String, std::io::Error)]) -> anyhow::Error {
+    if let [(_, e)] = failures {
+        return anyhow!("Failed to spawn {}: {}", name, e);
+    }
+    let tried: Vec<String> = failures
+        .iter()
+        .map(|(exec, e)| format!("{} ({})", exec, e))
+        .collect();
+    anyhow!("Failed to spawn {}, tried: {}", name, tried.join("; "))
+}
 
-        std::fs::write(&self.config_path, yaml)?;
-        Ok(())
+/// Put a process spawned suspended into its job, apply the affinity and resume it (Windows)
+/// false if it could not be resumed
+#[cfg(windows)]
+fn run_suspended(
+    svc: &mut ManagedService,
+    id: &str,
+    process: std::os::windows::io::RawHandle,
+    pid: u32,
+    plan: &LaunchPlan,
+) -> bool {
+    svc.job = JobObject::assign(process);
+    if svc.job.is_none() {
+        warn!(service = %id, "Could not create a job object, stop falls back to the process tree");
+    }
+    // Set before the resume, children started by the process inherit it
+    if let Some(mask) = plan.affinity_mask
+        && !set_affinity(process, mask)
+    {
+        warn!(service = %id, mask = %format!("{:#x}", mask), "Failed to set the CPU affinity");
     }
+    // CREATE_SUSPENDED asked for in creation_flags is left to the user
+    plan.creation_flags & CREATE_SUSPENDED != 0 || resume_process(pid)
+}
 
-    pub fn upsert_service(&mut self, config: ServiceConfig) -> Result<()> {
-        let id = config.id.clone();
-        if !self.service_order.contains(&id) {
-            self.service_order.push(id.clone());
+/// Start a plan suspended under another account (Windows)
+/// Returns the pid and the process handle, which the caller closes
+#[cfg(windows)]
+fn spawn_with_logon(
+    plan: &LaunchPlan,
+    user: &str,
+    password: &str,
+) -> std::io::Result<(u32, windows_sys::Win32::Foundation::HANDLE)> {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{
+        CreateProcessWithLogonW, LOGON_WITH_PROFILE, PROCESS_INFORMATION, STARTF_USESHOWWINDOW, STARTUPINFOW,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::SW_HIDE;
+    let wide = |s: &str| s.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>();
+    // DOMAIN\user, user@domain (no domain then) or a local account
+    let (domain, name) = match user.trim().split_once('\\') {
+        Some((domain, name)) => (Some(domain), name),
+        None if user.contains('@') => (None, user.trim()),
+        None => (Some("."), user.trim()),
+    };
+    let (name, domain, password) = (wide(name), domain.map(wide), wide(password));
+    let dir = plan.working_dir.as_deref().map(wide);
+    let mut line = wide(&windows_command_line(plan));
+    // Only these flags are accepted by the logon API, hidden windows go through STARTUPINFO
+    let flags = plan.creation_flags
+        & (CREATE_NEW_CONSOLE | CREATE_NEW_PROCESS_GROUP | CREATE_DEFAULT_ERROR_MODE | PRIORITY_CLASSES)
+        | CREATE_SUSPENDED;
+    unsafe {
+        let mut startup: STARTUPINFOW = std::mem::zeroed();
+        startup.cb = std::mem::size_of::<STARTUPINFOW>() as u32;
+        if plan.creation_flags & (CREATE_NO_WINDOW | DETACHED_PROCESS) != 0 {
+            startup.dwFlags = STARTF_USESHOWWINDOW;
+            startup.wShowWindow = SW_HIDE as u16;
         }
-
-        if let Some(svc) = self.services.get_mut(&config.id) {
-            svc.config = config;
-        } else {
-            self.services
-                .insert(config.id.clone(), ManagedService::new(config));
+        let mut info: PROCESS_INFORMATION = std::mem::zeroed();
+        let ok = CreateProcessWithLogonW(
+            name.as_ptr(),
+            domain.as_ref().map_or(std::ptr::null(), |d| d.as_ptr()),
+            password.as_ptr(),
+            LOGON_WITH_PROFILE,
+            std::ptr::null(),
+            line.as_mut_ptr(),
+            flags,
+            std::ptr::null(),
+            dir.as_ref().map_or(std::ptr::null(), |d| d.as_ptr()),
+            &startup,
+            &mut info,
+        );
+        if ok == 0 {
+            return Err(std::io::Error::last_os_error());
         }
-        self.save_to_disk()
+        CloseHandle(info.hThread);
+        Ok((info.dwProcessId, info.hProcess))
     }
+}
 
-    pub async fn remove_service(&mut self, id: &str) -> Result<()> {
-        let _ = self.stop(id).await;
+/// Command line of a plan the way Command would build it (Windows)
+#[cfg(windows)]
+fn windows_command_line(plan: &LaunchPlan) -> String {
+    let (program, params) = windows_program_and_params(plan);
+    match params.is_empty() {
+        true => quote_windows_arg(&program),
+        false => format!("{} {}", quote_windows_arg(&program), params),
+    }
+}
+
+/// Program of a plan and the rest of its command line, quoted (Windows)
+#[cfg(windows)]
+fn windows_program_and_params(plan: &LaunchPlan) -> (String, String) {
+    if plan.shell {
+        let mut line = format!("/C {}", plan.exec);
+        for arg in &plan.args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        return ("cmd".to_string(), line);
+    }
+    if let Some((program, pre)) = plan.interpreter.split_first()
+        && is_cmd(program)
+    {
+        let mut parts: Vec<String> = pre.iter().map(|arg| quote_windows_arg(arg)).collect();
+        parts.push(cmd_script_line(&plan.exec, &plan.args));
+        return (program.clone(), parts.join(" "));
+    }
+    let (program, args) = plan.argv();
+    let params: Vec<String> = args.into_iter().map(quote_windows_arg).collect();
+    (program.to_string(), params.join(" "))
+}
+
+/// Whether AppManager runs elevated (as Administrator with UAC on) (Windows)
+#[cfg(windows)]
+fn is_elevated() -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::Security::{GetTokenInformation, TOKEN_ELEVATION, TOKEN_QUERY, TokenElevation};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+    unsafe {
+        let mut token: HANDLE = std::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+        let mut size = 0u32;
+        let ok = GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut TOKEN_ELEVATION as *mut _,
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        );
+        CloseHandle(token);
+        ok != 0 && elevation.TokenIsElevated != 0
+    }
+}
+
+/// Start a plan elevated through the runas verb, the user is asked for consent (Windows)
+/// Returns the pid and the process handle, which the caller closes
+#[cfg(windows)]
+fn spawn_with_runas(plan: &LaunchPlan) -> std::io::Result<(u32, windows_sys::Win32::Foundation::HANDLE)> {
+    use windows_sys::Win32::System::Threading::GetProcessId;
+    use windows_sys::Win32::UI::Shell::{
+        SEE_MASK_FLAG_NO_UI, SEE_MASK_NOASYNC, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW, ShellExecuteExW,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{SW_HIDE, SW_SHOWNORMAL};
+    let wide = |s: &str| s.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>();
+    let (program, params) = windows_program_and_params(plan);
+    let (verb, file, params) = (wide("runas"), wide(&program), wide(&params));
+    let dir = plan.working_dir.as_deref().map(wide);
+    let mut info = SHELLEXECUTEINFOW {
+        cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
+        fMask: SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC | SEE_MASK_FLAG_NO_UI,
+        lpVerb: verb.as_ptr(),
+        lpFile: file.as_ptr(),
+        lpParameters: params.as_ptr(),
+        lpDirectory: dir.as_ref().map_or(std::ptr::null(), |d| d.as_ptr()),
+        // Hidden like CREATE_NO_WINDOW / DETACHED_PROCESS would make it
+        nShow: if plan.creation_flags & (CREATE_NO_WINDOW | DETACHED_PROCESS) != 0 { SW_HIDE } else { SW_SHOWNORMAL },
+        ..Default::default()
+    };
+    unsafe {
+        if ShellExecuteExW(&mut info) == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if info.hProcess.is_null() {
+            return Err(std::io::Error::other("no process was started"));
+        }
+        Ok((GetProcessId(info.hProcess), info.hProcess))
+    }
+}
+
+/// Whether an interpreter is cmd.exe, which needs its own quoting (Windows)
+#[cfg(windows)]
+fn is_cmd(program: &str) -> bool {
+    let name = exec_file_name(program);
+    name.eq_ignore_ascii_case("cmd") || name.eq_ignore_ascii_case("cmd.exe")
+}
+
+/// Script and arguments for cmd /C, wrapped in one more pair of quotes (Windows)
+/// cmd.exe drops the first and the last quote of the line, so a quoted script path
+/// and quoted arguments survive. Arguments with & | < > ^ ( ) are quoted too
+#[cfg(windows)]
+fn cmd_script_line(script: &str, args: &[String]) -> String {
+    let quote = |arg: &str| match arg.contains(['&', '|', '<', '>', '^', '(', ')']) {
+        true => format!("\"{}\"", arg.replace('"', "\"\"")),
+        false => quote_windows_arg(arg),
+    };
+    let mut parts = vec![quote(script)];
+    parts.extend(args.iter().map(|arg| quote(arg)));
+    format!("\"{}\"", parts.join(" "))
+}
+
+/// Quote an argument for the usual Windows command line parsing (CommandLineToArgvW)
+#[cfg(windows)]
+fn quote_windows_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        // Backslashes only escape when a quote follows
+        let escapes = if c == '"' { backslashes * 2 + 1 } else { backslashes };
+        quoted.extend(std::iter::repeat_n('\\', escapes));
+        quoted.push(c);
+        backslashes = 0;
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// Restrict a process to the CPUs in mask (Windows)
+#[cfg(windows)]
+fn set_affinity(process: std::os::windows::io::RawHandle, mask: u64) -> bool {
+    use windows_sys::Win32::System::Threading::SetProcessAffinityMask;
+    unsafe { SetProcessAffinityMask(process as _, mask as usize) != 0 }
+}
+
+/// Resume the threads of a process spawned with CREATE_SUSPENDED (Windows)
+#[cfg(windows)]
+fn resume_process(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, TH32CS_SNAPTHREAD, THREADENTRY32, Thread32First, Thread32Next,
+    };
+    use windows_sys::Win32::System::Threading::{OpenThread, ResumeThread, THREAD_SUSPEND_RESUME};
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return false;
+        }
+        let mut entry: THREADENTRY32 = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+        let mut resumed = false;
+        let mut more = Thread32First(snapshot, &mut entry) != 0;
+        while more {
+            if entry.th32OwnerProcessID == pid {
+                let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+                if !thread.is_null() {
+                    resumed |= ResumeThread(thread) != u32::MAX;
+                    CloseHandle(thread);
+                }
+            }
+            more = Thread32Next(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+        resumed
+    }
+}
+
+/// Output file of stdio: file, appended to across restarts
+fn log_file_path(config: &ServiceConfig, working_dir: Option<&str>) -> Result<PathBuf> {
+    let Some(file) = config.log_file.as_deref() else {
+        return Err(ServiceError::Invalid(format!(
+            "Service '{}' uses stdio: file but has no log_file",
+            config.id
+        ))
+        .into());
+    };
+    let path = match working_dir {
+        Some(dir) => Path::new(dir).join(unquote(file)),
+        None => PathBuf::from(unquote(file)),
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create log directory \"{}\"", parent.display()))?;
+    }
+    Ok(path)
+}
+/// Open log_file for the process to write into
+fn open_log_file(config: &ServiceConfig, working_dir: Option<&str>) -> Result<std::fs::File> {
+    let path = log_file_path(config, working_dir)?;
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file \"{}\"", path.display()))
+}
+/// Open log_file for AppManager to write the captured output into, rotated as configured
+fn open_rotating_log(config: &ServiceConfig, working_dir: Option<&str>) -> Result<SharedLog> {
+    let path = log_file_path(config, working_dir)?;
+    let rotation = Rotation {
+        max_size: config.log_max_size.map(|size| size.0),
+        every: config.log_rotate,
+        max_files: config.log_max_files.unwrap_or(log_file::DEFAULT_MAX_FILES),
+        compress: config.log_compress.unwrap_or(false),
+    };
+    let log = RotatingLog::open(&path, rotation)
+        .with_context(|| format!("Failed to open log file \"{}\"", path.display()))?;
+    Ok(Arc::new(tokio::sync::Mutex::new(log)))
+}
+/// Log file shared by the stdout and stderr readers of a process
+type SharedLog = Arc<tokio::sync::Mutex<RotatingLog>>;
+
+/// Lines a slow log subscriber may fall behind before it skips ahead
+const OUTPUT_BUFFER_LINES: usize = 256;
+/// Last output lines kept for GET .../logs?tail=N
+pub const OUTPUT_HISTORY_LINES: usize = 1000;
+/// Longer output lines are cut, so a missing newline can't grow the buffer
+const MAX_LINE_BYTES: usize = 8 * 1024;
+/// A stop in progress, carried from asking the service to exit to confirming it is gone
+struct StopRun {
+    pid: Option<u32>,
+    /// Descendants of the main process, found before anything was asked to exit
+    tree: Vec<Pid>,
+    was_running: bool,
+    via_stop_cmd: bool,
+    /// The exit was asked for: the process and how long it gets
+    exit_wait: Option<(u32, Duration)>,
+    /// It exited within that time
+    on_request: bool,
+}
+impl StopRun {
+    fn exit_waited(&mut self, id: &str, exited: bool) {
+        let Some((pid, timeout)) = self.exit_wait else { return };
+        self.on_request = exited;
+        match (exited, self.via_stop_cmd) {
+            (true, true) => info!(service = %id, pid, event = "stopped", "Service exited after stop_cmd"),
+            (true, false) => info!(service = %id, pid, event = "stopped", "Service exited gracefully"),
+            (false, true) => {
+                warn!(service = %id, pid, timeout_secs = timeout.as_secs(), "Service still running after stop_cmd, killing it")
+            }
+            (false, false) => {
+                warn!(service = %id, pid, timeout_secs = timeout.as_secs(), "Service ignored the stop request, killing it")
+            }
+        }
+    }
+}
+/// Lifecycle hooks of a service
+#[derive(Debug, Clone, Copy)]
+enum Hook {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+}
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Self::PreStart => "pre_start",
+            Self::PostStart => "post_start",
+            Self::PreStop => "pre_stop",
+            Self::PostStop => "post_stop",
+        }
+    }
+}
+/// stop_cmd of a stop, prepared under the manager lock and run without it
+struct StopCmd {
+    pid: u32,
+    /// How long the service gets to exit once the command succeeded
+    wait: Duration,
+    command: Result<Command>,
+    timeout: u64,
+}
+impl StopCmd {
+    async fn run(self, id: &str, run: &mut StopRun) {
+        match run_command_line(self.command, self.timeout).await {
+            Ok(()) => run.exit_wait = Some((self.pid, self.wait)),
+            Err(e) => {
+                warn!(service = %id, error = %format!("{:#}", e), event = "stop_cmd_failed", "stop_cmd failed, killing the service");
+            }
+        }
+    }
+}
+/// A lifecycle hook, prepared under the manager lock and run without it
+struct HookRun {
+    id: String,
+    hook: Hook,
+    command: Result<Command>,
+    timeout: u64,
+    on_failure: HookFailure,
+}
+impl HookRun {
+    /// Run the hook and wait for it
+    /// Failures are logged, only a hook with on_failure: abort returns them
+    async fn run(self) -> Result<()> {
+        let (id, hook) = (self.id, self.hook);
+        match run_command_line(self.command, self.timeout).await {
+            Ok(()) => {
+                info!(service = %id, hook = hook.name(), event = "hook_done", "Hook finished");
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    service = %id,
+                    hook = hook.name(),
+                    error = %format!("{:#}", e),
+                    event = "hook_failed",
+                    "Hook failed",
+                );
+                match self.on_failure {
+                    HookFailure::Continue => Ok(()),
+                    HookFailure::Abort => Err(anyhow!("{} hook of '{}' failed: {:#}", hook.name(), id, e)),
+                }
+            }
+        }
+    }
+}
+/// Command for a line run the way hooks run (shell, working_dir, run_as)
+fn command_line(config: &ServiceConfig, line: &str) -> Result<Command> {
+    Ok(LaunchPlan::resolve(config)?.with_secrets()?.hook_command(line))
+}
+/// Run a command from command_line and wait for it
+/// Fails on a non-zero exit, killed after `timeout` seconds
+async fn run_command_line(command: Result<Command>, timeout: u64) -> Result<()> {
+    let mut command = command?;
+    let run = command.output();
+    match tokio::time::timeout(Duration::from_secs(timeout), run).await {
+        Ok(Ok(output)) if output.status.success() => Ok(()),
+        Ok(Ok(output)) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(anyhow!("{} {}", output.status, stderr.trim()))
+        }
+        Ok(Err(e)) => Err(anyhow!("failed to run: {}", e)),
+        // Dropping the future kills the command
+        Err(_) => Err(anyhow!("killed after {}s", timeout)),
+    }
+}
+/// Wait before the second keep-alive restart, doubled for each further one
+const RESTART_BACKOFF_BASE: Duration = Duration::from_secs(5);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// A service running this long is considered stable again
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+/// A replaced exec must stay unchanged this long before the restart, a copy may still be running
+const UPDATE_SETTLE: Duration = Duration::from_secs(5);
+/// How long a started service may take to pass ready_when, unless its timeout is set
+const DEFAULT_READY_TIMEOUT_SECS: u64 = 30;
+/// Pause between readiness probes while a dependent waits
+const READY_POLL: Duration = Duration::from_millis(200);
+/// Output lines quoted in the error of a start that failed during its startup grace
+const STARTUP_OUTPUT_LINES: usize = 5;
+/// How long a hook may run, unless hook_timeout_secs is set
+const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 30;
+/// How long stop waits for killed processes to disappear, unless kill_timeout_secs is set
+const DEFAULT_KILL_TIMEOUT_SECS: u64 = 5;
+
+/// ready_when.log_matches of a spawn: set once an output line matched
+#[derive(Clone)]
+struct ReadyLine {
+    pattern: Regex,
+    seen: Arc<AtomicBool>,
+}
+
+/// ready_when.log_matches as a regular expression
+fn ready_pattern(pattern: &str) -> Result<Regex, ServiceError> {
+    Regex::new(pattern).map_err(|e| ServiceError::Invalid(format!("Invalid ready_when.log_matches: {}", e)))
+}
+
+/// Whether another process listens on a port
+fn port_taken(port: u16) -> bool {
+    // Another program may listen on only one of these, on Windows and macOS a bind on 0.0.0.0 still works then
+    // Other errors (no IPv6 on this machine, a port below 1024 without root) are no conflict
+    ["0.0.0.0", "127.0.0.1", "::"].into_iter().any(|ip| {
+        std::net::TcpListener::bind((ip, port)).is_err_and(|e| match e.kind() {
+            std::io::ErrorKind::AddrInUse => true,
+            // WSAEACCES, the port is held with exclusive access
+            #[cfg(windows)]
+            std::io::ErrorKind::PermissionDenied => true,
+            _ => false,
+        })
+    })
+}
+
+/// Whether the port of ready_when takes connections, on IPv4 or IPv6 loopback
+async fn probe_ready_port(port: Option<u16>) -> bool {
+    let Some(port) = port else {
+        return true;
+    };
+    health::probe_tcp(&format!("127.0.0.1:{}", port), READY_POLL).await.is_ok()
+        || health::probe_tcp(&format!("[::1]:{}", port), READY_POLL).await.is_ok()
+}
+
+/// Progress of start_many, kept between its steps so a shared manager can be unlocked while waiting
+#[derive(Default)]
+struct StartPlan {
+    waves: Vec<Vec<String>>,
+    failed: HashSet<String>,
+    results: Vec<(String, Result<()>)>,
+}
+impl StartPlan {
+    fn finish(&mut self, id: String, result: Result<()>) {
+        if result.is_err() {
+            self.failed.insert(id.clone());
+        }
+        self.results.push((id, result));
+    }
+}
+
+async fn wait_start_delay(id: &str, delay: u64) {
+    if delay > 0 {
+        info!(service = %id, delay_secs = delay, "Waiting start_delay");
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+    }
+}
+
+/// The startup grace periods of a wave are waited out together
+async fn wait_wave_grace(spawned: &[(String, u64)]) {
+    let max_grace = spawned.iter().map(|(_, grace)| *grace).max().unwrap_or(0);
+    if max_grace > 0 {
+        tokio::time::sleep(Duration::from_secs(max_grace)).await;
+    }
+}
+
+/// Captured output of a service: live subscribers and the last lines for late readers
+#[derive(Clone)]
+pub struct ServiceOutput {
+    live: broadcast::Sender<String>,
+    recent: Arc<std::sync::Mutex<VecDeque<String>>>,
+}
+impl ServiceOutput {
+    fn new() -> Self {
+        Self {
+            live: broadcast::channel(OUTPUT_BUFFER_LINES).0,
+            recent: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+        }
+    }
+    fn push(&self, line: String) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == OUTPUT_HISTORY_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+        // Nobody listening is fine, the line is dropped
+        let _ = self.live.send(line);
+    }
+    /// Follow new lines
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.live.subscribe()
+    }
+    /// The last `lines` lines, and the lines after them
+    /// Taken together, so nothing is missed or repeated in between
+    pub fn tail(&self, lines: usize) -> (Vec<String>, broadcast::Receiver<String>) {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let skip = recent.len().saturating_sub(lines);
+        (recent.iter().skip(skip).cloned().collect(), self.live.subscribe())
+    }
+}
+
+/// Forward output of a captured pipe line by line until it closes
+async fn pump_lines<R: AsyncRead + Unpin>(
+    pipe: R,
+    output: ServiceOutput,
+    mut log: Option<SharedLog>,
+    ready: Option<ReadyLine>,
+) {
+    let mut reader = BufReader::new(pipe);
+    let mut line = Vec::new();
+    loop {
+        let buf = match reader.fill_buf().await {
+            Ok(buf) if !buf.is_empty() => buf,
+            _ => break,
+        };
+        let (chunk, complete) = match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => (&buf[..end], true),
+            None => (buf, false),
+        };
+        let room = MAX_LINE_BYTES.saturating_sub(line.len());
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let used = chunk.len() + usize::from(complete);
+        reader.consume(used);
+        if complete {
+            send_line(&output, &mut log, ready.as_ref(), &mut line).await;
+        }
+    }
+    if !line.is_empty() {
+        send_line(&output, &mut log, ready.as_ref(), &mut line).await;
+    }
+}
+
+async fn send_line(output: &ServiceOutput, log: &mut Option<SharedLog>, ready: Option<&ReadyLine>, line: &mut Vec<u8>) {
+    let text = String::from_utf8_lossy(line).trim_end_matches('\r').to_string();
+    line.clear();
+    if let Some(ready) = ready
+        && ready.pattern.is_match(&text)
+    {
+        ready.seen.store(true, Ordering::Relaxed);
+    }
+    let failed = match log {
+        Some(file) => file.lock().await.write_line(&text).await.err(),
+        None => None,
+    };
+    // A full disk must not end the output stream, the file is given up on
+    if let Some(e) = failed {
+        warn!(error = %e, event = "log_write_failed", "Failed to write the service log, no longer writing it");
+        *log = None;
+    }
+    output.push(text);
+}
+
+/// Compare a process name with an exec file name ("app" also matches "app.exe")
+fn process_name_matches(name: &OsStr, exec_name: &str) -> bool {
+    if name.eq_ignore_ascii_case(exec_name) || name.eq_ignore_ascii_case(format!("{}.exe", exec_name)) {
+        return true;
+    }
+    // Linux keeps only the first 15 bytes of a process name
+    cfg!(target_os = "linux") && name.len() == 15 && exec_name.as_bytes().starts_with(name.as_encoded_bytes())
+}
+
+/// Recognizes a process of a service that was not started by this manager, by its match strategy:
+/// pid: none (only the spawned or recorded pid), name: file name and arguments,
+/// path: full executable path too (default), cmdline: text in the command line
+/// Arguments are compared when sysinfo can read them. With a pid_file only the process it names matches
+#[derive(Clone)]
+struct ProcessMatcher {
+    strategy: MatchStrategy,
+    /// None: the exec couldn't be resolved, no process matches by name or path
+    name: Option<String>,
+    /// The resolved exec, with the path strategy (None: not found on PATH)
+    exe: Option<PathBuf>,
+    /// None for shell commands, run through cmd/sh
+    args: Option<Vec<String>>,
+    cmdline_contains: Option<String>,
+    /// Some: the service has a pid_file, holding this pid (None: missing or unreadable)
+    pid_file: Option<Option<u32>>,
+}
+impl ProcessMatcher {
+    /// Resolve the exec like a start would, pid_file is left to process_matcher
+    fn new(config: &ServiceConfig) -> Self {
+        let strategy = match_strategy(config);
+        let cmdline_contains = config.process_match.as_ref().and_then(|m| m.cmdline_contains()).map(String::from);
+        let plan = match LaunchPlan::resolve(config) {
+            Ok(plan) => plan,
+            Err(e) => {
+                if matches!(strategy, MatchStrategy::Name | MatchStrategy::Path) {
+                    warn!(service = %config.id, error = %format!("{:#}", e), "Can't resolve the exec, running processes are not matched");
+                }
+                return Self { strategy, name: None, exe: None, args: None, cmdline_contains, pid_file: None };
+            }
+        };
+        // Shell commands run through cmd/sh, only the name is comparable
+        if plan.shell {
+            let name = Some(exec_file_name(&config.exec).to_string());
+            return Self { strategy, name, exe: None, args: None, cmdline_contains, pid_file: None };
+        }
+        // Scripts run as their interpreter, with the script as an argument
+        let (program, args) = plan.argv();
+        let name = Some(exec_file_name(program).to_string());
+        // A bare name is looked up on PATH like the OS does
+        let exe = match PathBuf::from(program) {
+            _ if strategy != MatchStrategy::Path => None,
+            path if path.components().count() > 1 => Some(path),
+            _ => find_on_path(program),
+        };
+        let args = Some(args.into_iter().map(String::from).collect());
+        Self { strategy, name, exe, args, cmdline_contains, pid_file: None }
+    }
+    fn matches(&self, p: &sysinfo::Process) -> bool {
+        if let Some(pid) = self.pid_file {
+            return pid == Some(p.pid().as_u32());
+        }
+        match self.strategy {
+            MatchStrategy::Pid => false,
+            MatchStrategy::Cmdline => self.matches_program(p),
+            MatchStrategy::Name | MatchStrategy::Path => self.matches_program(p) && self.matches_args(p),
+        }
+    }
+    /// Same program: file name, and the full path when it is known on both sides
+    /// (cmdline: the command line containing match.cmdline_contains)
+    fn matches_program(&self, p: &sysinfo::Process) -> bool {
+        if self.strategy == MatchStrategy::Cmdline {
+            let Some(text) = &self.cmdline_contains else { return false };
+            let cmdline: Vec<_> = p.cmd().iter().map(|a| a.to_string_lossy()).collect();
+            return cmdline.join(" ").contains(text.as_str());
+        }
+        if !self.name.as_deref().is_some_and(|name| process_name_matches(p.name(), name)) {
+            return false;
+        }
+        match (&self.exe, p.exe()) {
+            (Some(expected), Some(exe)) => same_file(expected, exe),
+            _ => true,
+        }
+    }
+    /// cmd is empty when sysinfo may not read it, then any arguments pass
+    fn matches_args(&self, p: &sysinfo::Process) -> bool {
+        let cmd = p.cmd();
+        match &self.args {
+            Some(args) if !cmd.is_empty() => cmd[1..].iter().map(|a| a.to_string_lossy()).eq(args.iter().map(String::as_str)),
+            _ => true,
+        }
+    }
+}
+
+/// Strategy of a service: match.by, else what the other options ask for
+/// (adopt: false is pid, cmdline_contains is cmdline)
+fn match_strategy(config: &ServiceConfig) -> MatchStrategy {
+    let by = config.process_match.as_ref().and_then(ProcessMatch::by);
+    match by {
+        _ if !config.adopt.unwrap_or(true) => MatchStrategy::Pid,
+        Some(by) => by,
+        None if config.process_match.as_ref().and_then(|m| m.cmdline_contains()).is_some() => MatchStrategy::Cmdline,
+        None => MatchStrategy::Path,
+    }
+}
+
+/// Refresh all processes, with the command lines ProcessMatcher compares
+/// (sysinfo's default refresh leaves them out)
+fn refresh_for_matching(sys: &mut System) {
+    let kind = ProcessRefreshKind::nothing()
+        .with_memory()
+        .with_cpu()
+        .with_disk_usage()
+        .with_exe(UpdateKind::OnlyIfNotSet)
+        .with_cmd(UpdateKind::OnlyIfNotSet)
+        .with_tasks();
+    sys.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+}
+
+/// Version of a file: modification time and size
+type FileStamp = (SystemTime, u64);
+fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Full path of a bare program name, from the directories on PATH
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        let candidate = dir.join(name);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        // Windows: "app" runs app.exe
+        let exe = dir.join(format!("{}.exe", name));
+        (cfg!(windows) && exe.is_file()).then_some(exe)
+    })
+}
+
+/// Pid written by the service to its pid_file (relative to working_dir)
+fn read_pid_file(config: &ServiceConfig) -> Option<u32> {
+    let file = config.pid_file.as_deref()?;
+    let working_dir = LaunchPlan::resolve(config).ok().and_then(|plan| plan.working_dir);
+    let path = match working_dir {
+        Some(dir) => Path::new(&dir).join(unquote(file)),
+        None => PathBuf::from(unquote(file)),
+    };
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Compare paths after resolving links and relative parts (Windows may omit ".exe")
+fn same_file(expected: &Path, actual: &Path) -> bool {
+    let actual = actual.canonicalize().unwrap_or_else(|_| actual.to_path_buf());
+    let mut candidates = vec![expected.to_path_buf()];
+    if cfg!(windows) && expected.extension().is_none() {
+        candidates.push(expected.with_extension("exe"));
+    }
+    candidates.iter().any(|path| {
+        let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if cfg!(windows) {
+            path.as_os_str().eq_ignore_ascii_case(actual.as_os_str())
+        } else {
+            path == actual
+        }
+    })
+}
+
+/// All descendants of a process, level by level (children before grandchildren)
+fn descendants(sys: &System, root: Pid) -> Vec<Pid> {
+    let mut found = Vec::new();
+    let mut level = vec![root];
+    while !level.is_empty() {
+        let next: Vec<Pid> = sys
+            .processes()
+            .iter()
+            .filter(|(pid, p)| {
+                p.parent().is_some_and(|parent| level.contains(&parent)) && !found.contains(*pid)
+            })
+            .map(|(pid, _)| *pid)
+            .collect();
+        found.extend(&next);
+        level = next;
+    }
+    found
+}
+
+/// PID state file next to the config: services.yaml -> services.state.json
+fn state_path(config_path: &str) -> PathBuf {
+    Path::new(config_path).with_extension("state.json")
+}
+
+/// Read recorded PIDs, a missing or broken file just means no state
+fn load_pid_state(path: &Path) -> HashMap<String, u32> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Interpreter of a script by its extension, empty for anything else
+fn default_interpreter(exec: &str) -> Vec<String> {
+    let extension = Path::new(exec).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    // Windows PowerShell only exists on Windows, python is python3 on most Unix systems
+    let (powershell, python) = if cfg!(windows) { ("powershell", "python") } else { ("pwsh", "python3") };
+    let line: &[&str] = match extension.as_deref() {
+        Some("ps1") => &[powershell, "-NoProfile", "-ExecutionPolicy", "Bypass", "-File"],
+        Some("bat" | "cmd") => &["cmd", "/C"],
+        Some("py") => &[python],
+        Some("sh") => &["sh"],
+        _ => &[],
+    };
+    line.iter().map(|part| part.to_string()).collect()
+}
+
+/// Build a command that runs exec (plus args) through the platform shell
+fn shell_command(exec: &str, args: &[String]) -> Command {
+    let mut line = exec.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    #[cfg(windows)]
+    {
+        // Pass the line verbatim, cmd.exe does its own parsing
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").raw_arg(line);
+        cmd
+    }
+    #[cfg(not(windows))]
+    {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(line);
+        cmd
+    }
+}
+
+impl ServiceManager {
+    pub fn new(config_file: &str) -> Result<Self> {
+        // Read, migrate and parse YAML config file
+        let content = std::fs::read_to_string(config_file)
+            .context("Failed to read config file")?;
+        let (service_file, migrated) = parse_services_file(&content)?;
+        let mut manager = Self::from_config(service_file, Some(config_file))?;
+        manager.config_content = Some(content);
+        // Rewrite config in the current layout
+        if migrated {
+            info!(version = CONFIG_VERSION, "Migrated config");
+            manager.save_to_disk()?;
+        }
+        Ok(manager)
+    }
+    /// Manager from an already parsed config
+    /// With a config path, drop-in configs and recorded PIDs next to it are loaded
+    /// and changes are saved there; without one nothing touches the disk
+    pub fn from_config(service_file: ServicesFile, config_path: Option<&str>) -> Result<Self> {
+        // Service configs of the main file, then of each drop-in file
+        let mut entries: Vec<(ServiceConfig, Option<PathBuf>)> = service_file
+            .services
+            .iter()
+            .cloned()
+            .map(|cfg| (cfg, None))
+            .collect();
+        let Some(config_file) = config_path else {
+            return Ok(Self::build(service_file, entries, &HashMap::new(), "<memory>"));
+        };
+        let mut migrated = false;
+        let drop_in_paths = drop_in_files(config_file);
+        for path in &drop_in_paths {
+            let (file, file_migrated) = load_services_file(&path.to_string_lossy())?;
+            migrated |= file_migrated;
+            entries.extend(file.services.into_iter().map(|cfg| (cfg, Some(path.clone()))));
+        }
+        // PIDs recorded by the previous run, preferred over name matching
+        let saved_pids = load_pid_state(&state_path(config_file));
+        let mut manager = Self::build(service_file, entries, &saved_pids, config_file);
+        manager.config_path = Some(config_file.to_string());
+        manager.drop_in_paths = drop_in_paths;
+        if migrated {
+            info!(version = CONFIG_VERSION, "Migrated drop-in configs");
+            manager.save_to_disk()?;
+        }
+        Ok(manager)
+    }
+    /// Resolve duplicates and adopt already running processes
+    fn build(
+        service_file: ServicesFile,
+        entries: Vec<(ServiceConfig, Option<PathBuf>)>,
+        saved_pids: &HashMap<String, u32>,
+        config_file: &str,
+    ) -> Self {
+        // Storage services and their order
+        let mut services = HashMap::new();
+        let mut service_order = Vec::new();
+        // Resolve duplicates: skipped within one file, overridden by a later file
+        let mut configs: Vec<(ServiceConfig, Option<PathBuf>)> = Vec::new();
+        for (cfg, source) in entries {
+            match configs.iter_mut().find(|(c, _)| c.id == cfg.id) {
+                Some(existing) if existing.1 == source => {
+                    warn!(service = %cfg.id, "Duplicate service ID found in config. Skipping duplicate.");
+                }
+                Some(existing) => {
+                    info!(
+                        service = %cfg.id,
+                        file = %source.as_deref().unwrap_or(Path::new(config_file)).display(),
+                        "Service overridden by drop-in config",
+                    );
+                    *existing = (cfg, source);
+                }
+                None => configs.push((cfg, source)),
+            }
+        }
+        // Detect processes and pids
+        // use System::new() to save memory usage instead of System::new_all()
+        let mut sys = System::new();
+        refresh_for_matching(&mut sys);
+        let global_env = service_file.env.clone().unwrap_or_default();
+        // A PID can belong to only one service, recorded ones are reserved up front
+        let mut claimed_pids: HashSet<u32> = saved_pids.values().copied().collect();
+        // Read services from config
+        for (cfg, source) in configs {
+            let id = cfg.id.clone();
+            // Push service into order to show
+            service_order.push(cfg.id.clone());
+
+            let mut svc = ManagedService::new(ServiceConfig { inherited_env: global_env.clone(), ..cfg });
+            svc.source = source;
+
+            let shell = svc.config.shell.unwrap_or(false);
+            // Path and arguments must match too, a generic name alone may be another instance
+            let matcher = svc.process_matcher();
+            // Reconnect to the recorded PID if it still runs the same program
+            let recorded = saved_pids
+                .get(&id)
+                .and_then(|pid| sys.process(Pid::from_u32(*pid)))
+                .filter(|p| shell || matcher.matches_program(p));
+            // Otherwise find if process is already existing
+            let found_proc = recorded.or_else(|| {
+                sys.processes().values().find(|p| {
+                    !claimed_pids.contains(&p.pid().as_u32()) && matcher.matches(p)
+                })
+            });
+            // If existing, get PIDs
+            if let Some(proc) = found_proc {
+                let pid = proc.pid().as_u32();
+                info!(service = %id, pid, event = "adopted", "Adopted existing service");
+                claimed_pids.insert(pid);
+                svc.last_known_pid = Some(pid); // Catch pid who not started by app manager
+            }
+            services.insert(svc.config.id.clone(), svc);
+        }
+        let manager = Self {
+            services,
+            service_order,
+            sys,
+            config_path: None,
+            config_content: None,
+            drop_in_paths: Vec::new(),
+            config_listen: service_file.listen,
+            keep_alive_interval: service_file.keep_alive.unwrap_or(0),
+            stop_on_exit: service_file.stop_on_exit.unwrap_or(false),
+            cors: service_file.cors,
+            auth: service_file.auth,
+            tls: service_file.tls,
+            watch_config: service_file.watch_config.unwrap_or(false),
+            change_threshold: service_file.change_threshold,
+            global_env,
+            templates: service_file.templates.unwrap_or_default(),
+            stats: Arc::default(),
+            changes: ChangeTracker {
+                epoch: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0),
+                ..Default::default()
+            },
+            save_notify: None,
+            dirty: false,
+        };
+        manager.update_stats(None);
+        manager
+    }
+    // Check if serivce is already running
+    pub fn is_running(&mut self, id: &str) -> bool {
+        self.check_running(id, true)
+    }
+    /// Without refresh, the process list of the last refresh is used
+    fn check_running(&mut self, id: &str, refresh: bool) -> bool {
+        // Check by ID
+        if let Some(svc) = self.services.get_mut(id)
+            && let Some(child) = &mut svc.process
+        {
+            match child.try_wait() {
+                Ok(None) => return true,
+                Ok(Some(status)) => {
+                    info!(service = %id, %status, event = "exited", "Service exited");
+                    svc.record_exit(status.into());
+                    svc.process = None;
+                    svc.finish_run();
+                }
+                Err(_) => {
+                    svc.process = None;
+                }
+            }
+        }
+        // Check already running service by processes PIDs 
+        if refresh {
+            refresh_for_matching(&mut self.sys);
+        }
+        let (last_pid, matcher) = match self.services.get(id) {
+            Some(s) => (s.last_known_pid, s.process_matcher()),
+            None => return false,
+        };
+
+        if let Some(pid) = last_pid
+            && self.sys.process(Pid::from_u32(pid)).is_some()
+        {
+            return true;
+        }
+        // Check already running service by processes names (or its pid_file)
+        let found = self.sys.processes().values().find(|p| matcher.matches(p)).map(|p| p.pid().as_u32());
+        // A pid_file names the process to stop, e.g. after a daemon forked away from the spawned one
+        if matcher.pid_file.is_some()
+            && found.is_some()
+            && let Some(svc) = self.services.get_mut(id)
+        {
+            svc.last_known_pid = found;
+        }
+        found.is_some()
+    }
+    /// Start, after the services it depends on
+    /// Returns the PID of the started (or already running) process
+    pub async fn start(&mut self, id: &str) -> Result<Option<u32>> {
+        self.check_enabled(id)?;
+        for dep in self.dependencies(id)? {
+            if !self.is_running(&dep) {
+                info!(service = %id, dependency = %dep, "Starting dependency");
+                self.start_one(&dep)
+                    .await
+                    .with_context(|| format!("Dependency '{}' of '{}' failed to start", dep, id))?;
+            }
+            self.wait_ready(&dep).await.with_context(|| format!("Dependency '{}' of '{}' is not ready", dep, id))?;
+        }
+        self.start_one(id).await
+    }
+    /// start for a manager shared with other tasks, the lock is let go during the startup
+    /// grace periods and while a dependency gets ready
+    pub async fn start_shared(manager: &Mutex<ServiceManager>, id: &str) -> Result<Option<u32>> {
+        let deps = {
+            let mgr = manager.lock().await;
+            mgr.check_enabled(id)?;
+            mgr.dependencies(id)?
+        };
+        for dep in deps {
+            let running = manager.lock().await.is_running(&dep);
+            if !running {
+                info!(service = %id, dependency = %dep, "Starting dependency");
+                Self::start_one_shared(manager, &dep)
+                    .await
+                    .with_context(|| format!("Dependency '{}' of '{}' failed to start", dep, id))?;
+            }
+            Self::wait_ready_shared(manager, &dep)
+                .await
+                .with_context(|| format!("Dependency '{}' of '{}' is not ready", dep, id))?;
+        }
+        Self::start_one_shared(manager, id).await
+    }
+    /// Start a single service, dependencies are not looked at
+    async fn start_one(&mut self, id: &str) -> Result<Option<u32>> {
+        // Check if already running
+        if self.is_running(id) {
+            info!(service = %id, "Service is already running");
+            return Ok(self.running_pid(id));
+        }
+        self.check_enabled(id)?;
+        self.run_hook(id, Hook::PreStart).await?;
+        let grace = self.spawn(id)?;
+        if grace > 0 {
+            tokio::time::sleep(tokio::time::Duration::from_secs(grace)).await;
+        }
+        self.confirm_started(id, grace)?;
+        self.run_post_start(id).await?;
+        Ok(self.running_pid(id))
+    }
+    /// start_one for a manager shared with other tasks, the lock is let go during the startup grace period
+    async fn start_one_shared(manager: &Mutex<ServiceManager>, id: &str) -> Result<Option<u32>> {
+        let pre_start = {
+            let mut mgr = manager.lock().await;
+            if mgr.is_running(id) {
+                info!(service = %id, "Service is already running");
+                return Ok(mgr.running_pid(id));
+            }
+            mgr.check_enabled(id)?;
+            mgr.prepare_hook(id, Hook::PreStart)
+        };
+        if let Some(hook) = pre_start {
+            hook.run().await?;
+        }
+        let grace = {
+            let mut mgr = manager.lock().await;
+            // Started by another task while pre_start ran
+            if mgr.is_running(id) {
+                return Ok(mgr.running_pid(id));
+            }
+            mgr.spawn(id)?
+        };
+        if grace > 0 {
+            tokio::time::sleep(tokio::time::Duration::from_secs(grace)).await;
+        }
+        let post_start = {
+            let mut mgr = manager.lock().await;
+            mgr.confirm_started(id, grace)?;
+            mgr.prepare_hook(id, Hook::PostStart)
+        };
+        Self::run_post_start_shared(manager, id, post_start).await?;
+        Ok(manager.lock().await.running_pid(id))
+    }
+    /// post_start hook, a failure with on_failure: abort stops the fresh process again
+    async fn run_post_start(&mut self, id: &str) -> Result<()> {
+        if let Err(e) = self.run_hook(id, Hook::PostStart).await {
+            if let Err(stop_err) = self.stop(id).await {
+                warn!(service = %id, error = %format!("{:#}", stop_err), "Failed to stop after post_start failure");
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// run_post_start for a manager shared with other tasks, the hook and the stop run without the lock
+    async fn run_post_start_shared(manager: &Mutex<ServiceManager>, id: &str, hook: Option<HookRun>) -> Result<()> {
+        let Some(hook) = hook else { return Ok(()) };
+        if let Err(e) = hook.run().await {
+            if let Err(stop_err) = Self::stop_shared(manager, id).await {
+                warn!(service = %id, error = %format!("{:#}", stop_err), "Failed to stop after post_start failure");
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// PID of a running service: the recorded one, or a name match from the last refresh
+    fn running_pid(&self, id: &str) -> Option<u32> {
+        let svc = self.services.get(id)?;
+        svc.last_known_pid.or_else(|| {
+            let matcher = svc.process_matcher();
+            self.sys
+                .processes()
+                .values()
+                .find(|p| matcher.matches(p))
+                .map(|p| p.pid().as_u32())
+        })
+    }
+    /// Services with enabled: false stay in the config but are never started
+    fn check_enabled(&self, id: &str) -> Result<()> {
+        let svc = self.services.get(id).ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        if !svc.config.is_enabled() {
+            return Err(ServiceError::Conflict(format!("Service '{}' is disabled (enabled: false)", id)).into());
+        }
+        Ok(())
+    }
+    /// What ready_when still waits for: whether the log line was seen and the port to probe
+    /// None once the service is ready
+    fn ready_pending(&self, id: &str) -> Option<(bool, Option<u16>)> {
+        let Some(svc) = self.services.get(id) else {
+            return Some((false, None));
+        };
+        if svc.is_ready() {
+            return None;
+        }
+        let logged = svc.ready_line.as_ref().is_none_or(|line| line.seen.load(Ordering::Relaxed));
+        Some((logged, svc.config.ready_when.as_ref().and_then(|r| r.port)))
+    }
+    fn mark_ready(&mut self, id: &str) {
+        if let Some(svc) = self.services.get_mut(id) {
+            svc.ready = true;
+            info!(service = %id, event = "ready", "Service is ready");
+        }
+    }
+    /// Probe ready_when once, a pass counts until the next spawn
+    async fn probe_ready(&mut self, id: &str) -> bool {
+        let Some((logged, port)) = self.ready_pending(id) else {
+            return true;
+        };
+        // Both have to pass when both are set
+        let ready = logged && probe_ready_port(port).await;
+        if ready {
+            self.mark_ready(id);
+        }
+        ready
+    }
+    /// When waiting for ready_when ends and the timeout for the error
+    fn ready_deadline(&self, id: &str) -> Result<(Instant, Duration)> {
+        let svc = self.services.get(id).ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        let timeout = svc.ready_timeout();
+        Ok((svc.started_at.unwrap_or_else(Instant::now) + timeout, timeout))
+    }
+    /// Wait until a started service passes ready_when, an error after its timeout or when it exits
+    async fn wait_ready(&mut self, id: &str) -> Result<()> {
+        let (deadline, timeout) = self.ready_deadline(id)?;
+        loop {
+            if self.probe_ready(id).await {
+                return Ok(());
+            }
+            if !self.is_running(id) {
+                return Err(anyhow!("Service '{}' exited before it was ready", id));
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!("Service '{}' was not ready within {}s", id, timeout.as_secs()));
+            }
+            tokio::time::sleep(READY_POLL).await;
+        }
+    }
+    /// wait_ready locking the manager only to look at the service, not while probing or sleeping
+    async fn wait_ready_shared(manager: &Mutex<ServiceManager>, id: &str) -> Result<()> {
+        let (deadline, timeout) = manager.lock().await.ready_deadline(id)?;
+        loop {
+            let pending = manager.lock().await.ready_pending(id);
+            let Some((logged, port)) = pending else {
+                return Ok(());
+            };
+            let ready = logged && probe_ready_port(port).await;
+            {
+                let mut mgr = manager.lock().await;
+                if ready {
+                    mgr.mark_ready(id);
+                    return Ok(());
+                }
+                if !mgr.is_running(id) {
+                    return Err(anyhow!("Service '{}' exited before it was ready", id));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!("Service '{}' was not ready within {}s", id, timeout.as_secs()));
+            }
+            tokio::time::sleep(READY_POLL).await;
+        }
+    }
+    /// Probe the running services that aren't ready yet, the status shows Starting until they are
+    /// The manager is locked to pick them and to record the outcome, not while probing
+    /// Missing the timeout is logged once per start
+    pub(crate) async fn poll_readiness(manager: &Mutex<ServiceManager>) {
+        let mut waiting = Vec::new();
+        {
+            let mut mgr = manager.lock().await;
+            let ids: Vec<String> = mgr
+                .service_order
+                .iter()
+                .filter(|id| mgr.services.get(*id).is_some_and(|svc| !svc.is_ready() && svc.last_known_pid.is_some()))
+                .cloned()
+                .collect();
+            for id in ids {
+                if !mgr.is_running(&id) {
+                    continue;
+                }
+                let pid = mgr.services.get(&id).and_then(|svc| svc.last_known_pid);
+                if let (Some(pid), Some((logged, port))) = (pid, mgr.ready_pending(&id)) {
+                    waiting.push((id, pid, logged, port));
+                }
+            }
+        }
+        if waiting.is_empty() {
+            return;
+        }
+        // Both have to pass when both are set
+        let probes = waiting.into_iter().map(|(id, pid, logged, port)| async move {
+            let ready = logged && probe_ready_port(port).await;
+            (id, pid, ready)
+        });
+        let results = futures_util::future::join_all(probes).await;
+        let mut mgr = manager.lock().await;
+        for (id, pid, ready) in results {
+            // Stopped or started again while it was probed
+            if mgr.services.get(&id).is_none_or(|svc| svc.last_known_pid != Some(pid)) {
+                continue;
+            }
+            if ready {
+                mgr.mark_ready(&id);
+                continue;
+            }
+            let Some(svc) = mgr.services.get_mut(&id) else { continue };
+            let overdue = svc.started_at.is_some_and(|at| at.elapsed() >= svc.ready_timeout());
+            if overdue && !svc.ready_overdue {
+                svc.ready_overdue = true;
+                warn!(service = %id, event = "not_ready", "Service is not ready within {}s", svc.ready_timeout().as_secs());
+            }
+        }
+    }
+    /// Start several services at once (used for autorun), with their dependencies
+    /// Processes are refreshed once, start_delay is waited before each spawn. Services are started in waves by dependency depth:
+    /// each wave is spawned back to back and its startup grace periods are waited out together
+    pub async fn start_many(&mut self, ids: &[String]) -> Vec<(String, Result<()>)> {
+        let mut plan = self.plan_start(ids);
+        let waves = std::mem::take(&mut plan.waves);
+        let last = waves.len().saturating_sub(1);
+        for (depth, wave) in waves.into_iter().enumerate() {
+            let mut spawned = Vec::new();
+            for id in wave {
+                let Some(delay) = self.before_spawn(&mut plan, &id) else { continue };
+                wait_start_delay(&id, delay).await;
+                let pre_start = self.run_hook(&id, Hook::PreStart).await;
+                if let Some(grace) = self.spawn_planned(&mut plan, &id, pre_start) {
+                    spawned.push((id, grace));
+                }
+            }
+            wait_wave_grace(&spawned).await;
+            for (id, grace) in spawned {
+                let mut result = self.confirm_planned(&id, grace).await;
+                // Later waves may depend on it
+                if result.is_ok() && depth < last {
+                    result = self.wait_ready(&id).await;
+                }
+                plan.finish(id, result);
+            }
+        }
+        plan.results
+    }
+    /// start_many for a manager shared with other tasks, the lock is let go during start_delay,
+    /// the hooks, the startup grace periods and while a wave gets ready
+    pub async fn start_many_shared(manager: &Mutex<ServiceManager>, ids: &[String]) -> Vec<(String, Result<()>)> {
+        let mut plan = manager.lock().await.plan_start(ids);
+        let waves = std::mem::take(&mut plan.waves);
+        let last = waves.len().saturating_sub(1);
+        for (depth, wave) in waves.into_iter().enumerate() {
+            let mut spawned = Vec::new();
+            for id in wave {
+                let Some(delay) = manager.lock().await.before_spawn(&mut plan, &id) else { continue };
+                wait_start_delay(&id, delay).await;
+                let pre_start = manager.lock().await.prepare_hook(&id, Hook::PreStart);
+                let pre_start = match pre_start {
+                    Some(hook) => hook.run().await,
+                    None => Ok(()),
+                };
+                if let Some(grace) = manager.lock().await.spawn_planned(&mut plan, &id, pre_start) {
+                    spawned.push((id, grace));
+                }
+            }
+            wait_wave_grace(&spawned).await;
+            for (id, grace) in spawned {
+                let post_start = {
+                    let mut mgr = manager.lock().await;
+                    mgr.confirm_started(&id, grace).map(|_| mgr.prepare_hook(&id, Hook::PostStart))
+                };
+                let mut result = match post_start {
+                    Ok(hook) => Self::run_post_start_shared(manager, &id, hook).await,
+                    Err(e) => Err(e),
+                };
+                // Later waves may depend on it
+                if result.is_ok() && depth < last {
+                    result = Self::wait_ready_shared(manager, &id).await;
+                }
+                plan.finish(id, result);
+            }
+        }
+        plan.results
+    }
+    /// Waves of start_many: the services to start and their dependencies by dependency depth
+    fn plan_start(&mut self, ids: &[String]) -> StartPlan {
+        refresh_for_matching(&mut self.sys);
+        let mut plan = StartPlan::default();
+        let mut depths: HashMap<String, usize> = HashMap::new();
+        for id in ids {
+            match self.dependencies(id) {
+                Ok(deps) => {
+                    for dep in deps.into_iter().chain([id.clone()]) {
+                        let depth = self.dependency_depth(&dep);
+                        depths.insert(dep, depth);
+                    }
+                }
+                Err(e) => plan.results.push((id.clone(), Err(e))),
+            }
+        }
+        let max_depth = depths.values().copied().max();
+        for depth in max_depth.into_iter().flat_map(|max| 0..=max) {
+            // Keep the requested order within a wave
+            let mut wave: Vec<String> = depths.iter().filter(|(_, d)| **d == depth).map(|(id, _)| id.clone()).collect();
+            wave.sort_by_key(|id| ids.iter().position(|x| x == id).unwrap_or(usize::MAX));
+            plan.waves.push(wave);
+        }
+        plan
+    }
+    /// The start_delay to wait before spawning a service of a wave
+    /// None when it's skipped: already running, disabled or a dependency failed
+    fn before_spawn(&mut self, plan: &mut StartPlan, id: &str) -> Option<u64> {
+        let deps = self.services.get(id).and_then(|s| s.config.depends_on.clone()).unwrap_or_default();
+        if let Some(dep) = deps.iter().find(|dep| plan.failed.contains(*dep)) {
+            plan.finish(id.to_string(), Err(anyhow!("Dependency '{}' failed to start", dep)));
+            return None;
+        }
+        if self.check_running(id, false) {
+            info!(service = %id, "Service is already running");
+            plan.finish(id.to_string(), Ok(()));
+            return None;
+        }
+        if let Err(e) = self.check_enabled(id) {
+            plan.finish(id.to_string(), Err(e));
+            return None;
+        }
+        // Spread heavy services out instead of spawning all at once
+        Some(self.services.get(id).and_then(|s| s.config.start_delay).unwrap_or(0))
+    }
+    /// Spawn after the outcome of pre_start, the startup grace period to wait on success
+    fn spawn_planned(&mut self, plan: &mut StartPlan, id: &str, pre_start: Result<()>) -> Option<u64> {
+        match pre_start.and_then(|_| self.spawn(id)) {
+            Ok(grace) => Some(grace),
+            Err(e) => {
+                plan.finish(id.to_string(), Err(e));
+                None
+            }
+        }
+    }
+    /// Check a spawned service survived its grace period and run post_start
+    async fn confirm_planned(&mut self, id: &str, grace: u64) -> Result<()> {
+        self.confirm_started(id, grace)?;
+        self.run_post_start(id).await
+    }
+    /// Everything a service depends on, directly or not, in start order
+    /// Unknown ids and cycles are rejected
+    pub fn dependencies(&self, id: &str) -> Result<Vec<String>> {
+        fn visit(
+            mgr: &ServiceManager,
+            id: &str,
+            path: &mut Vec<String>,
+            order: &mut Vec<String>,
+        ) -> Result<()> {
+            if order.iter().any(|x| x == id) {
+                return Ok(());
+            }
+            if path.iter().any(|x| x == id) {
+                path.push(id.to_string());
+                return Err(ServiceError::Invalid(format!("Dependency cycle: {}", path.join(" -> "))).into());
+            }
+            let svc = mgr.services.get(id).ok_or_else(|| match path.last() {
+                Some(parent) => ServiceError::Invalid(format!("Service '{}' depends on unknown service '{}'", parent, id)),
+                None => ServiceError::NotFound(id.to_string()),
+            })?;
+            path.push(id.to_string());
+            for dep in svc.config.depends_on.iter().flatten() {
+                visit(mgr, dep, path, order)?;
+            }
+            path.pop();
+            order.push(id.to_string());
+            Ok(())
+        }
+        let mut order = Vec::new();
+        visit(self, id, &mut Vec::new(), &mut order)?;
+        order.pop();
+        Ok(order)
+    }
+    /// Longest chain of dependencies below a service (0: depends on nothing, or invalid)
+    fn dependency_depth(&self, id: &str) -> usize {
+        let Ok(mut order) = self.dependencies(id) else {
+            return 0;
+        };
+        order.push(id.to_string());
+        // Start order: every dependency has its depth before its dependents
+        let mut depths: HashMap<&str, usize> = HashMap::new();
+        for x in &order {
+            let depth = self.services[x.as_str()]
+                .config
+                .depends_on
+                .iter()
+                .flatten()
+                .map(|dep| depths.get(dep.as_str()).copied().unwrap_or(0) + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(x, depth);
+        }
+        depths.get(id).copied().unwrap_or(0)
+    }
+    /// Running services that depend on this one directly
+    pub fn running_dependents(&mut self, id: &str) -> Vec<String> {
+        let dependents: Vec<String> = self
+            .service_order
+            .iter()
+            .filter(|other| {
+                self.services
+                    .get(*other)
+                    .is_some_and(|svc| svc.config.depends_on.iter().flatten().any(|dep| dep == id))
+            })
+            .cloned()
+            .collect();
+        dependents.into_iter().filter(|other| self.is_running(other)).collect()
+    }
+    /// Make sure the declared port is free
+    /// Uses the last process refresh to find other running services on the same port
+    fn check_port(&mut self, id: &str) -> Result<()> {
+        let Some(port) = self.services.get(id).and_then(|s| s.config.port) else {
+            return Ok(());
+        };
+        let others: Vec<String> = self
+            .services
+            .values()
+            .filter(|s| s.config.id != id && s.config.port == Some(port))
+            .map(|s| s.config.id.clone())
+            .collect();
+        for other in others {
+            if self.check_running(&other, false) {
+                return Err(ServiceError::Conflict(format!(
+                    "Port {} already in use by service '{}'",
+                    port, other
+                ))
+                .into());
+            }
+        }
+        if port_taken(port) {
+            return Err(ServiceError::Conflict(format!(
+                "Port {} already in use by another process",
+                port
+            ))
+            .into());
+        }
+        Ok(())
+    }
+    /// Spawn the process of a service, returns its startup grace in seconds
+    fn spawn(&mut self, id: &str) -> Result<u64> {
+        self.check_port(id)?;
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        let plan = LaunchPlan::resolve(&svc.config)?;
+        // Matched by the output pumps of this spawn
+        let ready_line = match svc.config.ready_when.as_ref().and_then(|r| r.log_matches.as_deref()) {
+            Some(pattern) => Some(ReadyLine {
+                pattern: ready_pattern(pattern)?,
+                seen: Arc::default(),
+            }),
+            None => None,
+        };
+        if let Some(dir) = &plan.working_dir
+            && !Path::new(dir).is_dir()
+        {
+            if !svc.config.create_working_dir.unwrap_or(false) {
+                return Err(ServiceError::Invalid(format!(
+                    "Working directory \"{}\" does not exist (set create_working_dir: true to create it)",
+                    dir
+                ))
+                .into());
+            }
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create working directory \"{}\"", dir))?;
+            info!(service = %id, dir = %dir, "Created working directory");
+        }
+        #[cfg(windows)]
+        {
+            if svc.config.run_as.as_ref().is_some_and(|r| r.group.is_some()) {
+                warn!(service = %id, "run_as.group is not supported on Windows, ignored");
+            }
+            if svc.config.nice.is_some() {
+                warn!(service = %id, "nice is not supported on Windows, use windows.priority");
+            }
+            info!(service = %id, flags = %format!("{:#010x}", plan.creation_flags), "Applying creation flags");
+            for warning in plan.flag_warnings() {
+                warn!(service = %id, flags = %format!("{:#010x}", plan.creation_flags), "{}", warning);
+            }
+        }
+        #[cfg(windows)]
+        if let Some(user) = svc.config.run_as.as_ref().and_then(|r| r.user.clone()) {
+            return self.spawn_as_user(id, &user);
+        }
+        // An elevated AppManager passes its token on, a normal spawn is elevated then
+        #[cfg(windows)]
+        if svc.config.windows.as_ref().and_then(|w| w.elevated).unwrap_or(false) && !is_elevated() {
+            return self.spawn_elevated(id);
+        }
+        // Run command, falling back to the next exec candidate if it can't be spawned
+        let candidates = svc.config.exec_candidates();
+        let mut failures = Vec::new();
+        let mut spawned = None;
+        let mut log = None;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let plan = LaunchPlan::resolve(candidate)?.with_secrets()?;
+            let mut cmd = plan.command();
+            // Suspended until it is in the job, so no child can escape it
+            #[cfg(windows)]
+            cmd.creation_flags(plan.creation_flags | CREATE_SUSPENDED);
+            // Avoid blocking by main process
+            // Captured output is read by background tasks, the pipes end with the manager
+            // ready_when.log_matches reads the output too
+            let capture = svc.config.capture_output.unwrap_or(false) || ready_line.is_some();
+            let mode = svc.config.stdio.unwrap_or_default();
+            // AppManager writes log_file itself when the output is captured or the file rotates
+            log = None;
+            if mode == StdioMode::File && (capture || svc.config.rotates_log()) {
+                log = Some(open_rotating_log(&svc.config, plan.working_dir.as_deref())?);
+            }
+            match mode {
+                _ if capture || log.is_some() => {
+                    cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).stdin(Stdio::null());
+                }
+                StdioMode::Null => {
+                    cmd.stdout(Stdio::null()).stderr(Stdio::null()).stdin(Stdio::null());
+                }
+                StdioMode::Inherit => {
+                    cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit()).stdin(Stdio::inherit());
+                }
+                StdioMode::File => {
+                    let log = open_log_file(&svc.config, plan.working_dir.as_deref())?;
+                    cmd.stdout(log.try_clone()?).stderr(log).stdin(Stdio::null());
+                }
+            }
+            match cmd.spawn() {
+                Ok(child) => {
+                    if i > 0 {
+                        info!(service = %id, exec = %plan.exec, event = "exec_fallback", "Started with a fallback exec");
+                    }
+                    spawned = Some((child, (!plan.shell).then_some(plan.exec)));
+                    break;
+                }
+                Err(e) => {
+                    if candidates.len() > 1 {
+                        warn!(service = %id, exec = %plan.exec, error = %e, "Failed to spawn exec candidate");
+                    }
+                    failures.push((plan.exec, e));
+                }
+            }
+        }
+        let Some((mut child, exec)) = spawned else {
+            return Err(spawn_error(&svc.config.name, &failures));
+        };
+        #[cfg(windows)]
+        if let Some(handle) = child.raw_handle()
+            && !run_suspended(svc, id, handle, child.id().unwrap_or(0), &plan)
+        {
+            let _ = child.start_kill();
+            return Err(anyhow!("Failed to resume {} after spawn", svc.config.name));
+        }
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(pump_lines(stdout, svc.output.clone(), log.clone(), ready_line.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(pump_lines(stderr, svc.output.clone(), log, ready_line.clone()));
+        }
+        // record process and its pid
+        svc.spawned(child.id().unwrap_or(0), Some(child));
+        svc.watch_exec(exec.as_deref());
+        svc.ready_line = ready_line;
+        let grace = svc.config.startup_grace_secs.unwrap_or(0);
+        self.save_pid_state();
+        Ok(grace)
+    }
+    /// Windows run_as.user: spawn through CreateProcessWithLogonW with the password from run_as.password_env
+    /// There is no Child, the process is watched by its pid like an adopted one
+    #[cfg(windows)]
+    fn spawn_as_user(&mut self, id: &str, user: &str) -> Result<u64> {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::Threading::TerminateProcess;
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        let config = &svc.config;
+        // The logon API can't hand over pipes or files opened by AppManager
+        let reads_output = config.ready_when.as_ref().is_some_and(|r| r.log_matches.is_some());
+        if config.capture_output.unwrap_or(false) || reads_output || config.stdio.unwrap_or_default() != StdioMode::Null {
+            return Err(ServiceError::Invalid(format!(
+                "Service '{}' runs as another user, only stdio: null without capture_output or log_matches is supported on Windows",
+                id
+            ))
+            .into());
+        }
+        let Some(var) = config.run_as.as_ref().and_then(|r| r.password_env.as_deref()) else {
+            return Err(ServiceError::Invalid(format!(
+                "Service '{}' needs run_as.password_env, the environment variable holding the password of {}",
+                id, user
+            ))
+            .into());
+        };
+        let password = std::env::var(var).map_err(|_| {
+            ServiceError::Invalid(format!("Environment variable {} (run_as.password_env) is not set", var))
+        })?;
+        if config.env.as_ref().is_some_and(|env| !env.is_empty()) {
+            warn!(service = %id, "env is not passed to processes run as another user on Windows");
+        }
+        let mut failures = Vec::new();
+        let mut spawned = None;
+        for candidate in config.exec_candidates() {
+            let plan = LaunchPlan::resolve(&candidate)?;
+            match spawn_with_logon(&plan, user, &password) {
+                Ok(process) => {
+                    spawned = Some((plan, process));
+                    break;
+                }
+                Err(e) => failures.push((plan.exec, e)),
+            }
+        }
+        let Some((plan, (pid, handle))) = spawned else {
+            return Err(spawn_error(&svc.config.name, &failures));
+        };
+        let resumed = run_suspended(svc, id, handle as _, pid, &plan);
+        unsafe {
+            if !resumed {
+                TerminateProcess(handle, 1);
+            }
+            CloseHandle(handle);
+        }
+        if !resumed {
+            return Err(anyhow!("Failed to resume {} after spawn", svc.config.name));
+        }
+        info!(service = %id, pid, user = %user, "Started as another user");
+        svc.spawned(pid, None);
+        svc.watch_exec((!plan.shell).then_some(plan.exec.as_str()));
+        let grace = svc.config.startup_grace_secs.unwrap_or(0);
+        self.save_pid_state();
+        Ok(grace)
+    }
+    /// Windows elevated without an elevated AppManager: ShellExecuteEx with the runas verb
+    /// Like spawn_as_user there is no Child, and no pipes or env can be handed over
+    #[cfg(windows)]
+    fn spawn_elevated(&mut self, id: &str) -> Result<u64> {
+        use windows_sys::Win32::Foundation::{CloseHandle, ERROR_CANCELLED};
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        let config = &svc.config;
+        let reads_output = config.ready_when.as_ref().is_some_and(|r| r.log_matches.is_some());
+        if config.capture_output.unwrap_or(false) || reads_output || config.stdio.unwrap_or_default() != StdioMode::Null {
+            return Err(ServiceError::Invalid(format!(
+                "Service '{}' is elevated, only stdio: null without capture_output or log_matches is supported \
+                 unless AppManager runs as Administrator",
+                id
+            ))
+            .into());
+        }
+        if config.env.as_ref().is_some_and(|env| !env.is_empty()) || config.env_file.is_some() {
+            warn!(service = %id, "env is not passed to elevated processes unless AppManager runs as Administrator");
+        }
+        let mut failures = Vec::new();
+        let mut spawned = None;
+        for candidate in config.exec_candidates() {
+            let plan = LaunchPlan::resolve(&candidate)?;
+            match spawn_with_runas(&plan) {
+                Ok(process) => {
+                    spawned = Some((plan, process));
+                    break;
+                }
+                // Declined consent is the same for every candidate
+                Err(e) if e.raw_os_error() == Some(ERROR_CANCELLED as i32) => {
+                    return Err(anyhow!(
+                        "Elevation of {} was declined, run AppManager as Administrator to start it without asking",
+                        svc.config.name
+                    ));
+                }
+                Err(e) => failures.push((plan.exec, e)),
+            }
+        }
+        let Some((plan, (pid, handle))) = spawned else {
+            return Err(spawn_error(&svc.config.name, &failures));
+        };
+        // Already running, children started before this may escape the job
+        svc.job = JobObject::assign(handle as _);
+        if svc.job.is_none() {
+            warn!(service = %id, "Could not create a job object, stopping the elevated process may fail");
+        }
+        if let Some(mask) = plan.affinity_mask
+            && !set_affinity(handle as _, mask)
+        {
+            warn!(service = %id, mask = %format!("{:#x}", mask), "Failed to set the CPU affinity");
+        }
+        unsafe { CloseHandle(handle) };
+        info!(service = %id, pid, "Started elevated");
+        svc.spawned(pid, None);
+        svc.watch_exec((!plan.shell).then_some(plan.exec.as_str()));
+        let grace = svc.config.startup_grace_secs.unwrap_or(0);
+        self.save_pid_state();
+        Ok(grace)
+    }
+    /// Startup grace: catch processes that died right after spawn
+    fn confirm_started(&mut self, id: &str, grace: u64) -> Result<()> {
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        if grace > 0
+            && let Some(child) = svc.process.as_mut()
+            && let Ok(Some(status)) = child.try_wait()
+        {
+            svc.record_exit(status.into());
+            svc.process = None;
+            svc.last_known_pid = None;
+            svc.start_failed = true;
+            svc.finish_run();
+            // With capture_output the last lines usually say what was wrong
+            let (tail, _) = svc.output.tail(STARTUP_OUTPUT_LINES);
+            self.save_pid_state();
+            error!(
+                service = %id,
+                %status,
+                event = "start_failed",
+                "Service exited during startup grace",
+            );
+            let mut msg = format!("Service \"{}\" exited within {}s of starting ({})", id, grace, status);
+            if !tail.is_empty() {
+                msg = format!("{}, last output:\n{}", msg, tail.join("\n"));
+            }
+            return Err(anyhow!(msg));
+        }
+        // Seen exiting by another task while the manager was let go during the grace period
+        if grace > 0 && svc.process.is_none() && !self.is_running(id) {
+            if let Some(svc) = self.services.get_mut(id) {
+                svc.last_known_pid = None;
+                svc.start_failed = true;
+            }
+            self.save_pid_state();
+            error!(service = %id, event = "start_failed", "Service exited during startup grace");
+            return Err(anyhow!("Service \"{}\" exited within {}s of starting", id, grace));
+        }
+        let svc = &self.services[id];
+        info!(
+            service = %id,
+            pid = svc.last_known_pid.unwrap_or(0),
+            event = "started",
+            "Started service",
+        );
+        Ok(())
+    }
+    /// Stop
+    pub async fn stop(&mut self, id: &str) -> Result<()> {
+        let mut run = self.begin_stop(id).await?;
+        if let Some((pid, timeout)) = run.exit_wait {
+            let exited = self.wait_for_exit(id, pid, timeout).await;
+            run.exit_waited(id, exited);
+        }
+        let (killed, kill_timeout) = self.kill_remaining(id, &run).await?;
+        let survivors = self.wait_for_pids_gone(killed, kill_timeout).await;
+        if let Some(hook) = self.finish_stop(id, &run, survivors, kill_timeout)? {
+            hook.run().await?;
+        }
+        Ok(())
+    }
+    /// stop for a manager shared with other tasks, the lock is let go during the hooks and stop_cmd, while
+    /// the service gets time to exit (stop_timeout) and while the killed processes go away (kill_timeout)
+    pub async fn stop_shared(manager: &Mutex<ServiceManager>, id: &str) -> Result<()> {
+        Self::stop_locked_after(manager, id).await.1
+    }
+    /// stop_shared, returns the manager still locked after the last step
+    async fn stop_locked_after<'a>(
+        manager: &'a Mutex<ServiceManager>,
+        id: &str,
+    ) -> (MutexGuard<'a, ServiceManager>, Result<()>) {
+        let result = Self::stop_unlocked_waits(manager, id).await;
+        let mut mgr = manager.lock().await;
+        if let Some(svc) = mgr.services.get_mut(id) {
+            svc.stopping = false;
+        }
+        (mgr, result)
+    }
+    async fn stop_unlocked_waits(manager: &Mutex<ServiceManager>, id: &str) -> Result<()> {
+        let (mut run, pre_stop) = {
+            let mut mgr = manager.lock().await;
+            let target = mgr.stop_target(id)?;
+            // Keep-alive must not bring it back while it is going down
+            if let Some(svc) = mgr.services.get_mut(id) {
+                svc.stopping = true;
+            }
+            target
+        };
+        if let Some(hook) = pre_stop {
+            hook.run().await?;
+        }
+        let stop_cmd = manager.lock().await.ask_to_exit(id, &mut run);
+        if let Some(stop_cmd) = stop_cmd {
+            stop_cmd.run(id, &mut run).await;
+        }
+        if let Some((pid, timeout)) = run.exit_wait {
+            let deadline = tokio::time::Instant::now() + timeout;
+            let exited = loop {
+                if manager.lock().await.has_exited(id, pid) {
+                    break true;
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    break false;
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            };
+            run.exit_waited(id, exited);
+        }
+        let (mut killed, kill_timeout) = manager.lock().await.kill_remaining(id, &run).await?;
+        let deadline = Instant::now() + kill_timeout;
+        loop {
+            manager.lock().await.retain_alive(&mut killed);
+            if killed.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let post_stop = manager.lock().await.finish_stop(id, &run, killed, kill_timeout)?;
+        if let Some(hook) = post_stop {
+            hook.run().await?;
+        }
+        Ok(())
+    }
+    /// First part of a stop: pre_stop, then ask the service to exit (stop_cmd, signal or window message)
+    async fn begin_stop(&mut self, id: &str) -> Result<StopRun> {
+        let (mut run, pre_stop) = self.stop_target(id)?;
+        if let Some(hook) = pre_stop {
+            hook.run().await?;
+        }
+        if let Some(stop_cmd) = self.ask_to_exit(id, &mut run) {
+            stop_cmd.run(id, &mut run).await;
+        }
+        Ok(run)
+    }
+    /// What a stop is about and its pre_stop hook, an aborting pre_stop leaves the process running
+    fn stop_target(&mut self, id: &str) -> Result<(StopRun, Option<HookRun>)> {
+        // An exit before the stop is reported as it happened, not as our stop
+        self.check_running(id, false);
+        // Stop process
+        let svc = self
+            .services
+            .get(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+
+        // Get the parent process PID
+        // Use last_known_pid, it is same as process handle id
+        let target_pid_u32 = svc.last_known_pid.or_else(|| {
+            svc.process.as_ref().map(|p| p.id().unwrap_or(0))
+        });
+        // The hook may still need the running process (e.g. to flush its cache)
+        let was_running = target_pid_u32.is_some_and(|pid| pid > 0);
+        let pre_stop = if was_running { self.prepare_hook(id, Hook::PreStop) } else { None };
+        let run = StopRun {
+            pid: target_pid_u32,
+            tree: Vec::new(),
+            was_running,
+            via_stop_cmd: false,
+            exit_wait: None,
+            on_request: false,
+        };
+        Ok((run, pre_stop))
+    }
+    /// Ask the service to exit after pre_stop: signal or window message, or the stop_cmd to run
+    fn ask_to_exit(&mut self, id: &str, run: &mut StopRun) -> Option<StopCmd> {
+        let target_pid_u32 = run.pid;
+        let svc = self.services.get(id)?;
+        let graceful = svc
+            .config
+            .windows
+            .as_ref()
+            .and_then(|w| w.graceful)
+            .unwrap_or(false);
+        let stop_cmd = svc.config.stop_cmd.clone().filter(|line| !line.trim().is_empty());
+        let stop_signal = svc.config.unix.as_ref().and_then(|u| u.stop_signal).filter(|_| cfg!(unix));
+        // windows.graceful, unix.stop_signal or stop_cmd alone keep a 5s wait
+        let stop_timeout = svc.config.stop_timeout.unwrap_or(if graceful || stop_signal.is_some() || stop_cmd.is_some() {
+            DEFAULT_GRACEFUL_STOP_SECS
+        } else {
+            0
+        });
+        run.via_stop_cmd = stop_cmd.is_some();
+        // Find all descendants of parent process before anything exits or is killed
+        if let Some(pid_val) = target_pid_u32
+            && pid_val > 0
+        {
+            refresh_for_matching(&mut self.sys);
+            run.tree = descendants(&self.sys, Pid::from_u32(pid_val));
+        }
+        // Graceful phase: ask the process to exit and give it time to clean up
+        // stop_cmd replaces the signal / window message
+        let wait = Duration::from_secs(stop_timeout);
+        if let Some(line) = &stop_cmd
+            && let Some(pid_val) = target_pid_u32
+            && pid_val > 0
+        {
+            let config = &self.services[id].config;
+            return Some(StopCmd {
+                pid: pid_val,
+                wait,
+                command: command_line(config, line),
+                timeout: config.hook_timeout_secs.unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS),
+            });
+        } else if stop_timeout > 0
+            && let Some(pid_val) = target_pid_u32
+            && pid_val > 0
+        {
+            if self.request_exit(pid_val, graceful, stop_signal.unwrap_or_default()) {
+                run.exit_wait = Some((pid_val, wait));
+            } else {
+                warn!(service = %id, pid = pid_val, "Could not ask the service to exit, killing it");
+            }
+        }
+        None
+    }
+    /// Kill what is left of the service after the graceful phase
+    /// Returns the processes to see gone and how long to wait for them
+    async fn kill_remaining(&mut self, id: &str, run: &StopRun) -> Result<(Vec<Pid>, Duration)> {
+        let target_pid_u32 = run.pid;
+        let on_request = run.on_request;
+        let tree = run.tree.clone();
+        // The job holds the whole tree, grandchildren included
+        #[cfg(windows)]
+        if let Some(job) = self.services.get_mut(id).and_then(|svc| svc.job.take())
+            && job.terminate()
+        {
+            info!(service = %id, event = "killed_job", "Terminated the service's job object");
+        }
+        // The group holds the tree too, except processes that left it
+        #[cfg(unix)]
+        if let Some(pid_val) = target_pid_u32
+            && let Some(group) = own_process_group(pid_val)
+            && unsafe { libc::killpg(group, libc::SIGKILL) } == 0
+        {
+            info!(service = %id, pid = pid_val, event = "killed_group", "Killed the service's process group");
+        }
+        // Try to clear the process tree (some apps has more than one process)
+        if !tree.is_empty() {
+            refresh_for_matching(&mut self.sys);
+            // Kill child process first, deepest level first (e.g. Worker)
+            for child_pid in tree.iter().rev() {
+                if let Some(proc) = self.sys.process(*child_pid)
+                    && proc.kill()
+                {
+                    info!(
+                        service = %id,
+                        pid = child_pid.as_u32(),
+                        event = "killed_child",
+                        "Killed child process",
+                    );
+                }
+            }
+        }
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        // Kill main process handle (e.g. Monitor)
+        if let Some(mut child) = svc.process.take() {
+            // Try to kill process
+            let _ = child.kill().await;
+            svc.record_exit(ExitInfo::stopped(child.wait().await.ok(), on_request));
+            info!(
+                service = %id,
+                pid = target_pid_u32,
+                event = "stopped",
+                "Stopped service via handle",
+            );
+        } else if let Some(pid_val) = target_pid_u32 {
+            // If lose handle (e.g. restart apps), try to use sysinfo to kill main process
+            let found = self.sys.process(Pid::from_u32(pid_val));
+            if on_request || found.is_some() {
+                svc.record_exit(ExitInfo::stopped(None, on_request));
+            }
+            if let Some(proc) = found {
+                proc.kill();
+                info!(
+                    service = %id,
+                    pid = pid_val,
+                    event = "stopped",
+                    "Killed orphaned main process",
+                );
+            }
+        }
+        // Kill by process name
+        // If still survival under PID killer, use process name to kill
+        // Only processes from this service's tree, unless aggressive_kill is set
+        // (system-wide, may hit unrelated processes sharing the exec name)
+        let target_exec = svc.config.exec.clone();
+        let target_name = exec_file_name(&target_exec);
+        let aggressive = svc.config.aggressive_kill.unwrap_or(false);
+
+        refresh_for_matching(&mut self.sys);
+
+        // Only when escape from PID killer
+        let remining_pids: Vec<Pid> = self.sys.processes().values()
+            .filter(|p| process_name_matches(p.name(), target_name))
+            .filter(|p| aggressive || tree.contains(&p.pid()))
+            .map(|p| p.pid())
+            .collect();
+
+        if !remining_pids.is_empty() {
+            warn!(
+                service = %id,
+                count = remining_pids.len(),
+                "Found lingering processes, cleaning up by name",
+            );
+            for &pid in &remining_pids {
+                if let Some(proc) = self.sys.process(pid) {
+                    proc.kill();
+                    info!(
+                        service = %id,
+                        pid = pid.as_u32(),
+                        name = target_name,
+                        event = "killed_lingering",
+                        "Killed lingering process",
+                    );
+                }
+            }
+        }
+        let kill_timeout = self.services[id].config.kill_timeout_secs.unwrap_or(DEFAULT_KILL_TIMEOUT_SECS);
+
+        // Confirm that everything killed is really gone
+        let mut killed = tree;
+        killed.extend(remining_pids);
+        if let Some(pid_val) = target_pid_u32
+            && pid_val > 0
+        {
+            killed.push(Pid::from_u32(pid_val));
+        }
+        Ok((killed, Duration::from_secs(kill_timeout)))
+    }
+    /// Last part of a stop: forget the process unless it survived, returns post_stop to run
+    fn finish_stop(&mut self, id: &str, run: &StopRun, survivors: Vec<Pid>, kill_timeout: Duration) -> Result<Option<HookRun>> {
+        let target_pid_u32 = run.pid;
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        // clear PID state, unless the main process survived and is still ours to watch
+        if !target_pid_u32.is_some_and(|pid| survivors.contains(&Pid::from_u32(pid))) {
+            svc.last_known_pid = None;
+            svc.finish_run();
+        }
+        self.save_pid_state();
+        if !survivors.is_empty() {
+            let pids: Vec<String> = survivors.iter().map(|p| p.to_string()).collect();
+            error!(
+                service = %id,
+                pids = %pids.join(", "),
+                event = "stop_incomplete",
+                "Processes survived the stop",
+            );
+            return Err(anyhow!(
+                "Service \"{}\" still has {} process(es) running {}s after stop: {}",
+                id,
+                survivors.len(),
+                kill_timeout.as_secs(),
+                pids.join(", ")
+            ));
+        }
+        Ok(if run.was_running { self.prepare_hook(id, Hook::PostStop) } else { None })
+    }
+    /// Run a lifecycle hook of the service and wait for it, see HookRun::run
+    async fn run_hook(&self, id: &str, hook: Hook) -> Result<()> {
+        match self.prepare_hook(id, hook) {
+            Some(run) => run.run().await,
+            None => Ok(()),
+        }
+    }
+    /// A lifecycle hook of the service to run without the manager, None when it has none
+    fn prepare_hook(&self, id: &str, hook: Hook) -> Option<HookRun> {
+        let config = &self.services.get(id)?.config;
+        let hook_config = match hook {
+            Hook::PreStart => &config.pre_start,
+            Hook::PostStart => &config.post_start,
+            Hook::PreStop => &config.pre_stop,
+            Hook::PostStop => &config.post_stop,
+        };
+        let hook_config = hook_config.as_ref().filter(|h| !h.cmd().trim().is_empty())?;
+        let timeout = hook_config
+            .timeout_secs()
+            .or(config.hook_timeout_secs)
+            .unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS);
+        Some(HookRun {
+            id: id.to_string(),
+            hook,
+            command: command_line(config, hook_config.cmd()),
+            timeout,
+            on_failure: hook_config.on_failure(),
+        })
+    }
+    /// Poll until none of the processes exists (zombies count as gone)
+    /// Returns the ones still alive after the timeout
+    async fn wait_for_pids_gone(&mut self, mut pids: Vec<Pid>, timeout: Duration) -> Vec<Pid> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.retain_alive(&mut pids);
+            if pids.is_empty() || Instant::now() >= deadline {
+                return pids;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+    /// Drop the processes that are gone, zombies included
+    fn retain_alive(&mut self, pids: &mut Vec<Pid>) {
+        self.sys.refresh_processes(ProcessesToUpdate::Some(pids), true);
+        pids.retain(|pid| {
+            self.sys
+                .process(*pid)
+                .is_some_and(|p| p.status() != sysinfo::ProcessStatus::Zombie)
+        });
+    }
+    /// Ask the process to exit on its own
+    /// Unix: stop_signal (SIGTERM) to its process group, or to the process when it has none
+    /// Windows: WM_CLOSE to its windows, and CTRL_BREAK when it has its own process group
+    #[allow(unused_variables)] // each platform uses its own options
+    fn request_exit(&mut self, pid: u32, process_group: bool, signal: UnixSignal) -> bool {
+        #[cfg(windows)]
+        {
+            let closed = post_close(pid);
+            let sent_break = process_group && send_ctrl_break(pid);
+            closed || sent_break
+        }
+        #[cfg(unix)]
+        {
+            signal_service(pid, signal.number())
+        }
+    }
+    /// Poll until the process exits, false if it is still alive after the timeout
+    async fn wait_for_exit(&mut self, id: &str, pid: u32, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if self.has_exited(id, pid) {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+    /// Whether the process asked to exit is gone
+    fn has_exited(&mut self, id: &str, pid: u32) -> bool {
+        match self.services.get_mut(id).and_then(|s| s.process.as_mut()) {
+            Some(child) => !matches!(child.try_wait(), Ok(None)),
+            None => {
+                let pid = Pid::from_u32(pid);
+                self.sys.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+                self.sys
+                    .process(pid)
+                    .is_none_or(|p| p.status() == sysinfo::ProcessStatus::Zombie)
+            }
+        }
+    }
+    /// Stop every running service (manager shutdown)
+    pub async fn stop_all(&mut self) -> Vec<(String, Result<()>)> {
+        let order = self.service_order.clone();
+        let running: Vec<String> = order.into_iter().filter(|id| self.is_running(id)).collect();
+        self.stop_many(&running).await
+    }
+    /// Stop the running services flagged stop_on_exit (own flag, else the top-level one) in reverse service order
+    pub async fn stop_for_exit(&mut self) -> Vec<(String, Result<()>)> {
+        let flagged: Vec<String> = self
+            .service_order
+            .iter()
+            .rev()
+            .filter(|id| {
+                self.services
+                    .get(*id)
+                    .is_some_and(|svc| svc.config.stop_on_exit.unwrap_or(self.stop_on_exit))
+            })
+            .cloned()
+            .collect();
+        let running: Vec<String> = flagged.into_iter().filter(|id| self.is_running(id)).collect();
+        self.stop_many(&running).await
+    }
+    /// Stop several services, dependents before what they depend on, otherwise in the given order
+    /// Services that are not running count as stopped
+    pub async fn stop_many(&mut self, ids: &[String]) -> Vec<(String, Result<()>)> {
+        let mut results = Vec::new();
+        let mut order = ids.to_vec();
+        order.sort_by_key(|id| Reverse(self.dependency_depth(id)));
+        for id in order {
+            let result = match self.is_running(&id) {
+                true => self.stop(&id).await,
+                false => Ok(()),
+            };
+            results.push((id, result));
+        }
+        results
+    }
+    /// stop_many for a manager shared with other tasks, see stop_shared
+    pub async fn stop_many_shared(manager: &Mutex<ServiceManager>, ids: &[String]) -> Vec<(String, Result<()>)> {
+        let mut results = Vec::new();
+        let mut order = ids.to_vec();
+        {
+            let mgr = manager.lock().await;
+            order.sort_by_key(|id| Reverse(mgr.dependency_depth(id)));
+        }
+        for id in order {
+            let running = manager.lock().await.is_running(&id);
+            let result = match running {
+                true => Self::stop_shared(manager, &id).await,
+                false => Ok(()),
+            };
+            results.push((id, result));
+        }
+        results
+    }
+    /// Services to start along with the manager: autorun, enabled and not stopped by hand, in service order
+    pub fn autorun_ids(&self) -> Vec<String> {
+        self.service_order
+            .iter()
+            .filter(|id| {
+                self.services.get(*id).is_some_and(|svc| {
+                    svc.config.autorun.unwrap_or(false) && !svc.config.is_held_stopped() && svc.config.is_enabled()
+                })
+            })
+            .cloned()
+            .collect()
+    }
+    /// listen of the config file, None for the default address
+    pub fn listen(&self) -> Option<&str> {
+        self.config_listen.as_deref()
+    }
+    /// Seconds between keep-alive checks, 0 when keep-alive is off
+    pub fn keep_alive_interval(&self) -> u64 {
+        self.keep_alive_interval
+    }
+    /// Services in service order, optionally only those with the given autorun setting / in a group
+    pub fn select(&self, autorun: Option<bool>, group: Option<&str>) -> Vec<String> {
+        self.service_order
+            .iter()
+            .filter(|id| {
+                self.services.get(*id).is_some_and(|svc| {
+                    autorun.is_none_or(|autorun| svc.config.autorun.unwrap_or(false) == autorun)
+                        && group.is_none_or(|group| svc.config.in_group(group))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+    /// Members of a group, in service order
+    pub fn group_members(&self, group: &str) -> Result<Vec<String>> {
+        let members = self.select(None, Some(group));
+        if members.is_empty() {
+            return Err(ServiceError::Invalid(format!("No service is in group '{}'", group)).into());
+        }
+        Ok(members)
+    }
+    /// Restart
+    /// Returns the PID of the new process
+    pub async fn restart(&mut self, id: &str) -> Result<Option<u32>> {
+        self.stop(id).await?;
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        self.start(id).await
+    }
+    /// restart for a manager shared with other tasks, see start_shared
+    pub async fn restart_shared(manager: &Mutex<ServiceManager>, id: &str) -> Result<Option<u32>> {
+        Self::stop_shared(manager, id).await?;
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        Self::start_shared(manager, id).await
+    }
+    /// List
+    pub fn list(&mut self) -> Vec<ServiceStatusSnapshot> {
+        let order = self.service_order.clone();
+        // One process refresh for the whole list
+        refresh_for_matching(&mut self.sys);
+        let snapshots: Vec<ServiceStatusSnapshot> =
+            order.iter().filter_map(|id| self.snapshot_refreshed(id)).collect();
+        self.update_stats(Some(snapshots.iter().filter(|s| s.running).count()));
+        snapshots
+    }
+    /// Services whose config or status changed since a token from an earlier call
+    /// Without a valid token every service is returned
+    pub fn changes(&mut self, since: Option<&str>) -> ChangeSet {
+        let snapshots = self.list();
+        let threshold = self.change_threshold.unwrap_or_default();
+        let tracker = &mut self.changes;
+        // Record what changed since the last call under a new revision
+        // Usage is compared with what was last reported, small moves add up until they count
+        let next = tracker.revision + 1;
+        for snapshot in &snapshots {
+            let id = &snapshot.config.id;
+            if tracker.seen.get(id).is_none_or(|(_, seen)| snapshot_changed(seen, snapshot, threshold)) {
+                tracker.seen.insert(id.clone(), (next, snapshot.clone()));
+                tracker.revision = next;
+            }
+        }
+        let gone: Vec<String> = tracker
+            .seen
+            .keys()
+            .filter(|id| !snapshots.iter().any(|s| &s.config.id == *id))
+            .cloned()
+            .collect();
+        for id in gone {
+            tracker.seen.remove(&id);
+            tracker.removed.push((id, next));
+            tracker.revision = next;
+        }
+        if tracker.removed.len() > MAX_REMOVED_CHANGES {
+            let excess = tracker.removed.len() - MAX_REMOVED_CHANGES;
+            tracker.removed_floor = tracker.removed[excess - 1].1;
+            tracker.removed.drain(..excess);
+        }
+        // Token: "<epoch>-<revision>"
+        let since = since
+            .and_then(|token| token.split_once('-'))
+            .filter(|(epoch, _)| *epoch == format!("{:x}", tracker.epoch))
+            .and_then(|(_, rev)| rev.parse::<u64>().ok())
+            .filter(|rev| *rev <= tracker.revision && *rev >= tracker.removed_floor);
+        let token = format!("{:x}-{}", tracker.epoch, tracker.revision);
+        match since {
+            Some(since) => ChangeSet {
+                token,
+                full: false,
+                changed: snapshots
+                    .into_iter()
+                    .filter(|s| tracker.seen.get(&s.config.id).is_some_and(|(rev, _)| *rev > since))
+                    .collect(),
+                removed: tracker
+                    .removed
+                    .iter()
+                    .filter(|(_, rev)| *rev > since)
+                    .map(|(id, _)| id.clone())
+                    .collect(),
+            },
+            None => ChangeSet { token, full: true, changed: snapshots, removed: Vec::new() },
+        }
+    }
+    /// Publish counts for the health endpoint
+    /// Without an exact running count, services with a known PID are counted
+    fn update_stats(&self, running: Option<usize>) {
+        let running = running.unwrap_or_else(|| {
+            self.services.values().filter(|s| s.last_known_pid.is_some()).count()
+        });
+        self.stats.services.store(self.services.len(), Ordering::Relaxed);
+        self.stats.running.store(running, Ordering::Relaxed);
+    }
+    /// Status of a single service
+    pub fn snapshot(&mut self, id: &str) -> Option<ServiceStatusSnapshot> {
+        if !self.services.contains_key(id) {
+            return None;
+        }
+        refresh_for_matching(&mut self.sys);
+        self.snapshot_refreshed(id)
+    }
+    /// Status from the last process refresh
+    fn snapshot_refreshed(&mut self, id: &str) -> Option<ServiceStatusSnapshot> {
+        let running = self.check_running(id, false);
+        let usage = if running { self.usage(id) } else { None };
+        let svc = self.services.get(id)?;
+        // Spawned by us: since the spawn, adopted: as the system reports it
+        let uptime_secs = match (running, svc.process.is_some(), svc.started_at) {
+            (false, _, _) => None,
+            (true, true, Some(at)) => Some(at.elapsed().as_secs()),
+            (true, _, _) => self
+                .running_pid(id)
+                .and_then(|pid| self.sys.process(Pid::from_u32(pid)))
+                .map(|p| p.run_time()),
+        };
+        Some(ServiceStatusSnapshot {
+            config: svc.config.clone(),
+            running,
+            pid: svc.last_known_pid,
+            // Running without a Child handle: found by PID/name, not spawned by us
+            adopted: running && svc.process.is_none(),
+            last_exit: svc.last_exit,
+            health: svc.config.health_check.as_ref().map(|_| svc.health.clone()),
+            usage,
+            last_run: svc.last_run,
+            uptime_secs,
+            restarts: svc.starts.saturating_sub(1),
+            crashes: svc.crashes,
+            start_failed: svc.start_failed,
+            ready: svc.is_ready(),
+        })
+    }
+    /// CPU and memory of a service's process tree, from the last process refresh
+    fn usage(&mut self, id: &str) -> Option<ResourceUsage> {
+        let root_pid = self.running_pid(id)?;
+        let root = self.sys.process(Pid::from_u32(root_pid))?;
+        let tree: Vec<&sysinfo::Process> = descendants(&self.sys, root.pid())
+            .into_iter()
+            .filter_map(|pid| self.sys.process(pid))
+            .chain([root])
+            .collect();
+        let cpu_ms = root.accumulated_cpu_time();
+        let tree_cpu_ms = tree.iter().map(|p| p.accumulated_cpu_time()).sum();
+        let mut usage = ResourceUsage {
+            memory_rss: root.memory(),
+            tree_memory_rss: tree.iter().map(|p| p.memory()).sum(),
+            processes: tree.len(),
+            ..Default::default()
+        };
+        let svc = self.services.get_mut(id)?;
+        let now = Instant::now();
+        let sample = match svc.cpu_sample {
+            Some(last) if last.pid == root_pid && now - last.at < CPU_SAMPLE_MIN => last,
+            Some(last) if last.pid == root_pid => {
+                let elapsed = (now - last.at).as_millis().max(1) as f32;
+                // Exited children take their CPU time with them, the tree total may drop
+                let percent = |used: u64, before: u64| used.saturating_sub(before) as f32 * 100.0 / elapsed;
+                CpuSample {
+                    pid: root_pid,
+                    at: now,
+                    cpu_ms,
+                    tree_cpu_ms,
+                    cpu_percent: percent(cpu_ms, last.cpu_ms),
+                    tree_cpu_percent: percent(tree_cpu_ms, last.tree_cpu_ms),
+                }
+            }
+            // First look at this process: no percentage yet
+            _ => CpuSample { pid: root_pid, at: now, cpu_ms, tree_cpu_ms, cpu_percent: 0.0, tree_cpu_percent: 0.0 },
+        };
+        svc.cpu_sample = Some(sample);
+        usage.cpu_percent = sample.cpu_percent;
+        usage.tree_cpu_percent = sample.tree_cpu_percent;
+        Some(usage)
+    }
+
+    /// Captured output of a service
+    pub fn output(&self, id: &str) -> Result<&ServiceOutput> {
+        let svc = self
+            .services
+            .get(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        if !svc.config.capture_output.unwrap_or(false) {
+            return Err(ServiceError::Invalid(format!(
+                "Output capture is not enabled for service '{}' (capture_output: true)",
+                id
+            ))
+            .into());
+        }
+        Ok(&svc.output)
+    }
+    /// Follow the captured output of a service
+    pub fn subscribe_output(&self, id: &str) -> Result<broadcast::Receiver<String>> {
+        Ok(self.output(id)?.subscribe())
+    }
+
+    /// Main process and all its descendants, empty when not running
+    pub fn process_tree(&mut self, id: &str) -> Result<Vec<ProcessInfo>> {
+        if !self.services.contains_key(id) {
+            return Err(ServiceError::NotFound(id.to_string()).into());
+        }
+        if !self.is_running(id) {
+            return Ok(Vec::new());
+        }
+        let Some(root) = self.services.get(id).and_then(|s| s.last_known_pid) else {
+            return Ok(Vec::new());
+        };
+        let root = Pid::from_u32(root);
+        refresh_for_matching(&mut self.sys);
+        let tree = std::iter::once(root)
+            .chain(descendants(&self.sys, root))
+            .filter_map(|pid| self.sys.process(pid))
+            .map(|p| ProcessInfo {
+                pid: p.pid().as_u32(),
+                parent: p.parent().map(|pid| pid.as_u32()),
+                name: p.name().to_string_lossy().into_owned(),
+                memory: p.memory(),
+            })
+            .collect();
+        Ok(tree)
+    }
+
+    /// Record the PIDs of all services so the next run can reconnect to them
+    fn save_pid_state(&self) {
+        self.update_stats(None);
+        let Some(config_path) = &self.config_path else {
+            return;
+        };
+        let pids: HashMap<&str, u32> = self
+            .services
+            .iter()
+            .filter_map(|(id, svc)| svc.last_known_pid.map(|pid| (id.as_str(), pid)))
+            .collect();
+        let result = serde_json::to_string_pretty(&pids)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(state_path(config_path), json)?));
+        if let Err(e) = result {
+            warn!(error = %e, "Failed to save PID state");
+        }
+    }
+
+    /// Apply edits of the main config file made outside AppManager
+    /// Unchanged services are left alone, the processes to stop, restart and start are returned for apply_reload
+    /// Returns None when the file still holds what was last read or written
+    pub fn reload_config(&mut self) -> Result<Option<ReloadActions>> {
+        let Some(config_path) = self.config_path.clone() else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(&config_path)
+            .context("Failed to read config file")?;
+        if self.config_content.as_deref() == Some(content.as_str()) {
+            return Ok(None);
+        }
+        let (file, _) = parse_services_file(&content)?;
+        self.config_content = Some(content);
+        // Global settings: keep-alive applies from the next check, listen, CORS, auth and TLS after a restart
+        self.config_listen = file.listen;
+        self.keep_alive_interval = file.keep_alive.unwrap_or(0);
+        self.stop_on_exit = file.stop_on_exit.unwrap_or(false);
+        self.cors = file.cors;
+        self.auth = file.auth;
+        self.tls = file.tls;
+        self.watch_config = file.watch_config.unwrap_or(false);
+        self.change_threshold = file.change_threshold;
+        self.global_env = file.env.unwrap_or_default();
+        self.templates = file.templates.unwrap_or_default();
+        // Services of the main file, duplicates are skipped like on load
+        let mut incoming: Vec<ServiceConfig> = Vec::new();
+        for cfg in file.services {
+            if incoming.iter().any(|c| c.id == cfg.id) {
+                warn!(service = %cfg.id, "Duplicate service ID found in config. Skipping duplicate.");
+                continue;
+            }
+            incoming.push(ServiceConfig { inherited_env: self.global_env.clone(), ..cfg });
+        }
+        // Removed from the main file
+        let removed: Vec<String> = self
+            .services
+            .values()
+            .filter(|s| s.source.is_none() && !incoming.iter().any(|c| c.id == s.config.id))
+            .map(|s| s.config.id.clone())
+            .collect();
+        let mut actions = ReloadActions::default();
+        for id in removed {
+            if self.is_running(&id) {
+                // Out of the config file, but still listed until its process is stopped
+                if let Some(svc) = self.services.get_mut(&id) {
+                    svc.removed = true;
+                }
+                actions.stop.push(id);
+            } else {
+                self.services.remove(&id);
+                info!(service = %id, event = "removed", "Service removed from config");
+            }
+        }
+        // Added or changed
+        for cfg in &incoming {
+            let id = cfg.id.clone();
+            // Removed by an earlier reload but still running: back in the file
+            if let Some(svc) = self.services.get_mut(&id) {
+                svc.removed = false;
+            }
+            match self.services.get_mut(&id) {
+                None => {
+                    if cfg.autorun.unwrap_or(false) && !cfg.is_held_stopped() && cfg.is_enabled() {
+                        actions.start.push(id.clone());
+                    }
+                    self.services.insert(id.clone(), ManagedService::new(cfg.clone()));
+                    info!(service = %id, event = "added", "Service added from config");
+                }
+                // A drop-in config overrides the main file
+                Some(svc) if svc.source.is_some() || svc.config == *cfg => {}
+                Some(svc) => {
+                    let relaunch = svc.config.process_differs(cfg);
+                    svc.set_config(cfg.clone());
+                    info!(service = %id, event = "changed", "Service changed in config");
+                    if relaunch {
+                        actions.restart.push(id);
+                    }
+                }
+            }
+        }
+        // Drop-in services only take the new top-level env
+        for (id, svc) in self.services.iter_mut().filter(|(_, svc)| svc.source.is_some()) {
+            if svc.config.inherited_env != self.global_env {
+                svc.set_config(ServiceConfig { inherited_env: self.global_env.clone(), ..svc.config.clone() });
+                actions.restart.push(id.clone());
+            }
+        }
+        // Main file order first, then the drop-in services
+        let mut order: Vec<String> = incoming.iter().map(|c| c.id.clone()).collect();
+        order.extend(
+            self.service_order
+                .iter()
+                .filter(|id| self.services.contains_key(*id) && !order.contains(id))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        self.service_order = order;
+        self.update_stats(None);
+        Ok(Some(actions))
+    }
+    /// Stop, restart and start what reload_config asked for, the lock is let go like in stop_shared,
+    /// restart_shared and start_many_shared. A removed service whose stop failed stays until a stop succeeds
+    pub async fn apply_reload(manager: &Mutex<ServiceManager>, actions: ReloadActions) {
+        for (id, result) in Self::stop_many_shared(manager, &actions.stop).await {
+            match result {
+                Ok(()) => manager.lock().await.forget_removed(&id),
+                Err(e) => error!(service = %id, error = %format!("{:#}", e), "Failed to stop removed service, keeping it"),
+            }
+        }
+        for id in actions.restart {
+            let running = manager.lock().await.is_running(&id);
+            if running && let Err(e) = Self::restart_shared(manager, &id).await {
+                error!(service = %id, error = %format!("{:#}", e), "Failed to restart changed service");
+            }
+        }
+        for (id, result) in Self::start_many_shared(manager, &actions.start).await {
+            if let Err(e) = result {
+                error!(service = %id, error = %format!("{:#}", e), event = "start_failed", "Failed to autorun service");
+            }
+        }
+    }
+    /// Drop a service removed from the config file once stopped, unless it was added back meanwhile
+    fn forget_removed(&mut self, id: &str) {
+        if !self.services.get(id).is_some_and(|svc| svc.removed) {
+            return;
+        }
+        self.services.remove(id);
+        self.service_order.retain(|x| x != id);
+        self.update_stats(None);
+        info!(service = %id, event = "removed", "Service removed from config");
+    }
+
+    /// Persist the config, or only mark it dirty for the flusher once saves are deferred
+    pub fn save_to_disk(&mut self) -> Result<()> {
+        if let Some(notify) = &self.save_notify {
+            self.dirty = true;
+            notify.notify_one();
+            return Ok(());
+        }
+        self.write_config()
+    }
+    /// Coalesce saves from now on: save_to_disk only marks the config dirty
+    /// The returned Notify fires on each change, the caller flushes after a delay
+    pub(crate) fn defer_saves(&mut self) -> Arc<Notify> {
+        self.save_notify.get_or_insert_with(Arc::default).clone()
+    }
+    /// Write deferred changes, if any
+    /// On failure they stay pending for the next flush
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.write_config()?;
+        self.dirty = false;
+        Ok(())
+    }
+    /// Write configs back to the file each service came from
+    /// Global settings and new services go to the main config
+    fn write_config(&mut self) -> Result<()> {
+        let Some(config_path) = &self.config_path else {
+            return Ok(());
+        };
+        let mut configs = Vec::new();
+        let mut drop_in_configs: Vec<Vec<ServiceConfig>> = vec![Vec::new(); self.drop_in_paths.len()];
+        let mut saved_ids = HashSet::new();
+
+        for id in &self.service_order {
+            if saved_ids.contains(id) { continue; }
+
+            // A removed service that is still running is not written back
+            if let Some(svc) = self.services.get(id).filter(|svc| !svc.removed) {
+                let drop_in = svc
+                    .source
+                    .as_ref()
+                    .and_then(|src| self.drop_in_paths.iter().position(|p| p == src));
+                match drop_in {
+                    Some(index) => drop_in_configs[index].push(svc.config.for_file()),
+                    None => configs.push(svc.config.for_file()),
+                }
+                saved_ids.insert(id.clone());
+            }
+        }
+        let wrapper = ServicesFile {
+            version: Some(CONFIG_VERSION),
+            services: configs,
+            listen: self.config_listen.clone(),
+            keep_alive: if self.keep_alive_interval > 0 { Some(self.keep_alive_interval) } else { None },
+            stop_on_exit: if self.stop_on_exit { Some(true) } else { None },
+            cors_origins: None,
+            cors: self.cors.clone(),
+            auth: self.auth.clone(),
+            tls: self.tls.clone(),
+            watch_config: if self.watch_config { Some(true) } else { None },
+            change_threshold: self.change_threshold,
+            env: if self.global_env.is_empty() { None } else { Some(self.global_env.clone()) },
+            templates: if self.templates.is_empty() { None } else { Some(self.templates.clone()) },
+        };
+
+        let yaml = serde_yaml::to_string(&wrapper)?;
+
+        std::fs::write(config_path, &yaml)?;
+        // Our own write must not look like an external edit
+        self.config_content = Some(yaml);
+        // Drop-in files only hold services
+        for (path, services) in self.drop_in_paths.iter().zip(drop_in_configs) {
+            let wrapper = ServicesFile {
+                version: Some(CONFIG_VERSION),
+                services,
+                ..Default::default()
+            };
+            std::fs::write(path, serde_yaml::to_string(&wrapper)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn upsert_service(&mut self, mut config: ServiceConfig) -> Result<()> {
+        config.inherited_env = self.global_env.clone();
+        config.apply_cmd().map_err(ServiceError::Invalid)?;
+        if config.exec.trim().is_empty() {
+            return Err(ServiceError::Invalid("exec or cmd is required".into()).into());
+        }
+        if let Some(schedule) = &config.schedule {
+            scheduler::check(schedule).map_err(ServiceError::Invalid)?;
+        }
+        if let Some(pattern) = config.ready_when.as_ref().and_then(|r| r.log_matches.as_deref()) {
+            ready_pattern(pattern)?;
+        }
+        // An empty text would match every process
+        let cmdline_contains = config.process_match.as_ref().and_then(|m| m.cmdline_contains());
+        if match_strategy(&config) == MatchStrategy::Cmdline && cmdline_contains.is_none_or(str::is_empty) {
+            return Err(ServiceError::Invalid("match by cmdline needs a non-empty cmdline_contains".into()).into());
+        }
+        let id = config.id.clone();
+        if !self.service_order.contains(&id) {
+            self.service_order.push(id.clone());
+        }
+
+        if let Some(svc) = self.services.get_mut(&config.id) {
+            svc.set_config(config);
+        } else {
+            self.services
+                .insert(config.id.clone(), ManagedService::new(config));
+            self.update_stats(None);
+        }
+        self.save_to_disk()
+    }
+
+    /// Copy a service config under a new id (not started)
+    /// The copy only follows processes it starts itself, the source's running process would match it too
+    pub fn clone_service(&mut self, source_id: &str, new_id: &str, new_name: Option<String>) -> Result<()> {
+        if self.services.contains_key(new_id) {
+            return Err(ServiceError::AlreadyExists(new_id.to_string()).into());
+        }
+        let mut config = self
+            .services
+            .get(source_id)
+            .ok_or_else(|| ServiceError::NotFound(source_id.to_string()))?
+            .config
+            .clone();
+        config.id = new_id.to_string();
+        config.name = new_name.unwrap_or_else(|| format!("{} (copy)", config.name));
+        config.adopt = Some(false);
+        config.pid_file = None;
+        self.upsert_service(config)
+    }
+
+    /// Add a service made from a template
+    pub fn instantiate_template(&mut self, name: &str, id: &str, params: &HashMap<String, String>) -> Result<()> {
+        if self.services.contains_key(id) {
+            return Err(ServiceError::AlreadyExists(id.to_string()).into());
+        }
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| ServiceError::Invalid(format!("Template '{}' not found", name)))?;
+        let config = crate::service::instantiate_template(template, id, params)
+            .map_err(|e| ServiceError::Invalid(format!("Template '{}': {:#}", name, e)))?;
+        self.upsert_service(config)
+    }
+
+    /// Stop and remove a service
+    /// Refuses (Conflict) while its process still runs after the stop, so it is not orphaned
+    /// With force the config is removed anyway and the leftover process is only logged
+    pub async fn remove_service(&mut self, id: &str, force: bool) -> Result<()> {
+        if !self.services.contains_key(id) {
+            return Err(ServiceError::NotFound(id.to_string()).into());
+        }
+        let stopped = self.stop(id).await;
+        self.remove_stopped(id, force, stopped)
+    }
+    /// remove_service for a manager shared with other tasks, see stop_shared
+    pub async fn remove_service_shared(manager: &Mutex<ServiceManager>, id: &str, force: bool) -> Result<()> {
+        if !manager.lock().await.services.contains_key(id) {
+            return Err(ServiceError::NotFound(id.to_string()).into());
+        }
+        // Removed while still locked, keep-alive gets no chance to start it again
+        let (mut mgr, stopped) = Self::stop_locked_after(manager, id).await;
+        mgr.remove_stopped(id, force, stopped)
+    }
+    /// Second part of remove_service, once the stop is done
+    fn remove_stopped(&mut self, id: &str, force: bool, stopped: Result<()>) -> Result<()> {
+        if !self.services.contains_key(id) {
+            return Err(ServiceError::NotFound(id.to_string()).into());
+        }
+        if let Err(e) = &stopped {
+            error!(service = %id, error = %format!("{:#}", e), event = "delete_stop_failed", "Failed to stop service before delete");
+        }
+        if self.is_running(id) {
+            if !force {
+                let reason = match stopped {
+                    Err(e) => format!("{:#}", e),
+                    Ok(()) => "process is still running".to_string(),
+                };
+                return Err(ServiceError::Conflict(format!(
+                    "Service \"{}\" could not be stopped ({}), pass force=true to delete it anyway",
+                    id, reason
+                ))
+                .into());
+            }
+            warn!(service = %id, pid = ?self.services[id].last_known_pid, event = "delete_orphaned", "Deleting service whose process is still running");
+        }
+
+        self.services.remove(id);
+        self.service_order.retain(|x| x != id);
+        self.update_stats(None);
+        self.save_to_disk()?;
+        Ok(())
+    }
+
+    /// Reorder services
+    /// Unknown ids are rejected and nothing changes
+    /// Services missing from the request keep their relative order and are appended at the end
+    /// With a tag only the services carrying it are reordered, among their own positions
+    pub fn reorder_services(&mut self, new_order: Vec<String>, tag: Option<&str>) -> Result<()> {
+        let unknown: Vec<&str> = new_order
+            .iter()
+            .filter(|id| !self.services.contains_key(id.as_str()))
+            .map(|id| id.as_str())
+            .collect();
+        if !unknown.is_empty() {
+            return Err(ServiceError::Invalid(format!(
+                "Unknown service id(s): {}",
+                unknown.join(", ")
+            ))
+            .into());
+        }
+        let in_scope = |id: &str| {
+            tag.is_none_or(|tag| self.services.get(id).is_some_and(|s| s.config.has_tag(tag)))
+        };
+        let untagged: Vec<&str> = new_order
+            .iter()
+            .filter(|id| !in_scope(id))
+            .map(|id| id.as_str())
+            .collect();
+        if !untagged.is_empty() {
+            return Err(ServiceError::Invalid(format!(
+                "Service id(s) without tag '{}': {}",
+                tag.unwrap_or_default(),
+                untagged.join(", ")
+            ))
+            .into());
+        }
+
+        let mut unique_order = Vec::new();
+        let mut seen = HashSet::new();
+
+        for id in new_order {
+            if seen.insert(id.clone()) {
+                unique_order.push(id);
+            }
+        }
+        // Append services the request did not mention
+        for id in &self.service_order {
+            if in_scope(id) && !seen.contains(id) {
+                unique_order.push(id.clone());
+            }
+        }
+        // Fill the positions of the reordered services, the others keep theirs
+        let mut reordered = unique_order.into_iter();
+        self.service_order = self
+            .service_order
+            .iter()
+            .map(|id| match in_scope(id) {
+                true => reordered.next().unwrap_or_else(|| id.clone()),
+                false => id.clone(),
+            })
+            .collect();
+        self.save_to_disk()
+    }
+
+    /// Record whether the user wants a service running or stopped, saved only when it changes
+    /// Running (manual start / resume) also forgets earlier keep-alive restarts
+    pub fn set_desired_state(&mut self, id: &str, state: DesiredState) -> Result<()> {
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        if state == DesiredState::Running {
+            svc.restart_attempts = 0;
+            svc.next_restart = None;
+        }
+        let stored = (state == DesiredState::Stopped).then_some(DesiredState::Stopped);
+        if svc.config.desired_state == stored {
+            return Ok(());
+        }
+        svc.config.desired_state = stored;
+        self.save_to_disk()
+    }
+    /// Set the enabled flag, saved only when it changes
+    pub fn set_enabled(&mut self, id: &str, enabled: bool) -> Result<()> {
+        let svc = self
+            .services
+            .get_mut(id)
+            .ok_or_else(|| ServiceError::NotFound(id.to_string()))?;
+        if svc.config.is_enabled() == enabled {
+            return Ok(());
+        }
+        svc.config.enabled = (!enabled).then_some(false);
+        self.save_to_disk()
+    }
+
+    pub fn set_global_config(&mut self, keep_alive: u64) -> Result<()> {
+        self.keep_alive_interval = keep_alive;
+        self.save_to_disk()
+    }
+
+    /// Keep-alive: whether a service should be restarted now: stopped, failing its health check
+    /// or over its memory limit. Honors the restart policy, max_retries and the backoff between attempts
+    pub(crate) fn needs_restart(&mut self, id: &str) -> bool {
+        // Tasks end on purpose, they only run when started
+        if self.services.get(id).is_none_or(|svc| svc.config.is_task()) {
+            return false;
+        }
+        let running = self.is_running(id);
+        let over_limit = running && self.over_memory_limit(id);
+        let Some(svc) = self.services.get_mut(id) else {
+            return false;
+        };
+        let now = Instant::now();
+        let unhealthy = svc.config.health_check.is_some() && svc.health.status == HealthStatus::Unhealthy;
+        if running && !unhealthy && !over_limit {
+            // Up long enough: the next crash starts a fresh series
+            if svc.started_at.is_some_and(|at| now - at >= RESTART_RESET_AFTER) {
+                svc.restart_attempts = 0;
+                svc.next_restart = None;
+            }
+            return false;
+        }
+        if svc.stopping || svc.removed || svc.config.is_held_stopped() || !svc.config.is_enabled() {
+            return false;
+        }
+        let wanted = match svc.config.restart_policy() {
+            RestartPolicy::Always => true,
+            // A failing health check or a broken limit counts as a failure
+            // Exit unknown (e.g. adopted process): assume a crash
+            RestartPolicy::OnFailure => running || svc.last_exit.is_none_or(|e| e.reason == ExitReason::Crashed),
+            RestartPolicy::Never => false,
+        };
+        if !wanted {
+            return false;
+        }
+        if let Some(max) = svc.config.max_retries
+            && svc.restart_attempts >= max
+        {
+            if svc.next_restart.take().is_some() {
+                error!(service = %id, attempts = svc.restart_attempts, event = "restart_given_up", "Restart limit reached, giving up");
+            }
+            return false;
+        }
+        svc.next_restart.is_none_or(|at| now >= at)
+    }
+    /// Notice tasks that finished, so their duration is exact to the polling interval
+    pub(crate) fn poll_tasks(&mut self) {
+        let running: Vec<String> = self
+            .services
+            .values()
+            .filter(|svc| svc.config.is_task() && svc.process.is_some())
+            .map(|svc| svc.config.id.clone())
+            .collect();
+        for id in running {
+            self.check_running(&id, false);
+        }
+    }
+    /// restart_on_update: running services whose exec was replaced and has settled
+    /// A new version must stay unchanged for UPDATE_SETTLE, a half-copied binary is not started
+    pub(crate) fn updated_services(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut updated = Vec::new();
+        let watched: Vec<String> = self
+            .service_order
+            .iter()
+            .filter(|id| self.services.get(*id).is_some_and(|svc| svc.exec_stamp.is_some()))
+            .cloned()
+            .collect();
+        for id in watched {
+            if !self.is_running(&id) {
+                continue;
+            }
+            let Some(svc) = self.services.get_mut(&id) else { continue };
+            let current = svc.exec_file.as_deref().and_then(file_stamp);
+            match (current, svc.exec_change) {
+                // Unchanged, or gone for a moment while it is replaced
+                (None, _) => svc.exec_change = None,
+                (Some(stamp), _) if Some(stamp) == svc.exec_stamp => svc.exec_change = None,
+                (Some(stamp), Some((seen, since))) if seen == stamp => {
+                    if now - since >= UPDATE_SETTLE {
+                        updated.push(id);
+                    }
+                }
+                (Some(stamp), _) => svc.exec_change = Some((stamp, now)),
+            }
+        }
+        updated
+    }
+    /// Health checks due for running services, their next probe gets scheduled
+    /// The probes run without the manager, results come back through record_health
+    pub(crate) fn due_health_checks(&mut self) -> Vec<Probe> {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .service_order
+            .iter()
+            .filter(|id| {
+                self.services.get(*id).is_some_and(|svc| {
+                    svc.config.health_check.is_some() && svc.next_probe.is_none_or(|at| now >= at)
+                })
+            })
+            .cloned()
+            .collect();
+        let mut probes = Vec::new();
+        for id in due {
+            let running = self.is_running(&id);
+            let Some(svc) = self.services.get_mut(&id) else {
+                continue;
+            };
+            let Some(check) = &svc.config.health_check else {
+                continue;
+            };
+            let interval = check.interval.unwrap_or(health::DEFAULT_INTERVAL_SECS);
+            svc.next_probe = Some(now + Duration::from_secs(interval));
+            if let (true, Some(pid)) = (running, svc.last_known_pid) {
+                // A probe command runs like the hooks: shell, working_dir, run_as
+                probes.push(Probe::new(&id, pid, check, |line| {
+                    LaunchPlan::resolve(&svc.config).map(|plan| plan.hook_command(line))
+                }));
+            }
+        }
+        probes
+    }
+    /// Count a probe of the process `pid`, results for an earlier process are dropped
+    pub(crate) fn record_health(&mut self, id: &str, pid: u32, result: Result<()>) {
+        let Some(svc) = self.services.get_mut(id) else {
+            return;
+        };
+        let Some(check) = &svc.config.health_check else {
+            return;
+        };
+        if svc.last_known_pid != Some(pid) {
+            return;
+        }
+        let before = svc.health.status;
+        svc.health.record(result, check.failures.unwrap_or(health::DEFAULT_FAILURES));
+        match svc.health.status {
+            status if status == before => {}
+            HealthStatus::Unhealthy => warn!(
+                service = %id,
+                failures = svc.health.failures,
+                error = svc.health.last_error.as_deref().unwrap_or_default(),
+                event = "health_failed",
+                "Health check failing",
+            ),
+            status => info!(service = %id, status = ?status, event = "health_changed", "Health check passed"),
+        }
+    }
+    /// Whether the process tree uses more memory than limits.max_memory_mb and should be restarted
+    /// Crossing the limit is logged once, with action: alert that is all that happens
+    fn over_memory_limit(&mut self, id: &str) -> bool {
+        let Some(limits) = self.services.get(id).and_then(|svc| svc.config.limits) else {
+            return false;
+        };
+        let Some(max_mb) = limits.max_memory_mb else {
+            return false;
+        };
+        refresh_for_matching(&mut self.sys);
+        let Some(rss) = self.usage(id).map(|usage| usage.tree_memory_rss) else {
+            return false;
+        };
+        let over = rss > max_mb.saturating_mul(1024 * 1024);
+        let Some(svc) = self.services.get_mut(id) else {
+            return false;
+        };
+        if over && !svc.over_memory {
+            warn!(
+                service = %id,
+                memory_mb = rss / (1024 * 1024),
+                max_memory_mb = max_mb,
+                event = "memory_limit",
+                "Service is over its memory limit",
+            );
+        }
+        svc.over_memory = over;
+        over && limits.action.unwrap_or_default() == LimitAction::Restart
+    }
+    /// Count a keep-alive restart, the next one waits twice as long
+    pub(crate) fn record_restart(&mut self, id: &str) {
+        if let Some(svc) = self.services.get_mut(id) {
+            svc.restart_attempts += 1;
+            let backoff = RESTART_BACKOFF_BASE
+                .saturating_mul(1 << (svc.restart_attempts - 1).min(16))
+                .min(RESTART_BACKOFF_MAX);
+            svc.next_restart = Some(Instant::now() + backoff);
+        }
+    }
+
+    /// Keep-alive interval of a service in seconds, 0 when it isn't watched
+    pub(crate) fn check_interval(&self, id: &str) -> u64 {
+        self.services
+            .get(id)
+            .and_then(|svc| svc.config.check_interval_secs)
+            .unwrap_or(self.keep_alive_interval)
+    }
+}
+
+/// Next keep-alive check of every watched service, earliest first
+#[derive(Default)]
+pub(crate) struct CheckSchedule {
+    queue: BinaryHeap<Reverse<(Instant, String)>>,
+    scheduled: HashSet<String>,
+}
+impl CheckSchedule {
+    /// Schedule services not in the queue yet (new, or interval set since), one interval from now
+    pub fn sync(&mut self, mgr: &ServiceManager, now: Instant) {
+        for id in mgr.services.keys() {
+            let interval = mgr.check_interval(id);
+            if interval > 0 && self.scheduled.insert(id.clone()) {
+                self.queue.push(Reverse((now + Duration::from_secs(interval), id.clone())));
+            }
+        }
+    }
+    /// Services whose check is due, each rescheduled with its current interval
+    /// Removed services and those no longer watched drop out
+    pub fn take_due(&mut self, mgr: &ServiceManager, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        while let Some(Reverse((at, _))) = self.queue.peek()
+            && *at <= now
+        {
+            let Reverse((_, id)) = self.queue.pop().unwrap();
+            let interval = mgr.check_interval(&id);
+            if interval == 0 {
+                self.scheduled.remove(&id);
+                continue;
+            }
+            self.queue.push(Reverse((now + Duration::from_secs(interval), id.clone())));
+            due.push(id);
+        }
+        due
+    }
+    /// When the earliest check is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((at, _))| *at)
+    }
+}
 
-        if self.services.remove(id).is_some() {
-            self.service_order.retain(|x| x != id);
-            self.save_to_disk()?;
-            Ok(())
-        } else {
-            Err(anyhow!("Service not found"))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{HealthCheck, Limits};
+    #[cfg(unix)]
+    use crate::service::{ByteSize, HookConfig, RunAs, ServiceKind};
+    use crate::test_support::{TestDir, sleeper};
+    use sysinfo::ProcessStatus;
+
+    fn manager(services: Vec<ServiceConfig>) -> ServiceManager {
+        ServiceManager::from_config(ServicesFile { services, ..Default::default() }, None).unwrap()
+    }
+
+    fn service(id: &str, exec: &str, args: &[String]) -> ServiceConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "exec": exec,
+            "args": args,
+        }))
+        .unwrap()
+    }
+
+    /// Killed processes may linger as zombies until their parent reaps them
+    fn alive(pid: u32) -> bool {
+        let mut sys = System::new();
+        let pid = Pid::from_u32(pid);
+        sys.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+        sys.process(pid).is_some_and(|p| p.status() != ProcessStatus::Zombie)
+    }
+
+    #[tokio::test]
+    async fn start_and_stop_change_running_state() {
+        let (exec, args, _dir) = sleeper("amt_start_stop");
+        let mut mgr = manager(vec![service("svc", &exec, &args)]);
+        assert!(!mgr.is_running("svc"));
+
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert!(mgr.is_running("svc"));
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(pid));
+        assert!(alive(pid));
+        // Already running: the same PID
+        assert_eq!(mgr.start("svc").await.unwrap(), Some(pid));
+        let usage = mgr.snapshot("svc").unwrap().usage.unwrap();
+        assert!(usage.memory_rss > 0 && usage.tree_memory_rss >= usage.memory_rss);
+        assert_eq!(usage.processes, 1);
+
+        mgr.stop("svc").await.unwrap();
+        assert!(!mgr.is_running("svc"));
+        assert!(!alive(pid));
+        assert_eq!(mgr.services["svc"].last_known_pid, None);
+        assert_eq!(mgr.snapshot("svc").unwrap().usage, None);
+    }
+
+    #[tokio::test]
+    async fn delete_refuses_while_a_process_survives_the_stop() {
+        let (exec, args, _dir) = sleeper("amt_delete");
+        let mut mgr = manager(vec![service("svc", &exec, &args)]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        // A second instance outside the service's tree is not killed by stop
+        let mut outside = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+
+        let err = mgr.remove_service("svc", false).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ServiceError>(), Some(ServiceError::Conflict(_))));
+        assert!(!alive(pid));
+        assert!(mgr.services.contains_key("svc"));
+
+        mgr.remove_service("svc", true).await.unwrap();
+        assert!(!mgr.services.contains_key("svc"));
+        outside.kill().unwrap();
+        outside.wait().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn survivors_of_a_kill_are_reported_after_the_timeout() {
+        let mut mgr = manager(Vec::new());
+        let mut exited = std::process::Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        let gone = Pid::from_u32(exited.id());
+        let me = Pid::from_u32(std::process::id());
+
+        let survivors = mgr.wait_for_pids_gone(vec![gone, me], Duration::from_millis(300)).await;
+        assert_eq!(survivors, [me]);
+    }
+
+    #[tokio::test]
+    async fn dependencies_start_first() {
+        let (exec_a, args, _dir) = sleeper("amt_dep_a");
+        let (exec_b, _, _dir) = sleeper("amt_dep_b");
+        let (exec_c, _, _dir) = sleeper("amt_dep_c");
+        let mut b = service("b", &exec_b, &args);
+        b.depends_on = Some(vec!["a".into()]);
+        let mut c = service("c", &exec_c, &args);
+        c.depends_on = Some(vec!["b".into(), "a".into()]);
+        let services = vec![c, b, service("a", &exec_a, &args)];
+
+        let mut mgr = manager(services.clone());
+        assert_eq!(mgr.dependencies("c").unwrap(), ["a", "b"]);
+        mgr.start("c").await.unwrap();
+        assert!(mgr.is_running("a") && mgr.is_running("b") && mgr.is_running("c"));
+        assert_eq!(mgr.running_dependents("a"), ["c", "b"]);
+        for (_, result) in mgr.stop_all().await {
+            result.unwrap();
         }
+
+        let mut mgr = manager(services);
+        let results = mgr.start_many(&["c".to_string()]).await;
+        let order: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(order, ["a", "b", "c"]);
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+        mgr.stop_all().await;
+
+        let mut x = service("x", "x", &[]);
+        x.depends_on = Some(vec!["y".into()]);
+        let mut y = service("y", "y", &[]);
+        y.depends_on = Some(vec!["x".into()]);
+        let mut z = service("z", "z", &[]);
+        z.depends_on = Some(vec!["nope".into()]);
+        let mgr = manager(vec![x, y, z]);
+        let err = mgr.dependencies("x").unwrap_err().to_string();
+        assert_eq!(err, "Dependency cycle: x -> y -> x");
+        let err = mgr.dependencies("z").unwrap_err().to_string();
+        assert!(err.contains("unknown service 'nope'"), "{err}");
     }
 
-    pub fn reorder_services(&mut self, new_order: Vec<String>) -> Result<()> {
+    #[tokio::test]
+    async fn restart_gives_a_new_pid() {
+        let (exec, args, _dir) = sleeper("amt_restart");
+        let mut mgr = manager(vec![service("svc", &exec, &args)]);
+        mgr.start("svc").await.unwrap();
+        let first = mgr.services["svc"].last_known_pid.unwrap();
 
-        let mut unique_order = Vec::new();
-        let mut seen = HashSet::new();
-        
-        for id in new_order {
+        let second = mgr.restart("svc").await.unwrap().unwrap();
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(second));
+        assert_ne!(first, second);
+        assert!(!alive(first));
+        assert!(alive(second));
 
-            if self.services.contains_key(&id) && !seen.contains(&id) {
-                unique_order.push(id.clone());
-                seen.insert(id);
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[test]
+    fn command_line_text_finds_interpreter_hosted_services() {
+        let (exec, args, _dir) = sleeper("amt_cmdline");
+        let mut external = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+        // Started as "node server.js" would be: the exec name says nothing about the process
+        let mut config = service("svc", "interpreter", &["script".to_string()]);
+        config.process_match = Some(ProcessMatch::Full { by: None, cmdline_contains: Some("amt_cmdline".into()) });
+        let mgr = manager(vec![config]);
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(external.id()));
+
+        let mut mgr = manager(vec![]);
+        let mut config = service("svc", "interpreter", &[]);
+        config.process_match = Some(ProcessMatch::Full { by: None, cmdline_contains: Some(String::new()) });
+        assert!(mgr.upsert_service(config.clone()).is_err());
+        config.process_match = Some(ProcessMatch::By(MatchStrategy::Cmdline));
+        assert!(mgr.upsert_service(config).is_err());
+        external.kill().unwrap();
+        external.wait().unwrap();
+    }
+
+    #[test]
+    fn match_strategy_comes_from_match_or_the_other_options() {
+        let strategy = |extra: serde_json::Value| {
+            let mut config = serde_json::json!({"id": "svc", "name": "svc", "exec": "app", "args": []});
+            config.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            match_strategy(&serde_json::from_value(config).unwrap())
+        };
+        assert_eq!(strategy(serde_json::json!({})), MatchStrategy::Path);
+        assert_eq!(strategy(serde_json::json!({"match": "name"})), MatchStrategy::Name);
+        assert_eq!(strategy(serde_json::json!({"match": {"cmdline_contains": "app.py"}})), MatchStrategy::Cmdline);
+        assert_eq!(strategy(serde_json::json!({"match": {"by": "pid"}})), MatchStrategy::Pid);
+        assert_eq!(strategy(serde_json::json!({"match": "path", "adopt": false})), MatchStrategy::Pid);
+    }
+
+    #[tokio::test]
+    async fn adopt_false_ignores_processes_started_elsewhere() {
+        let (exec, args, _dir) = sleeper("amt_no_adopt");
+        let mut external = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+        let mut config = service("svc", &exec, &args);
+        config.adopt = Some(false);
+
+        let mut mgr = manager(vec![config]);
+        assert_eq!(mgr.services["svc"].last_known_pid, None);
+        assert!(!mgr.is_running("svc"));
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert_ne!(pid, external.id());
+        assert!(mgr.is_running("svc"));
+        mgr.stop("svc").await.unwrap();
+        assert!(!mgr.is_running("svc"));
+        assert!(alive(external.id()));
+        external.kill().unwrap();
+        external.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_exec_that_does_not_resolve_matches_no_process() {
+        let (exec, args, _dir) = sleeper("amt_unresolved");
+        let mut external = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+        let file_name = Path::new(&exec).file_name().unwrap().to_string_lossy().into_owned();
+        let mut config = service("svc", &format!("${{AMT_UNSET_DIR}}/{}", file_name), &args);
+        config.strict_env = Some(true);
+        config.process_match = Some(ProcessMatch::By(MatchStrategy::Name));
+
+        let mut mgr = manager(vec![config.clone()]);
+        assert!(!mgr.is_running("svc"));
+        // Resolved again once the config changes
+        config.exec = exec;
+        mgr.upsert_service(config).unwrap();
+        assert!(mgr.is_running("svc"));
+        external.kill().unwrap();
+        external.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn group_members_are_started_and_stopped_together() {
+        let (exec, args, _dir) = sleeper("amt_group");
+        let mut configs = Vec::new();
+        for (id, group) in [("db", Some("Media")), ("other", None), ("web", Some("media"))] {
+            let mut config = service(id, &exec, &args);
+            config.group = group.map(String::from);
+            // Same program for all three, each only knows its own process
+            config.adopt = Some(false);
+            configs.push(config);
+        }
+        configs[2].depends_on = Some(vec!["db".into()]);
+        let mut mgr = manager(configs);
+        let members = mgr.group_members("media").unwrap();
+        assert_eq!(members, ["db", "web"]);
+        assert!(mgr.group_members("dev").is_err());
+        mgr.services.get_mut("db").unwrap().config.autorun = Some(true);
+        assert_eq!(mgr.select(Some(false), None), ["other", "web"]);
+        assert_eq!(mgr.select(Some(true), Some("media")), ["db"]);
+        assert_eq!(mgr.select(None, None).len(), 3);
+
+        for (_, result) in mgr.start_many(&members).await {
+            result.unwrap();
+        }
+        assert!(mgr.is_running("db") && mgr.is_running("web") && !mgr.is_running("other"));
+        // Dependents go first
+        let stopped: Vec<String> = mgr.stop_many(&members).await.into_iter().map(|(id, r)| r.map(|_| id).unwrap()).collect();
+        assert_eq!(stopped, ["web", "db"]);
+        assert!(!mgr.is_running("db") && !mgr.is_running("web"));
+    }
+
+    #[test]
+    fn clone_copies_the_config_under_a_new_id() {
+        let mut source = service("frpc", "frpc", &["-c".into(), "frpc.toml".into()]);
+        source.env = Some(HashMap::from([("TOKEN".into(), "x".into())]));
+        let mut mgr = manager(vec![source]);
+        mgr.clone_service("frpc", "frpc-2", None).unwrap();
+        let clone = &mgr.services["frpc-2"].config;
+        assert_eq!(clone.name, "frpc (copy)");
+        assert_eq!((&clone.args, &clone.env), (&mgr.services["frpc"].config.args, &mgr.services["frpc"].config.env));
+        assert_eq!(mgr.service_order, ["frpc", "frpc-2"]);
+        assert!(mgr.clone_service("frpc", "frpc-2", None).is_err());
+        assert!(mgr.clone_service("missing", "other", None).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_clone_is_not_running_the_source_process() {
+        let (exec, args, dir) = sleeper("amt_clone");
+        let mut source = service("svc", &exec, &args);
+        let pid_file = dir.join("svc.pid");
+        source.pid_file = Some(pid_file.to_string_lossy().into_owned());
+        let mut mgr = manager(vec![source]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        std::fs::write(&pid_file, pid.to_string()).unwrap();
+        assert!(mgr.is_running("svc"));
+
+        mgr.clone_service("svc", "svc-2", None).unwrap();
+        assert!(!mgr.is_running("svc-2"));
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pid_file_names_the_process_to_adopt() {
+        let (exec, args, _dir) = sleeper("amt_pid_file");
+        let mut external = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+        let mut other = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+        let pid_file = Path::new(&exec).parent().unwrap().join("amt_pid_file.pid");
+        std::fs::write(&pid_file, format!("{}\n", external.id())).unwrap();
+        let mut config = service("svc", &exec, &args);
+        config.pid_file = Some(pid_file.to_string_lossy().into());
+
+        let mut mgr = manager(vec![config]);
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(external.id()));
+        mgr.stop("svc").await.unwrap();
+        external.wait().unwrap();
+        // The pid file is stale now, the other instance is not taken for the service
+        assert!(!mgr.is_running("svc"));
+        assert!(alive(other.id()));
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn running_process_is_adopted_and_stopped() {
+        let (exec, args, _dir) = sleeper("amt_adopt");
+        let mut external = std::process::Command::new(&exec).args(&args).spawn().unwrap();
+
+        let mut mgr = manager(vec![service("svc", &exec, &args)]);
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(external.id()));
+        assert!(mgr.snapshot("svc").unwrap().adopted);
+
+        mgr.stop("svc").await.unwrap();
+        external.wait().unwrap();
+        assert!(!mgr.is_running("svc"));
+    }
+
+    #[test]
+    fn deferred_saves_are_written_on_flush() {
+        let dir = TestDir::new("deferred");
+        let config_path = dir.join("deferred.yaml");
+        let config = config_path.to_str().unwrap();
+        std::fs::write(config, "services: []\n").unwrap();
+        let mut mgr = ServiceManager::new(config).unwrap();
+        let notify = mgr.defer_saves();
+        let saved = std::fs::read_to_string(config).unwrap();
+
+        for id in ["a", "b", "c"] {
+            mgr.upsert_service(service(id, id, &[])).unwrap();
+        }
+        mgr.reorder_services(vec!["c".into(), "b".into()], None).unwrap();
+        assert_eq!(std::fs::read_to_string(config).unwrap(), saved);
+        // One stored permit for the whole burst
+        assert!(futures_util::FutureExt::now_or_never(notify.notified()).is_some());
+        assert!(futures_util::FutureExt::now_or_never(notify.notified()).is_none());
+
+        mgr.flush().unwrap();
+        let (file, _) = load_services_file(config).unwrap();
+        let ids: Vec<_> = file.services.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["c", "b", "a"]);
+    }
+
+    /// reload_config and apply_reload the way the config watcher runs them
+    async fn reload(manager: &Mutex<ServiceManager>) -> bool {
+        let actions = manager.lock().await.reload_config().unwrap();
+        let reloaded = actions.is_some();
+        if let Some(actions) = actions {
+            ServiceManager::apply_reload(manager, actions).await;
+        }
+        reloaded
+    }
+
+    #[tokio::test]
+    async fn reload_reconciles_external_edits() {
+        let (exec_a, _, _dir) = sleeper("amt_reload_a");
+        let (exec_b, _, _dir) = sleeper("amt_reload_b");
+        let dir = Path::new(&exec_a).parent().unwrap();
+        let config_path = dir.join("reload.yaml");
+        let config = config_path.to_str().unwrap();
+        let entry = |id: &str, exec: &str, secs: u32| {
+            format!("- {{ id: {id}, name: {id}, exec: '{exec}', args: ['{secs}'] }}\n")
+        };
+        std::fs::write(config, format!("services:\n{}", entry("a", &exec_a, 30))).unwrap();
+        let manager = Mutex::new(ServiceManager::new(config).unwrap());
+        manager.lock().await.start("a").await.unwrap();
+        let pid = manager.lock().await.services["a"].last_known_pid;
+
+        // Added service, unchanged one keeps running
+        let edited = format!("services:\n{}{}", entry("a", &exec_a, 30), entry("b", &exec_b, 30));
+        std::fs::write(config, &edited).unwrap();
+        assert!(reload(&manager).await);
+        let mut mgr = manager.lock().await;
+        assert!(mgr.services.contains_key("b"));
+        assert_eq!(mgr.service_order, ["a", "b"]);
+        assert_eq!(mgr.services["a"].last_known_pid, pid);
+        assert!(mgr.is_running("a"));
+        drop(mgr);
+        // Nothing new in the file
+        assert!(!reload(&manager).await);
+
+        // Changed command restarts the running service
+        let edited = format!("services:\n{}{}", entry("a", &exec_a, 31), entry("b", &exec_b, 30));
+        std::fs::write(config, &edited).unwrap();
+        assert!(reload(&manager).await);
+        let mut mgr = manager.lock().await;
+        assert!(mgr.is_running("a"));
+        assert_ne!(mgr.services["a"].last_known_pid, pid);
+        let pid = mgr.services["a"].last_known_pid.unwrap();
+        // A stop that fails keeps the removed service, without writing it back
+        mgr.services.get_mut("a").unwrap().config.pre_stop =
+            Some(HookConfig::Full { cmd: "exit 1".into(), timeout_secs: None, on_failure: Some(HookFailure::Abort) });
+        drop(mgr);
+
+        std::fs::write(config, format!("services:\n{}", entry("b", &exec_b, 30))).unwrap();
+        assert!(reload(&manager).await);
+        let mut mgr = manager.lock().await;
+        assert!(mgr.services.contains_key("a") && alive(pid));
+        assert!(!mgr.needs_restart("a"));
+        mgr.set_global_config(5).unwrap();
+        assert!(!std::fs::read_to_string(config).unwrap().contains("id: a"));
+        // Removed service is stopped at the next reload
+        mgr.services.get_mut("a").unwrap().config.pre_stop = None;
+        drop(mgr);
+        std::fs::write(config, format!("keep_alive: 6\nservices:\n{}", entry("b", &exec_b, 30))).unwrap();
+        assert!(reload(&manager).await);
+        let mut mgr = manager.lock().await;
+        assert!(!mgr.services.contains_key("a"));
+        assert_eq!(mgr.service_order, ["b"]);
+        assert!(!alive(pid));
+
+        // Our own writes are not external edits
+        mgr.set_global_config(5).unwrap();
+        drop(mgr);
+        assert!(!reload(&manager).await);
+    }
+
+    #[test]
+    fn creation_flag_warnings_catch_contradictions() {
+        // Defaults: DETACHED_PROCESS, CREATE_NO_WINDOW, with a process group
+        assert!(creation_flag_warnings(DETACHED_PROCESS).is_empty());
+        assert!(creation_flag_warnings(CREATE_NO_WINDOW | CREATE_NEW_PROCESS_GROUP).is_empty());
+
+        assert_eq!(creation_flag_warnings(CREATE_NO_WINDOW | CREATE_NEW_CONSOLE).len(), 1);
+        assert_eq!(creation_flag_warnings(DETACHED_PROCESS | CREATE_NEW_CONSOLE).len(), 1);
+        assert_eq!(creation_flag_warnings(0x20 | 0x80).len(), 1);
+        assert_eq!(creation_flag_warnings(0x0080_0000).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn output_lines_are_split_and_capped() {
+        let output = ServiceOutput::new();
+        let mut rx = output.subscribe();
+        let long = "x".repeat(MAX_LINE_BYTES + 100);
+        let input = format!("first\r\n{long}\nlast");
+        pump_lines(input.as_bytes(), output.clone(), None, None).await;
+
+        assert_eq!(rx.recv().await.unwrap(), "first");
+        assert_eq!(rx.recv().await.unwrap().len(), MAX_LINE_BYTES);
+        assert_eq!(rx.recv().await.unwrap(), "last");
+        assert!(rx.try_recv().is_err());
+        // Late readers get the last lines, then what follows
+        let (tail, mut rx) = output.tail(1);
+        assert_eq!(tail, ["last"]);
+        pump_lines("next\n".as_bytes(), output.clone(), None, None).await;
+        assert_eq!(rx.recv().await.unwrap(), "next");
+        assert_eq!(output.tail(10).0.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn changes_report_only_deltas_since_the_token() {
+        let (exec, args, _dir) = sleeper("amt_changes");
+        let mut mgr = manager(vec![service("a", &exec, &args), service("b", "amt_none", &[])]);
+        let first = mgr.changes(None);
+        assert!(first.full);
+        assert_eq!(first.changed.len(), 2);
+
+        let idle = mgr.changes(Some(&first.token));
+        assert!(!idle.full && idle.changed.is_empty() && idle.removed.is_empty());
+        assert_eq!(idle.token, first.token);
+
+        mgr.start("a").await.unwrap();
+        mgr.remove_service("b", false).await.unwrap();
+        let delta = mgr.changes(Some(&first.token));
+        assert!(!delta.full);
+        assert_eq!(delta.changed.len(), 1);
+        assert!(delta.changed[0].running);
+        assert_eq!(delta.removed, ["b"]);
+
+        // Foreign or broken tokens fall back to the full list
+        assert!(mgr.changes(Some("0-1")).full);
+        assert!(mgr.changes(Some("garbage")).full);
+        mgr.stop("a").await.unwrap();
+    }
+
+    #[test]
+    fn usage_is_a_change_beyond_the_threshold() {
+        let mut mgr = manager(vec![service("a", "amt_none", &[])]);
+        let seen = ServiceStatusSnapshot {
+            usage: Some(ResourceUsage { cpu_percent: 10.0, memory_rss: 100 << 20, ..Default::default() }),
+            uptime_secs: Some(5),
+            ..mgr.snapshot("a").unwrap()
+        };
+        let now = |cpu_percent: f32, memory_rss: u64| ServiceStatusSnapshot {
+            usage: Some(ResourceUsage { cpu_percent, memory_rss, ..Default::default() }),
+            uptime_secs: Some(60),
+            ..seen.clone()
+        };
+        let defaults = ChangeThreshold::default();
+        assert!(!snapshot_changed(&seen, &now(14.0, 105 << 20), defaults));
+        assert!(snapshot_changed(&seen, &now(16.0, 100 << 20), defaults));
+        assert!(snapshot_changed(&seen, &now(10.0, 111 << 20), defaults));
+        assert!(snapshot_changed(&seen, &ServiceStatusSnapshot { usage: None, ..seen.clone() }, defaults));
+        let coarse = ChangeThreshold { cpu_percent: Some(50.0), memory: Some(crate::service::ByteSize(1 << 30)) };
+        assert!(!snapshot_changed(&seen, &now(40.0, 500 << 20), coarse));
+    }
+
+    #[test]
+    fn each_service_is_checked_at_its_own_interval() {
+        let mut fast = service("fast", "fast", &[]);
+        fast.check_interval_secs = Some(2);
+        let mut off = service("off", "off", &[]);
+        off.check_interval_secs = Some(0);
+        let mut mgr = manager(vec![fast, service("slow", "slow", &[]), off]);
+        mgr.keep_alive_interval = 5;
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut schedule = CheckSchedule::default();
+        schedule.sync(&mgr, start);
+        assert_eq!(schedule.next_due(), Some(at(2)));
+
+        let mut checked = Vec::new();
+        for secs in 1..=10 {
+            for id in schedule.take_due(&mgr, at(secs)) {
+                checked.push((secs, id));
             }
         }
-        
+        let times = |name: &str| -> Vec<u64> {
+            checked.iter().filter(|(_, id)| id == name).map(|(secs, _)| *secs).collect()
+        };
+        assert_eq!(times("fast"), [2, 4, 6, 8, 10]);
+        assert_eq!(times("slow"), [5, 10]);
+        assert!(times("off").is_empty());
 
-        for id in self.services.keys() {
-            if !seen.contains(id) {
-                unique_order.push(id.clone());
+        // Unwatched services drop out at their next check
+        mgr.keep_alive_interval = 0;
+        schedule.take_due(&mgr, at(15));
+        assert_eq!(schedule.next_due(), Some(at(17)));
+    }
+
+    #[test]
+    fn reorder_within_a_tag_keeps_other_positions() {
+        let tagged = |id: &str| {
+            let mut cfg = service(id, "x", &[]);
+            cfg.tags = Some(vec!["dl".into()]);
+            cfg
+        };
+        let mut mgr = manager(vec![
+            tagged("a"),
+            service("b", "x", &[]),
+            tagged("c"),
+            service("d", "x", &[]),
+            tagged("e"),
+        ]);
+        mgr.reorder_services(vec!["e".into(), "a".into()], Some("DL")).unwrap();
+        assert_eq!(mgr.service_order, ["e", "b", "a", "d", "c"]);
+
+        assert!(mgr.reorder_services(vec!["b".into()], Some("dl")).is_err());
+        mgr.reorder_services(vec!["d".into()], None).unwrap();
+        assert_eq!(mgr.service_order, ["d", "e", "b", "a", "c"]);
+    }
+
+    #[test]
+    fn only_exec_paths_are_resolved_against_working_dir() {
+        let dir = TestDir::new("exec_path");
+        std::fs::write(dir.join("python"), "").unwrap();
+        let working_dir = dir.to_str().unwrap();
+        // A file of the same name in working_dir doesn't shadow PATH
+        assert_eq!(resolve_exec_path(working_dir, "python"), "python");
+        assert_eq!(Path::new(&resolve_exec_path(working_dir, "./python")), dir.join("./python"));
+        assert_eq!(Path::new(&resolve_exec_path(working_dir, "bin/app")), dir.join("bin/app"));
+    }
+
+    #[tokio::test]
+    async fn missing_working_dir_is_reported_or_created() {
+        let (exec, args, _dir) = sleeper("amt_workdir");
+        let root = TestDir::new("workdir");
+        let dir = root.join("work");
+        let mut config = service("svc", &exec, &args);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        let mut mgr = manager(vec![config]);
+
+        let err = mgr.start("svc").await.unwrap_err();
+        assert!(err.to_string().contains(&*dir.to_string_lossy()), "{}", err);
+        assert!(!dir.exists());
+
+        mgr.services.get_mut("svc").unwrap().config.create_working_dir = Some(true);
+        mgr.start("svc").await.unwrap();
+        assert!(dir.is_dir());
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[test]
+    fn other_instance_with_the_same_name_is_not_adopted() {
+        // Short enough for the Linux process name (15 characters)
+        let (exec, args, _dir) = sleeper("amt_other_inst");
+        // Same binary, other arguments
+        let mut other_args = std::process::Command::new(&exec).arg("31").spawn().unwrap();
+        // Same name, other location
+        let elsewhere = TestDir::new("other");
+        let copy = elsewhere.join(Path::new(&exec).file_name().unwrap());
+        std::fs::copy(&exec, &copy).unwrap();
+        let mut other_path = std::process::Command::new(&copy).args(&args).spawn().unwrap();
+
+        let mut mgr = manager(vec![service("svc", &exec, &args)]);
+        assert_eq!(mgr.services["svc"].last_known_pid, None);
+        assert!(!mgr.is_running("svc"));
+        // Without the path check the copy elsewhere passes for the service
+        let mut config = service("svc", &exec, &args);
+        config.process_match = Some(ProcessMatch::By(MatchStrategy::Name));
+        let mgr = manager(vec![config]);
+        assert_eq!(mgr.services["svc"].last_known_pid, Some(other_path.id()));
+
+        other_args.kill().unwrap();
+        other_path.kill().unwrap();
+        other_args.wait().unwrap();
+        other_path.wait().unwrap();
+    }
+
+    #[test]
+    fn top_level_env_is_inherited_and_overridden() {
+        let global = HashMap::from([("DATA".to_string(), "/srv".to_string()), ("MODE".to_string(), "prod".to_string())]);
+        let mut config = service("svc", "app", &["{DATA}/{MODE}".to_string()]);
+        config.env = Some(HashMap::from([("MODE".to_string(), "dev".to_string())]));
+        let file = ServicesFile { services: vec![config.clone()], env: Some(global), ..Default::default() };
+        let mut mgr = ServiceManager::from_config(file, None).unwrap();
+        let plan = LaunchPlan::resolve(&mgr.services["svc"].config).unwrap();
+        assert_eq!(plan.args, ["/srv/dev"]);
+        assert_eq!(plan.env["DATA"], "/srv");
+
+        // A config sent through the API knows nothing of the top-level env
+        config.env = None;
+        mgr.upsert_service(config).unwrap();
+        assert_eq!(LaunchPlan::resolve(&mgr.services["svc"].config).unwrap().args, ["/srv/prod"]);
+    }
+
+    #[test]
+    fn quoted_paths_with_spaces_resolve() {
+        let mut config = service("svc", r#""C:\Program Files\My App\app.exe""#, &[]);
+        let plan = LaunchPlan::resolve(&config).unwrap();
+        assert_eq!(plan.exec, r"C:\Program Files\My App\app.exe");
+
+        config.exec = r#""bin\app.exe""#.into();
+        config.working_dir = Some(r#""C:\Program Files\My App""#.into());
+        let plan = LaunchPlan::resolve(&config).unwrap();
+        assert_eq!(plan.working_dir.as_deref(), Some(r"C:\Program Files\My App"));
+        assert_eq!(
+            plan.exec,
+            Path::new(r"C:\Program Files\My App").join(r"bin\app.exe").to_string_lossy()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn quoted_exec_with_spaces_starts_is_adopted_and_stops() {
+        let (source, args, _dir) = sleeper("amt_spaces_src");
+        let dir = Path::new(&source).parent().unwrap().join("My App");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(&source, dir.join("amt space")).unwrap();
+        let mut config = service("svc", "\"./amt space\"", &args);
+        config.working_dir = Some(format!("\"{}\"", dir.display()));
+
+        let mut mgr = manager(vec![config.clone()]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert!(mgr.is_running("svc"));
+
+        // A second manager finds it by name, path and arguments
+        let mut other = manager(vec![config]);
+        assert_eq!(other.services["svc"].last_known_pid, Some(pid));
+        other.stop("svc").await.unwrap();
+        assert!(!alive(pid));
+        assert!(!mgr.is_running("svc"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stdio_file_appends_output_to_log_file() {
+        let dir = TestDir::new("stdio");
+        // Uniquely named shell, a system-wide "sh" would count as already running
+        let shell = dir.join("amt_stdio_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let args = ["-c".into(), "echo out; echo err >&2".into()];
+        let mut config = service("svc", &shell.to_string_lossy(), &args);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.create_working_dir = Some(true);
+        config.stdio = Some(StdioMode::File);
+        config.log_file = Some("logs/svc.log".into());
+        let mut mgr = manager(vec![config]);
+
+        for _ in 0..2 {
+            mgr.start("svc").await.unwrap();
+            let child = mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap();
+            child.wait().await.unwrap();
+        }
+        let log = std::fs::read_to_string(dir.join("logs/svc.log")).unwrap();
+        assert_eq!(log.matches("out\n").count(), 2);
+        assert_eq!(log.matches("err\n").count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rotated_log_file_is_written_by_the_manager() {
+        let dir = TestDir::new("rotate");
+        let shell = dir.join("amt_rotate_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let args = ["-c".into(), "for i in 1 2 3 4 5 6; do echo line$i; done".into()];
+        let mut config = service("svc", &shell.to_string_lossy(), &args);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.stdio = Some(StdioMode::File);
+        config.log_file = Some("svc.log".into());
+        config.log_max_size = Some(ByteSize(14));
+        config.log_max_files = Some(2);
+        let mut mgr = manager(vec![config]);
+
+        mgr.start("svc").await.unwrap();
+        let child = mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap();
+        child.wait().await.unwrap();
+        let log = dir.join("svc.log");
+        for _ in 0..50 {
+            if std::fs::read_to_string(&log).unwrap_or_default().contains("line6") {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "line5\nline6\n");
+        assert_eq!(std::fs::read_to_string(dir.join("svc.log.1")).unwrap(), "line3\nline4\n");
+        assert_eq!(std::fs::read_to_string(dir.join("svc.log.2")).unwrap(), "line1\nline2\n");
+    }
 
-        self.service_order = unique_order;
-        self.save_to_disk()
+    #[tokio::test]
+    async fn start_falls_back_to_the_next_exec() {
+        let (exec, args, _dir) = sleeper("amt_fallback");
+        let dir = Path::new(&exec).parent().unwrap();
+        let missing = dir.join("amt_missing").to_string_lossy().into_owned();
+        let mut config = service("svc", &missing, &args);
+        config.exec_fallbacks = Some(vec![dir.join("amt_missing_too").to_string_lossy().into_owned()]);
+        let mut mgr = manager(vec![config.clone()]);
+        let err = mgr.start("svc").await.unwrap_err().to_string();
+        assert!(err.contains("amt_missing (") && err.contains("amt_missing_too ("), "{err}");
+
+        config.exec_fallbacks.as_mut().unwrap().push(exec);
+        let mut mgr = manager(vec![config]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert!(alive(pid));
+        mgr.stop("svc").await.unwrap();
+        assert!(!alive(pid));
     }
 
-    pub fn set_global_config(&mut self, keep_alive: u64) -> Result<()> {
-        self.keep_alive_interval = keep_alive;
-        self.save_to_disk()
+    #[tokio::test]
+    async fn replaced_exec_is_reported_once_settled() {
+        let (exec, args, _dir) = sleeper("amt_update");
+        let mut config = service("svc", &exec, &args);
+        config.restart_on_update = Some(true);
+        let mut mgr = manager(vec![config]);
+        mgr.start("svc").await.unwrap();
+        assert!(mgr.updated_services().is_empty());
+        // Deployed like a build script does: written next to it, then moved over the old one
+        let new = format!("{}.new", exec);
+        std::fs::copy(&exec, &new).unwrap();
+        std::fs::write(&new, [std::fs::read(&new).unwrap(), b"v2".to_vec()].concat()).unwrap();
+        std::fs::rename(&new, &exec).unwrap();
+        // Seen, but not settled yet
+        assert!(mgr.updated_services().is_empty());
+        let svc = mgr.services.get_mut("svc").unwrap();
+        let (stamp, _) = svc.exec_change.unwrap();
+        svc.exec_change = Some((stamp, Instant::now() - UPDATE_SETTLE));
+        assert_eq!(mgr.updated_services(), ["svc"]);
+        // The new version is what runs after the restart
+        mgr.restart("svc").await.unwrap();
+        assert!(mgr.updated_services().is_empty());
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_port_taken_on_ipv6_loopback_only_is_in_use() {
+        let (exec, args, _dir) = sleeper("amt_port_v6");
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let mut config = service("svc", &exec, &args);
+        config.port = Some(listener.local_addr().unwrap().port());
+        let mut mgr = manager(vec![config]);
+        let err = mgr.start("svc").await.unwrap_err();
+        assert!(err.to_string().contains("already in use by another process"), "{err:#}");
+        drop(listener);
+        mgr.start("svc").await.unwrap();
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_privileged_port_is_not_in_use_without_root() {
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        // A port the kernel refuses to bind without root, nothing else is known about it
+        let denied = (1..1024u16).find(|&port| {
+            std::net::TcpListener::bind(("0.0.0.0", port))
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+        });
+        // ip_unprivileged_port_start lowered on this machine
+        let Some(port) = denied else {
+            return;
+        };
+        assert!(!port_taken(port));
+    }
+
+    #[tokio::test]
+    async fn dependents_wait_for_the_ready_port() {
+        let (db_exec, db_args, _dir) = sleeper("amt_ready_db");
+        let (web_exec, web_args, _dir) = sleeper("amt_ready_web");
+        // A free port, nothing listens on it yet
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut db = service("db", &db_exec, &db_args);
+        db.ready_when = Some(crate::service::ReadyWhen { port: Some(port), log_matches: None, timeout: Some(1) });
+        let mut web = service("web", &web_exec, &web_args);
+        web.depends_on = Some(vec!["db".into()]);
+        let mut mgr = manager(vec![db, web]);
+
+        let err = mgr.start("web").await.unwrap_err();
+        assert!(format!("{err:#}").contains("'db' was not ready within 1s"), "{err:#}");
+        assert!(mgr.is_running("db") && !mgr.is_running("web"));
+        assert!(!mgr.snapshot("db").unwrap().ready);
+
+        let _listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        mgr.start("web").await.unwrap();
+        assert!(mgr.snapshot("db").unwrap().ready && mgr.snapshot("web").unwrap().ready);
+        mgr.stop_all().await;
+    }
+
+    #[tokio::test]
+    async fn shared_start_leaves_the_manager_unlocked_while_a_dependency_gets_ready() {
+        let (db_exec, db_args, _dir) = sleeper("amt_shared_db");
+        let (web_exec, web_args, _dir) = sleeper("amt_shared_web");
+        // Listening on IPv6 loopback only
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut db = service("db", &db_exec, &db_args);
+        db.ready_when = Some(crate::service::ReadyWhen { port: Some(port), log_matches: None, timeout: Some(10) });
+        let mut web = service("web", &web_exec, &web_args);
+        web.depends_on = Some(vec!["db".into()]);
+        let manager = Arc::new(Mutex::new(manager(vec![db, web])));
+
+        let start = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::start_shared(&manager, "web").await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // Others get the manager while web waits
+        let mut mgr = tokio::time::timeout(Duration::from_millis(500), manager.lock()).await.unwrap();
+        assert!(mgr.is_running("db") && !mgr.is_running("web"));
+        drop(mgr);
+
+        let _listener = std::net::TcpListener::bind(("::1", port)).unwrap();
+        start.await.unwrap().unwrap();
+        let mut mgr = manager.lock().await;
+        assert!(mgr.snapshot("db").unwrap().ready && mgr.is_running("web"));
+        mgr.stop_all().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ready_when_log_matches_waits_for_the_output_line() {
+        let mut db = service("db", "/bin/sh", &["-c".into(), "sleep 0.5; echo Server started on 5432; exec sleep 30".into()]);
+        db.ready_when = Some(crate::service::ReadyWhen {
+            port: None,
+            log_matches: Some(r"started on \d+$".into()),
+            timeout: Some(5),
+        });
+        db.adopt = Some(false);
+        let (exec, args, _dir) = sleeper("amt_log_ready");
+        let mut web = service("web", &exec, &args);
+        web.depends_on = Some(vec!["db".into()]);
+        let mut mgr = manager(vec![db, web]);
+
+        mgr.start("db").await.unwrap();
+        assert!(!mgr.snapshot("db").unwrap().ready);
+        mgr.start("web").await.unwrap();
+        assert!(mgr.snapshot("db").unwrap().ready);
+        // Read without capture_output
+        assert_eq!(mgr.services["db"].output.tail(1).0, ["Server started on 5432"]);
+        mgr.stop_all().await;
+
+        let mut bad = service("bad", "app", &[]);
+        bad.ready_when = Some(crate::service::ReadyWhen { port: None, log_matches: Some("(x".into()), timeout: None });
+        assert!(mgr.upsert_service(bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn manually_stopped_services_stay_down() {
+        let (exec, args, _dir) = sleeper("amt_desired");
+        let mut config = service("svc", &exec, &args);
+        config.autorun = Some(true);
+        let mut mgr = manager(vec![config]);
+        mgr.start("svc").await.unwrap();
+        // What the stop endpoint does
+        mgr.stop("svc").await.unwrap();
+        mgr.set_desired_state("svc", DesiredState::Stopped).unwrap();
+        assert!(!mgr.needs_restart("svc"));
+        // A manual stop doesn't retire the service
+        assert!(mgr.services["svc"].config.is_enabled());
+        // The start and resume endpoints make it wanted again
+        mgr.set_desired_state("svc", DesiredState::Running).unwrap();
+        assert!(mgr.needs_restart("svc"));
+    }
+
+    #[tokio::test]
+    async fn disabled_services_are_never_started() {
+        let (exec, args, _dir) = sleeper("amt_enabled");
+        let mut config = service("svc", &exec, &args);
+        config.enabled = Some(false);
+        config.autorun = Some(true);
+        let mut mgr = manager(vec![config]);
+        let err = mgr.start("svc").await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ServiceError::Conflict(_))), "{err}");
+        assert!(mgr.start_many(&["svc".to_string()]).await[0].1.is_err());
+        assert!(!mgr.needs_restart("svc"));
+        assert!(!mgr.is_running("svc"));
+        // Resuming after a manual stop leaves it retired, enabling brings it back
+        mgr.set_desired_state("svc", DesiredState::Running).unwrap();
+        assert!(!mgr.needs_restart("svc"));
+        mgr.set_enabled("svc", true).unwrap();
+        assert!(mgr.needs_restart("svc"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shell_mode_runs_pipes_and_chains() {
+        let mut config = service("svc", "printf 'one\\n' | tr o 0 && echo", &["two".into()]);
+        config.shell = Some(true);
+        config.capture_output = Some(true);
+        let mut mgr = manager(vec![config]);
+        mgr.start("svc").await.unwrap();
+        mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap().wait().await.unwrap();
+        for _ in 0..50 {
+            if mgr.services["svc"].output.tail(10).0.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(mgr.services["svc"].output.tail(10).0, ["0ne", "two"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn env_clear_and_env_remove_limit_the_inherited_environment() {
+        let mut config = service("svc", "/usr/bin/env", &[]);
+        config.env = Some(HashMap::from([("ONLY".to_string(), "1".to_string())]));
+        config.env_clear = Some(true);
+        config.capture_output = Some(true);
+        let mut mgr = manager(vec![config]);
+        let output = async |mgr: &mut ServiceManager| {
+            mgr.start("svc").await.unwrap();
+            mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap().wait().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            mgr.services["svc"].output.tail(1000).0
+        };
+        assert_eq!(output(&mut mgr).await, ["ONLY=1"]);
+
+        let config = &mut mgr.services.get_mut("svc").unwrap().config;
+        config.env_clear = None;
+        config.env_remove = Some(vec!["PATH".into()]);
+        let lines = output(&mut mgr).await;
+        assert!(lines.iter().any(|line| line == "ONLY=1") && lines.len() > 1, "{lines:?}");
+        assert!(!lines.iter().any(|line| line.starts_with("PATH=")), "{lines:?}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn scripts_run_through_their_interpreter() {
+        let dir = TestDir::new("script");
+        // Not executable, only sh can run it
+        std::fs::write(dir.join("run.sh"), "echo \"$0:$1\"\n").unwrap();
+        let mut config = service("svc", "./run.sh", &["two words".into()]);
+        config.working_dir = Some(dir.to_string_lossy().into());
+        config.capture_output = Some(true);
+        let plan = LaunchPlan::resolve(&config).unwrap();
+        let script = dir.join("./run.sh").to_string_lossy().to_string();
+        assert_eq!(plan.argv(), ("sh", vec![script.as_str(), "two words"]));
+        let mut mgr = manager(vec![config.clone()]);
+        mgr.start("svc").await.unwrap();
+        mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap().wait().await.unwrap();
+        for _ in 0..50 {
+            if !mgr.services["svc"].output.tail(1).0.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(mgr.services["svc"].output.tail(1).0, [format!("{}:two words", script)]);
+
+        config.interpreter = Some("bash -e".into());
+        assert_eq!(LaunchPlan::resolve(&config).unwrap().interpreter, ["bash", "-e"]);
+        config.interpreter = Some(String::new());
+        assert_eq!(LaunchPlan::resolve(&config).unwrap().argv(), (script.as_str(), vec!["two words"]));
+        config.exec = "app.PY".into();
+        config.interpreter = None;
+        assert_eq!(LaunchPlan::resolve(&config).unwrap().interpreter, ["python3"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn exit_during_startup_grace_fails_the_start() {
+        let mut config = service("svc", "/bin/sh", &["-c".into(), "echo bad config >&2; exit 2".into()]);
+        config.startup_grace_secs = Some(1);
+        config.capture_output = Some(true);
+        config.adopt = Some(false);
+        let mut mgr = manager(vec![config]);
+        let err = mgr.start("svc").await.unwrap_err().to_string();
+        assert!(err.contains("exited within 1s") && err.ends_with("last output:\nbad config"), "{err}");
+        assert!(mgr.list()[0].start_failed);
+        // The next spawn clears it
+        mgr.services.get_mut("svc").unwrap().config.args = vec!["-c".into(), "sleep 5".into()];
+        mgr.start("svc").await.unwrap();
+        assert!(!mgr.list()[0].start_failed);
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keep_alive_follows_the_restart_policy() {
+        async fn run_to_exit(mgr: &mut ServiceManager, code: u32) {
+            mgr.services.get_mut("svc").unwrap().config.args = vec!["-c".into(), format!("exit {code}")];
+            mgr.start("svc").await.unwrap();
+            let child = mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap();
+            child.wait().await.unwrap();
+        }
+        let (sleep, _, _dir) = sleeper("amt_policy_sleep");
+        let shell = Path::new(&sleep).parent().unwrap().join("amt_policy_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let mut config = service("svc", &shell.to_string_lossy(), &[]);
+        config.restart = Some(RestartPolicy::OnFailure);
+        config.max_retries = Some(2);
+        let mut mgr = manager(vec![config]);
+        let expire_backoff = |mgr: &mut ServiceManager| {
+            mgr.services.get_mut("svc").unwrap().next_restart = Some(Instant::now());
+        };
+
+        run_to_exit(&mut mgr, 3).await;
+        assert!(mgr.needs_restart("svc"));
+        mgr.record_restart("svc");
+        // Backoff before the next attempt
+        assert!(!mgr.needs_restart("svc"));
+        expire_backoff(&mut mgr);
+        assert!(mgr.needs_restart("svc"));
+        run_to_exit(&mut mgr, 3).await;
+        mgr.record_restart("svc");
+        expire_backoff(&mut mgr);
+        // max_retries reached
+        assert!(!mgr.needs_restart("svc"));
+        // A manual start forgets the failures
+        mgr.set_desired_state("svc", DesiredState::Running).unwrap();
+        assert!(mgr.needs_restart("svc"));
+
+        // A clean exit is not a failure
+        run_to_exit(&mut mgr, 0).await;
+        assert!(!mgr.needs_restart("svc"));
+        mgr.services.get_mut("svc").unwrap().config.restart = Some(RestartPolicy::Always);
+        assert!(mgr.needs_restart("svc"));
+        mgr.services.get_mut("svc").unwrap().config.restart = Some(RestartPolicy::Never);
+        assert!(!mgr.needs_restart("svc"));
+    }
+
+    #[tokio::test]
+    async fn memory_limit_asks_for_a_restart() {
+        let (exec, args, _dir) = sleeper("amt_memory");
+        let mut config = service("svc", &exec, &args);
+        config.restart = Some(RestartPolicy::Always);
+        config.limits = Some(Limits { max_memory_mb: Some(1024 * 1024), action: None });
+        let mut mgr = manager(vec![config]);
+        mgr.start("svc").await.unwrap();
+        assert!(!mgr.needs_restart("svc"));
+
+        // Any process is over a 0 MB limit
+        let limits = &mut mgr.services.get_mut("svc").unwrap().config.limits;
+        *limits = Some(Limits { max_memory_mb: Some(0), action: None });
+        assert!(mgr.needs_restart("svc"));
+        let limits = &mut mgr.services.get_mut("svc").unwrap().config.limits;
+        *limits = Some(Limits { max_memory_mb: Some(0), action: Some(LimitAction::Alert) });
+        assert!(!mgr.needs_restart("svc"));
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failing_health_check_asks_for_a_restart() {
+        let (exec, args, _dir) = sleeper("amt_health");
+        let mut config = service("svc", &exec, &args);
+        config.restart = Some(RestartPolicy::OnFailure);
+        config.health_check = Some(HealthCheck {
+            http: Some("http://127.0.0.1:9/".into()),
+            tcp: None,
+            cmd: None,
+            expect_exit: None,
+            interval: Some(0),
+            timeout: None,
+            failures: Some(2),
+        });
+        let mut mgr = manager(vec![config]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+
+        let due = mgr.due_health_checks();
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].id.as_str(), due[0].pid), ("svc", pid));
+        mgr.record_health("svc", pid, Err(anyhow!("refused")));
+        // One failure is below the threshold
+        assert!(!mgr.needs_restart("svc"));
+        // Results for an earlier process don't count
+        mgr.record_health("svc", pid + 1, Err(anyhow!("refused")));
+        assert!(!mgr.needs_restart("svc"));
+        mgr.record_health("svc", pid, Err(anyhow!("refused")));
+        let health = mgr.snapshot("svc").unwrap().health.unwrap();
+        assert_eq!((health.status, health.failures), (HealthStatus::Unhealthy, 2));
+        assert!(mgr.needs_restart("svc"));
+
+        // A new process starts out unprobed
+        mgr.restart("svc").await.unwrap();
+        assert_eq!(mgr.snapshot("svc").unwrap().health.unwrap(), HealthReport::default());
+        assert!(!mgr.needs_restart("svc"));
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_asks_politely_before_killing() {
+        let (sleep, _, _dir) = sleeper("amt_term_sleep");
+        let dir = Path::new(&sleep).parent().unwrap().join("term");
+        std::fs::create_dir_all(&dir).unwrap();
+        let shell = dir.join("amt_term_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let script = format!("trap 'echo term > term.log; exit 0' TERM; while :; do {sleep} 0.1; done");
+        let mut config = service("svc", &shell.to_string_lossy(), &["-c".into(), script]);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.stop_timeout = Some(10);
+        let mut mgr = manager(vec![config]);
+
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        let started = Instant::now();
+        mgr.stop("svc").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(std::fs::read_to_string(dir.join("term.log")).unwrap(), "term\n");
+        assert!(!alive(pid));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_shared_lets_go_of_the_manager_while_waiting() {
+        let (sleep, _, _dir) = sleeper("amt_stubborn_sleep");
+        // Ignores the stop request, only the kill after stop_timeout ends it
+        let script = format!("trap '' TERM; while :; do {sleep} 0.1; done");
+        let mut config = service("svc", "sh", &["-c".into(), script]);
+        config.stop_timeout = Some(2);
+        config.restart = Some(RestartPolicy::Always);
+        let manager = Arc::new(Mutex::new(manager(vec![config])));
+        let pid = manager.lock().await.start("svc").await.unwrap().unwrap();
+
+        let stopping = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::stop_shared(&manager, "svc").await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let mut mgr = tokio::time::timeout(Duration::from_millis(500), manager.lock()).await.unwrap();
+        // Keep-alive leaves a service alone while it goes down
+        assert!(!mgr.needs_restart("svc"));
+        drop(mgr);
+        stopping.await.unwrap().unwrap();
+        assert!(!alive(pid));
+    }
+
+    #[tokio::test]
+    async fn start_shared_lets_go_of_the_manager_during_the_grace_period() {
+        let (exec, args, _dir) = sleeper("amt_graceful_start");
+        let mut config = service("svc", &exec, &args);
+        config.startup_grace_secs = Some(2);
+        let manager = Arc::new(Mutex::new(manager(vec![config])));
+
+        let starting = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::start_shared(&manager, "svc").await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let mut mgr = tokio::time::timeout(Duration::from_millis(500), manager.lock()).await.unwrap();
+        assert!(mgr.is_running("svc"));
+        drop(mgr);
+        let pid = starting.await.unwrap().unwrap().unwrap();
+        assert!(alive(pid));
+        manager.lock().await.stop("svc").await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_signal_reaches_the_whole_process_group() {
+        let dir = TestDir::new("group");
+        let child = "trap 'echo child >> stop.log; exit 0' USR1; while :; do sleep 0.1; done";
+        let script = format!("sh -c \"{child}\" & trap 'wait; echo parent >> stop.log; exit 0' USR1; wait");
+        let mut config = service("svc", "sh", &["-c".into(), script]);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.unix = Some(crate::service::UnixOptions { stop_signal: Some(UnixSignal::Usr1), ..Default::default() });
+        let mut mgr = manager(vec![config]);
+
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert_eq!(own_process_group(pid), Some(pid as i32));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        mgr.stop("svc").await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("stop.log")).unwrap(), "child\nparent\n");
+        assert!(!alive(pid));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn truncated_linux_process_names_match() {
+        assert!(process_name_matches(OsStr::new("appmanager-work"), "appmanager-worker"));
+        assert!(!process_name_matches(OsStr::new("appmanager"), "appmanager-worker"));
+        assert!(!process_name_matches(OsStr::new("appmanager-serv"), "appmanager-worker"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_cmd_replaces_the_signal() {
+        let (sleep, _, _dir) = sleeper("amt_stopcmd_sleep");
+        let dir = Path::new(&sleep).parent().unwrap().join("stopcmd");
+        std::fs::create_dir_all(&dir).unwrap();
+        let shell = dir.join("amt_stopcmd_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let script = format!("echo $$ > app.pid; trap 'echo quit > quit.log; exit 0' USR1; while :; do {sleep} 0.1; done");
+        let mut config = service("svc", &shell.to_string_lossy(), &["-c".into(), script]);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.stop_cmd = Some("kill -USR1 $(cat app.pid)".into());
+        let mut mgr = manager(vec![config]);
+
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        mgr.stop("svc").await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("quit.log")).unwrap(), "quit\n");
+        assert!(!alive(pid));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hooks_run_around_start_and_stop() {
+        let (exec, args, _dir) = sleeper("amt_hooks");
+        let dir = Path::new(&exec).parent().unwrap().join("hooks");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = service("svc", &exec, &args);
+        config.working_dir = Some(dir.to_string_lossy().into_owned());
+        config.pre_start = Some(HookConfig::Cmd("echo starting >> hooks.log".into()));
+        config.post_start = Some(HookConfig::Cmd("echo started >> hooks.log".into()));
+        // A failing hook is only logged, the stop goes on
+        config.pre_stop = Some(HookConfig::Cmd("echo stopping >> hooks.log; exit 1".into()));
+        config.post_stop = Some(HookConfig::Cmd("echo stopped >> hooks.log".into()));
+        let mut mgr = manager(vec![config]);
+
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        let log = dir.join("hooks.log");
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "starting\nstarted\n");
+        mgr.stop("svc").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "starting\nstarted\nstopping\nstopped\n");
+        assert!(!alive(pid));
+        // Nothing running: no stop hooks
+        mgr.stop("svc").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "starting\nstarted\nstopping\nstopped\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn aborting_hooks_fail_the_start_and_stop() {
+        let (exec, args, _dir) = sleeper("amt_hooks_abort");
+        let abort = |cmd: &str| HookConfig::Full {
+            cmd: cmd.into(),
+            timeout_secs: Some(1),
+            on_failure: Some(HookFailure::Abort),
+        };
+        let mut config = service("svc", &exec, &args);
+        config.pre_start = Some(abort("exit 1"));
+        let mut mgr = manager(vec![config]);
+        let err = mgr.start("svc").await.unwrap_err();
+        assert!(err.to_string().starts_with("pre_start hook of 'svc' failed"), "{err}");
+        assert!(!mgr.is_running("svc"));
+
+        // A timed out post_start takes the process down again
+        let svc = mgr.services.get_mut("svc").unwrap();
+        svc.config.pre_start = None;
+        svc.config.post_start = Some(abort("sleep 5"));
+        assert!(mgr.start("svc").await.is_err());
+        assert!(!mgr.is_running("svc"));
+
+        let svc = mgr.services.get_mut("svc").unwrap();
+        svc.config.post_start = None;
+        svc.config.pre_stop = Some(abort("exit 1"));
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        assert!(mgr.stop("svc").await.is_err());
+        assert!(alive(pid));
+        mgr.services.get_mut("svc").unwrap().config.pre_stop = None;
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shared_starts_and_stops_run_commands_without_the_manager() {
+        let (exec, args, _dir) = sleeper("amt_slow_hooks");
+        let mut config = service("svc", &exec, &args);
+        config.pre_start = Some(HookConfig::Cmd("sleep 1".into()));
+        config.pre_stop = Some(HookConfig::Cmd("sleep 1".into()));
+        let manager = Arc::new(Mutex::new(manager(vec![config])));
+        // The hook is running when the manager is asked for
+        let locked_during = |task: tokio::task::JoinHandle<Result<()>>| {
+            let manager = manager.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(400)).await;
+                let locked = tokio::time::timeout(Duration::from_millis(300), manager.lock()).await.is_ok();
+                task.await.unwrap().unwrap();
+                locked
+            }
+        };
+
+        let starting = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::start_shared(&manager, "svc").await.map(|_| ()) }
+        });
+        assert!(locked_during(starting).await);
+        assert!(manager.lock().await.is_running("svc"));
+        let stopping = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::stop_shared(&manager, "svc").await }
+        });
+        assert!(locked_during(stopping).await);
+        assert!(!manager.lock().await.is_running("svc"));
+
+        {
+            let mut mgr = manager.lock().await;
+            let config = &mut mgr.services.get_mut("svc").unwrap().config;
+            config.pre_start = None;
+            config.pre_stop = None;
+            config.stop_cmd = Some("sleep 1".into());
+            config.stop_timeout = Some(0);
+        }
+        ServiceManager::start_shared(&manager, "svc").await.unwrap();
+        let stopping = tokio::spawn({
+            let manager = manager.clone();
+            async move { ServiceManager::stop_shared(&manager, "svc").await }
+        });
+        assert!(locked_during(stopping).await);
+        assert!(!manager.lock().await.is_running("svc"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_as_resolves_names_and_ids() {
+        let mut config = service("svc", "true", &[]);
+        let run_as = |user: &str, group: Option<&str>| {
+            Some(RunAs { user: Some(user.into()), group: group.map(Into::into), password_env: None })
+        };
+        config.run_as = run_as("root", None);
+        let plan = LaunchPlan::resolve(&config).unwrap();
+        assert_eq!((plan.uid, plan.gid), (Some(0), Some(0)));
+
+        config.run_as = run_as("12345", Some(" 678 "));
+        let plan = LaunchPlan::resolve(&config).unwrap();
+        assert_eq!((plan.uid, plan.gid), (Some(12345), Some(678)));
+
+        config.run_as = run_as("amt-no-such-user", None);
+        let err = LaunchPlan::resolve(&config).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ServiceError>(), Some(ServiceError::Invalid(_))));
+    }
+
+    #[test]
+    fn priority_replaces_the_class_in_creation_flags() {
+        let mut config = service("svc", "true", &[]);
+        config.windows = serde_yaml::from_str("{creation_flags: 0x08000080, priority: below_normal}").unwrap();
+        assert_eq!(LaunchPlan::resolve(&config).unwrap().creation_flags, 0x08004000);
+
+        config.windows = serde_yaml::from_str("{creation_flags: 8, affinity_mask: 0}").unwrap();
+        assert!(LaunchPlan::resolve(&config).is_err());
+        config.windows = None;
+        config.nice = Some(20);
+        assert!(LaunchPlan::resolve(&config).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn nice_value_is_set_before_exec() {
+        let (sleep, args, _dir) = sleeper("amt_nice_sleep");
+        let mut config = service("svc", &sleep, &args);
+        config.nice = Some(7);
+        let mut mgr = manager(vec![config]);
+        let pid = mgr.start("svc").await.unwrap().unwrap();
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+        assert_eq!(nice, 7);
+        mgr.stop("svc").await.unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn logon_command_line_is_quoted() {
+        let mut plan = LaunchPlan::resolve(&service("svc", r"C:\Program Files\app.exe", &[])).unwrap();
+        plan.args = vec!["plain".into(), "two words".into(), r#"say "hi""#.into(), r"dir\ end\".into(), String::new()];
+        let line = windows_command_line(&plan);
+        assert_eq!(line, r#""C:\Program Files\app.exe" plain "two words" "say \"hi\"" "dir\ end\\" """#);
+
+        let plan = LaunchPlan::resolve(&service("svc", r"C:\My Scripts\run.bat", &["a&b".into(), "x y".into()])).unwrap();
+        assert_eq!(windows_command_line(&plan), r#"cmd /C ""C:\My Scripts\run.bat" "a&b" "x y"""#);
+        // ShellExecuteEx (elevated) takes the program apart from the rest
+        let (program, params) = windows_program_and_params(&plan);
+        assert_eq!((program.as_str(), params.as_str()), ("cmd", r#"/C ""C:\My Scripts\run.bat" "a&b" "x y"""#));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn task_runs_once_and_records_its_run() {
+        let (sleep, _, _dir) = sleeper("amt_task_sleep");
+        let shell = Path::new(&sleep).parent().unwrap().join("amt_task_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let mut config = service("task", &shell.to_string_lossy(), &["-c".into(), "exit 2".into()]);
+        config.kind = Some(ServiceKind::Task);
+        config.restart = Some(RestartPolicy::Always);
+        let mut mgr = manager(vec![config]);
+
+        mgr.start("task").await.unwrap();
+        let child = mgr.services.get_mut("task").unwrap().process.as_mut().unwrap();
+        child.wait().await.unwrap();
+        mgr.poll_tasks();
+        let snapshot = mgr.snapshot("task").unwrap();
+        assert!(!snapshot.running);
+        assert_eq!(snapshot.last_exit.unwrap().code, Some(2));
+        assert!(snapshot.last_run.unwrap().duration_ms.is_some());
+        // Keep-alive leaves it alone, whatever the restart policy
+        assert!(!mgr.needs_restart("task"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn exit_code_is_kept_until_the_next_start() {
+        let (sleep, _, _dir) = sleeper("amt_exit_sleep");
+        let shell = Path::new(&sleep).parent().unwrap().join("amt_exit_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let args = ["-c".into(), "exit 3".into()];
+        let mut mgr = manager(vec![service("svc", &shell.to_string_lossy(), &args)]);
+
+        mgr.start("svc").await.unwrap();
+        let child = mgr.services.get_mut("svc").unwrap().process.as_mut().unwrap();
+        child.wait().await.unwrap();
+        assert!(!mgr.is_running("svc"));
+        let exit = mgr.snapshot("svc").unwrap().last_exit.unwrap();
+        assert_eq!((exit.code, exit.signal, exit.reason), (Some(3), None, ExitReason::Crashed));
+        assert!(exit.at > 0);
+        assert_eq!(mgr.snapshot("svc").unwrap().uptime_secs, None);
+
+        mgr.services.get_mut("svc").unwrap().config.args = vec!["-c".into(), format!("{sleep} 30")];
+        mgr.start("svc").await.unwrap();
+        let snapshot = mgr.snapshot("svc").unwrap();
+        assert_eq!(snapshot.last_exit, None);
+        assert_eq!((snapshot.restarts, snapshot.crashes), (1, 1));
+        assert!(snapshot.uptime_secs.is_some());
+        // Our own stop is told apart from a crash
+        mgr.stop("svc").await.unwrap();
+        assert_eq!(mgr.snapshot("svc").unwrap().last_exit.unwrap().reason, ExitReason::Killed);
+
+        mgr.services.get_mut("svc").unwrap().config.stop_timeout = Some(5);
+        mgr.start("svc").await.unwrap();
+        mgr.stop("svc").await.unwrap();
+        let snapshot = mgr.snapshot("svc").unwrap();
+        assert_eq!(snapshot.last_exit.unwrap().reason, ExitReason::Stopped);
+        // Stops are not crashes
+        assert_eq!((snapshot.restarts, snapshot.crashes), (2, 1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_kills_the_process_tree() {
+        let (child, _, _dir) = sleeper("amt_tree_child");
+        // A copied shell as parent, so the service name is unique as well
+        let dir = Path::new(&child).parent().unwrap().to_path_buf();
+        let shell = dir.join("amt_tree_sh");
+        std::fs::copy("/bin/sh", &shell).unwrap();
+        let script = format!("{child} 30 & {child} 30");
+        let args = ["-c".to_string(), script];
+        let mut mgr = manager(vec![service("svc", &shell.to_string_lossy(), &args)]);
+
+        mgr.start("svc").await.unwrap();
+        // Give the shell time to fork its children
+        let mut tree = Vec::new();
+        for _ in 0..50 {
+            tree = mgr.process_tree("svc").unwrap();
+            if tree.len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(tree.len(), 3, "shell and two children expected: {:?}", tree);
+
+        mgr.stop("svc").await.unwrap();
+        for p in &tree {
+            assert!(!alive(p.pid), "{} ({}) survived stop", p.name, p.pid);
+        }
     }
 }
@@ -0,0 +1,257 @@
+// src/scheduler.rs
+
+//! Cron schedules: services started and stopped at set local times
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::manager::ServiceManager;
+use crate::service::DesiredState;
+
+/// Cron expression: minute hour day-of-month month day-of-week
+/// Fields take `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/10`, `8-18/2`)
+/// Day of week is 0-7, Sunday is 0 or 7
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day fields starting with `*`: then both must match, otherwise either one does
+    any_day: bool,
+    any_weekday: bool,
+}
+impl FromStr for Cron {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Cron expression \"{}\" needs 5 fields: minute hour day month weekday", s));
+        };
+        let field = |text: &str, min: u32, max: u32| {
+            parse_field(text, min, max).map_err(|e| format!("Invalid cron expression \"{}\": {}", s, e))
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        // 7 is Sunday too
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+impl Cron {
+    pub fn matches(&self, time: &LocalTime) -> bool {
+        let has = |bits: u64, value: u32| bits & 1 << value != 0;
+        let day = has(self.days, time.day);
+        let weekday = has(self.weekdays, time.weekday);
+        let day_matches = if self.any_day || self.any_weekday { day && weekday } else { day || weekday };
+        has(self.minutes, time.minute) && has(self.hours, time.hour) && has(self.months, time.month) && day_matches
+    }
+}
+
+/// Bits of the values a cron field selects
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let number = |text: &str| text.parse::<u32>().map_err(|_| format!("\"{}\" is not a number", text));
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match number(step)? {
+                0 => return Err(format!("step of \"{}\" is 0", part)),
+                step => (range, step),
+            },
+            None => (part, 1),
+        };
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (number(low)?, number(high)?),
+            // "5/15": from 5 on
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if low < min || high > max || low > high {
+            return Err(format!("\"{}\" is outside {}-{}", part, min, max));
+        }
+        for value in (low..=high).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Wall clock minute in the local time zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub minute: u32,
+    pub hour: u32,
+    /// 1-31
+    pub day: u32,
+    /// 1-12
+    pub month: u32,
+    /// 0 = Sunday
+    pub weekday: u32,
+}
+impl LocalTime {
+    #[cfg(unix)]
+    pub fn now() -> Self {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&secs, &mut tm) };
+        Self {
+            minute: tm.tm_min as u32,
+            hour: tm.tm_hour as u32,
+            day: tm.tm_mday as u32,
+            month: tm.tm_mon as u32 + 1,
+            weekday: tm.tm_wday as u32,
+        }
+    }
+    #[cfg(windows)]
+    pub fn now() -> Self {
+        let mut time: windows_sys::Win32::Foundation::SYSTEMTIME = unsafe { std::mem::zeroed() };
+        unsafe { windows_sys::Win32::System::SystemInformation::GetLocalTime(&mut time) };
+        Self {
+            minute: time.wMinute as u32,
+            hour: time.wHour as u32,
+            day: time.wDay as u32,
+            month: time.wMonth as u32,
+            weekday: time.wDayOfWeek as u32,
+        }
+    }
+}
+
+/// What a schedule does to a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledAction {
+    Start,
+    Stop,
+}
+
+/// Finds the scheduled starts / stops of a minute
+/// Broken expressions are reported once, not every minute
+#[derive(Default)]
+pub struct Scheduler {
+    reported: HashSet<String>,
+}
+impl Scheduler {
+    /// Actions due at `time`, in service order
+    pub fn due(&mut self, mgr: &ServiceManager, time: &LocalTime) -> Vec<(String, ScheduledAction)> {
+        let mut due = Vec::new();
+        for id in &mgr.service_order {
+            let service = mgr.services.get(id).filter(|svc| svc.config.is_enabled());
+            let Some(schedule) = service.and_then(|svc| svc.config.schedule.as_ref()) else {
+                continue;
+            };
+            let entries = [(&schedule.start, ScheduledAction::Start), (&schedule.stop, ScheduledAction::Stop)];
+            for (expr, action) in entries {
+                let Some(expr) = expr else { continue };
+                match expr.parse::<Cron>() {
+                    Ok(cron) if cron.matches(time) => due.push((id.clone(), action)),
+                    Ok(_) => {}
+                    Err(e) => {
+                        if self.reported.insert(format!("{} {}", id, expr)) {
+                            warn!(service = %id, "{}, schedule ignored", e);
+                        }
+                    }
+                }
+            }
+        }
+        due
+    }
+}
+
+/// Run a scheduled action like the matching button in the UI:
+/// a scheduled stop is a manual stop (keep-alive leaves it down), a scheduled start lifts it
+/// The manager is only locked while looking at it, not while a dependency gets ready or the service exits
+pub async fn apply(manager: &Mutex<ServiceManager>, id: &str, action: ScheduledAction) {
+    let result = match action {
+        ScheduledAction::Start => {
+            info!(service = %id, event = "scheduled_start", "Starting service on schedule");
+            let lifted = manager.lock().await.set_desired_state(id, DesiredState::Running);
+            match lifted {
+                Ok(()) => ServiceManager::start_shared(manager, id).await.map(|_| ()),
+                Err(e) => Err(e),
+            }
+        }
+        ScheduledAction::Stop => {
+            info!(service = %id, event = "scheduled_stop", "Stopping service on schedule");
+            let held = manager.lock().await.set_desired_state(id, DesiredState::Stopped);
+            match held {
+                Ok(()) => ServiceManager::stop_shared(manager, id).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if let Err(e) = result {
+        error!(service = %id, error = %format!("{:#}", e), event = "schedule_failed", "Scheduled {:?} failed", action);
+    }
+}
+
+/// Whether a service config's schedule can be parsed
+pub fn check(schedule: &crate::service::Schedule) -> Result<(), String> {
+    for expr in [&schedule.start, &schedule.stop].into_iter().flatten() {
+        expr.parse::<Cron>()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minute: u32, hour: u32, day: u32, month: u32, weekday: u32) -> LocalTime {
+        LocalTime { minute, hour, day, month, weekday }
+    }
+
+    #[test]
+    fn cron_fields_select_the_right_minutes() {
+        let nightly: Cron = "0 2 * * *".parse().unwrap();
+        assert!(nightly.matches(&at(0, 2, 15, 6, 3)));
+        assert!(!nightly.matches(&at(1, 2, 15, 6, 3)));
+
+        let office: Cron = "*/15 8-18/2 * * 1-5".parse().unwrap();
+        assert!(office.matches(&at(45, 10, 1, 1, 5)));
+        assert!(!office.matches(&at(45, 11, 1, 1, 5)));
+        assert!(!office.matches(&at(45, 10, 1, 1, 6)));
+
+        // Sunday as 7, and day of month or weekday when both are set
+        let cron: Cron = "30 6 1,15 * 7".parse().unwrap();
+        assert!(cron.matches(&at(30, 6, 15, 3, 2)));
+        assert!(cron.matches(&at(30, 6, 9, 3, 0)));
+        assert!(!cron.matches(&at(30, 6, 9, 3, 1)));
+
+        for bad in ["0 2 * *", "60 * * * *", "* * 0 * *", "*/0 * * * *", "a * * * *", "5-1 * * * *"] {
+            assert!(bad.parse::<Cron>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn due_actions_follow_the_service_schedules() {
+        let services = serde_json::from_value(serde_json::json!([
+            {"id": "backup", "name": "backup", "exec": "backup", "args": [],
+             "schedule": {"start": "0 2 * * *", "stop": "0 6 * * *"}},
+            {"id": "broken", "name": "broken", "exec": "broken", "args": [], "schedule": {"start": "0 25 * * *"}},
+            {"id": "plain", "name": "plain", "exec": "plain", "args": []},
+        ]))
+        .unwrap();
+        let file = crate::service::ServicesFile { services, ..Default::default() };
+        let mgr = ServiceManager::from_config(file, None).unwrap();
+        let mut scheduler = Scheduler::default();
+        assert_eq!(scheduler.due(&mgr, &at(0, 2, 1, 1, 1)), vec![("backup".to_string(), ScheduledAction::Start)]);
+        assert_eq!(scheduler.due(&mgr, &at(0, 6, 1, 1, 1)), vec![("backup".to_string(), ScheduledAction::Stop)]);
+        assert!(scheduler.due(&mgr, &at(1, 6, 1, 1, 1)).is_empty());
+        assert_eq!(scheduler.reported.len(), 1);
+    }
+}
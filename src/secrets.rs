@@ -0,0 +1,170 @@
+// src/secrets.rs
+
+//! Secrets in the OS credential store, referenced from env values as `!secret name`
+//! Windows: Credential Manager (generic credentials "AppManager/<name>")
+//! Elsewhere: libsecret through `secret-tool` (attributes service=appmanager name=<name>)
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+
+use crate::manager::ServiceError;
+
+/// How a secret reference is kept in a loaded config
+pub const PREFIX: &str = "!secret ";
+
+/// Name of the secret an env value refers to
+pub fn reference(value: &str) -> Option<&str> {
+    value.strip_prefix(PREFIX).map(str::trim)
+}
+
+/// Names end up in credential targets and URLs, keep them plain
+pub fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+    if !valid {
+        return Err(ServiceError::Invalid(format!("Invalid secret name \"{}\", use letters, digits, _ - .", name)).into());
+    }
+    Ok(())
+}
+
+fn missing(name: &str) -> anyhow::Error {
+    ServiceError::Invalid(format!("Secret '{}' is not in the credential store", name)).into()
+}
+
+/// Replace `!secret name` values with the stored secrets
+pub fn resolve_env(env: &mut HashMap<String, String>) -> Result<()> {
+    for (key, value) in env.iter_mut() {
+        if let Some(name) = reference(value) {
+            *value = get(name).with_context(|| format!("Secret for env {}", key))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn target(name: &str) -> Vec<u16> {
+    format!("AppManager/{}", name).encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+#[cfg(windows)]
+pub fn get(name: &str) -> Result<String> {
+    use windows_sys::Win32::Security::Credentials::{CRED_TYPE_GENERIC, CREDENTIALW, CredFree, CredReadW};
+    check_name(name)?;
+    let target = target(name);
+    let mut credential: *mut CREDENTIALW = std::ptr::null_mut();
+    if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+        return Err(missing(name));
+    }
+    let value = unsafe {
+        let blob = std::slice::from_raw_parts((*credential).CredentialBlob, (*credential).CredentialBlobSize as usize);
+        let value = String::from_utf8(blob.to_vec());
+        CredFree(credential as *const _);
+        value
+    };
+    value.map_err(|_| anyhow!("Secret {} is not UTF-8", name))
+}
+
+#[cfg(windows)]
+pub fn set(name: &str, value: &str) -> Result<()> {
+    use windows_sys::Win32::Security::Credentials::{
+        CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC, CREDENTIALW, CredWriteW,
+    };
+    check_name(name)?;
+    let mut target = target(name);
+    let mut blob = value.as_bytes().to_vec();
+    let credential = CREDENTIALW {
+        Type: CRED_TYPE_GENERIC,
+        TargetName: target.as_mut_ptr(),
+        CredentialBlobSize: blob.len() as u32,
+        CredentialBlob: blob.as_mut_ptr(),
+        Persist: CRED_PERSIST_LOCAL_MACHINE,
+        ..Default::default()
+    };
+    if unsafe { CredWriteW(&credential, 0) } == 0 {
+        bail!("Failed to store secret {}: {}", name, std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn delete(name: &str) -> Result<()> {
+    use windows_sys::Win32::Security::Credentials::{CRED_TYPE_GENERIC, CredDeleteW};
+    check_name(name)?;
+    if unsafe { CredDeleteW(target(name).as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+        return Err(missing(name));
+    }
+    Ok(())
+}
+
+/// Run secret-tool with the attributes of a secret, `input` goes to its stdin
+#[cfg(not(windows))]
+fn secret_tool(action: &str, name: &str, input: Option<&str>) -> Result<std::process::Output> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    check_name(name)?;
+    let mut command = Command::new("secret-tool");
+    command.arg(action);
+    if action == "store" {
+        command.arg(format!("--label=AppManager {}", name));
+    }
+    let mut child = command
+        .args(["service", "appmanager", "name", name])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run secret-tool (libsecret-tools)")?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    if let Some(input) = input {
+        stdin.write_all(input.as_bytes())?;
+    }
+    drop(stdin);
+    Ok(child.wait_with_output()?)
+}
+
+#[cfg(not(windows))]
+pub fn get(name: &str) -> Result<String> {
+    let output = secret_tool("lookup", name, None)?;
+    // Exits with 1 and no output for an unknown secret
+    if !output.status.success() {
+        return Err(missing(name));
+    }
+    String::from_utf8(output.stdout).map_err(|_| anyhow!("Secret {} is not UTF-8", name))
+}
+
+#[cfg(not(windows))]
+pub fn set(name: &str, value: &str) -> Result<()> {
+    let output = secret_tool("store", name, Some(value))?;
+    if !output.status.success() {
+        bail!("Failed to store secret {}: {}", name, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn delete(name: &str) -> Result<()> {
+    // clear succeeds for unknown secrets too
+    get(name)?;
+    let output = secret_tool("clear", name, None)?;
+    if !output.status.success() {
+        bail!("Failed to delete secret {}: {}", name, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_and_names() {
+        assert_eq!(reference("!secret api_token"), Some("api_token"));
+        assert_eq!(reference("plain"), None);
+        assert!(check_name("api_token-2.prod").is_ok());
+        assert!(check_name("../x").is_err());
+        assert!(check_name("").is_err());
+        // Without a reference nothing is looked up
+        let mut env = HashMap::from([("A".to_string(), "1".to_string())]);
+        resolve_env(&mut env).unwrap();
+        assert_eq!(env["A"], "1");
+    }
+}
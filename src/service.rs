@@ -1,55 +1,1396 @@
 /// src/service.rs
+use anyhow::{Context, Result, anyhow, bail};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use serde_yaml::Value;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// Config layout version understood by this build
+pub const CONFIG_VERSION: u32 = 4;
 
 /// Service config files structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServiceConfig {
     pub id: String,
     pub name :String,
+    /// Left out when cmd is given
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub exec: String,
+    /// Whole command line instead of exec and args, e.g. "frpc.exe -c frpc.toml"
+    /// Split like a shell does: "double" and 'single' quotes group, \" is a quote inside "..."
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<String>,
+    /// service (default): kept running. task: runs to completion, never restarted by keep-alive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ServiceKind>,
+    /// Tried in order when exec can't be spawned (e.g. other install locations)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_fallbacks: Option<Vec<String>>,
     pub working_dir: Option<String>,
+    #[serde(default)]
     pub args: Vec<String>,
     pub env: Option<HashMap<String, String>>,
+    /// Dotenv file (KEY=VALUE lines, relative to working_dir) read at start, env overrides its keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>,
+    /// Start with an empty environment instead of AppManager's, only the config's env and env_file are set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_clear: Option<bool>,
+    /// Variables of AppManager's environment the service does not inherit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_remove: Option<Vec<String>>,
+    /// Top-level env of the config file, filled in by the manager (env_file and env override it)
+    #[serde(skip)]
+    pub inherited_env: HashMap<String, String>,
     pub windows: Option<WindowsOptions>,
+    /// Unix only: process group, session and stop signal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix: Option<UnixOptions>,
     pub autorun: Option<bool>,
+    /// Seconds to wait before autorun / keep-alive starts this service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_delay: Option<u64>,
+    /// Ids of services that must run before this one is started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depends_on: Option<Vec<String>>,
+    /// Keep-alive restarts: always, on-failure or never (default: always with autorun, else never)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartPolicy>,
+    /// Consecutive keep-alive restarts before giving up (default: unlimited)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
     pub url: Option<String>,
+    /// Seconds to wait after spawn before confirming the process is still alive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_grace_secs: Option<u64>,
+    /// Restart the running service when its exec file is replaced (a new build deployed)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_on_update: Option<bool>,
+    /// Stop the service when AppManager exits, overrides the top-level stop_on_exit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_on_exit: Option<bool>,
+    /// Files or directories (relative to working_dir) whose changes restart the running service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch: Option<Vec<String>>,
+    /// Fail to start when a ${VAR} / %VAR% reference can't be resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_env: Option<bool>,
+    /// Env keys whose values are masked in API responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_env: Option<Vec<String>>,
+    /// Run exec as a command line through `cmd /C` (Windows) or `sh -c` (Unix)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<bool>,
+    /// Program a script exec is run with, e.g. "python3 -u"; "" runs the script itself
+    /// Default by extension: .ps1 powershell -File, .bat/.cmd cmd /C, .py python, .sh sh
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interpreter: Option<String>,
+    /// On stop, also kill every process with the same exec name system-wide
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggressive_kill: Option<bool>,
+    /// How a process not spawned by AppManager is recognized: pid, name, path (default) or cmdline
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub process_match: Option<ProcessMatch>,
+    /// Take processes not spawned by AppManager for the service (default: true)
+    /// false: running only while its own process (or the one in pid_file) runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adopt: Option<bool>,
+    /// TCP port the service listens on, checked for conflicts before start
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// stopped: set by a manual stop, keep-alive and autorun leave it alone until it is started or resumed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired_state: Option<DesiredState>,
+    /// false keeps the service in the config without running it: it can't be started, autorun and keep-alive skip it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Pipe stdout/stderr to the manager so the output can be streamed live
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_output: Option<bool>,
+    /// Create working_dir before start when it doesn't exist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub create_working_dir: Option<bool>,
+    /// Free-form labels to group and filter services
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    /// Stack the service belongs to, started / stopped as one through /api/groups/{name}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Where stdin/stdout/stderr go (default: null)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdio: Option<StdioMode>,
+    /// Output file for stdio: file, relative to working_dir
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<String>,
+    /// Rotate log_file once it would grow past this size: bytes, or "512KB" / "10MB" / "1GB"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_max_size: Option<ByteSize>,
+    /// Rotate log_file every day or hour: daily, hourly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_rotate: Option<LogRotate>,
+    /// Rotated log files kept (default: 5)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_max_files: Option<usize>,
+    /// Gzip rotated log files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_compress: Option<bool>,
+    /// Keep-alive check interval for this service in seconds (default: global keep_alive)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check_interval_secs: Option<u64>,
+    /// Seconds stop waits for killed processes to exit before reporting a failure (default: 5)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kill_timeout_secs: Option<u64>,
+    /// Seconds to wait after asking the process to exit (SIGTERM / WM_CLOSE / CTRL_BREAK / stop_cmd)
+    /// before it is killed, 0 or unset kills right away (5 with windows.graceful or stop_cmd)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_timeout: Option<u64>,
+    /// Shell command that asks the service to exit (e.g. nginx -s quit), run on stop in place of
+    /// SIGTERM / WM_CLOSE. The process is killed if still running stop_timeout seconds later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_cmd: Option<String>,
+    /// Shell command run before the process is spawned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_start: Option<HookConfig>,
+    /// Shell command run once the process has started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_start: Option<HookConfig>,
+    /// Shell command run before the process is stopped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_stop: Option<HookConfig>,
+    /// Shell command run once the process is gone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_stop: Option<HookConfig>,
+    /// Seconds a hook may run before it is killed, unless the hook sets its own (default: 30)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_timeout_secs: Option<u64>,
+    /// Account to run the process as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<RunAs>,
+    /// File the service writes its pid to (relative to working_dir)
+    /// Set: the process is found through it only, never by its name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid_file: Option<String>,
+    /// Unix: nice value of the process, -20 (highest priority) to 19 (lowest), below 0 needs root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    /// Probe the service (HTTP, TCP or a command), keep-alive restarts it when the probes keep failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// When a started service counts as ready: dependents wait for it, the status says Starting until then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ready_when: Option<ReadyWhen>,
+    /// Resource limits, checked by keep-alive at the service's check interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+    /// Cron times (local) at which the service is started / stopped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+
+/// Resource limits of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Memory (RSS) of the whole process tree in MB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_mb: Option<u64>,
+    /// restart (default, follows the restart policy) or alert (logged only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<LimitAction>,
+}
+
+/// What keep-alive does about a service over its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    #[default]
+    Restart,
+    Alert,
+}
+
+/// Long running service or one-shot task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceKind {
+    #[default]
+    Service,
+    Task,
+}
+
+/// How a process not spawned by AppManager is recognized: a strategy (`match: name`),
+/// or one with the text for cmdline (`match: {cmdline_contains: "server.js"}`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ProcessMatch {
+    By(MatchStrategy),
+    Full {
+        /// Default: cmdline with cmdline_contains, otherwise path
+        #[serde(skip_serializing_if = "Option::is_none")]
+        by: Option<MatchStrategy>,
+        /// Text the command line must contain, e.g. "server.js"
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cmdline_contains: Option<String>,
+    },
+}
+impl ProcessMatch {
+    pub fn by(&self) -> Option<MatchStrategy> {
+        match self {
+            Self::By(by) => Some(*by),
+            Self::Full { by, .. } => *by,
+        }
+    }
+    pub fn cmdline_contains(&self) -> Option<&str> {
+        match self {
+            Self::By(_) => None,
+            Self::Full { cmdline_contains, .. } => cmdline_contains.as_deref(),
+        }
+    }
+}
+
+/// What identifies a service's process among the running ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchStrategy {
+    /// Only the process AppManager spawned (or recorded, or found in pid_file)
+    Pid,
+    /// Exe file name and arguments
+    Name,
+    /// Full executable path, file name and arguments
+    Path,
+    /// Command line containing cmdline_contains, for interpreter-hosted services
+    /// (`python app.py`, `node server.js`) whose name says nothing
+    Cmdline,
+}
+
+/// Start / stop times of a service as cron expressions, e.g. start: "0 2 * * *"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<String>,
+}
+
+/// Health check of a service, one of `http`, `tcp` or `cmd`
+/// Unhealthy after `failures` failed probes in a row
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// URL to GET, healthy on a 2xx/3xx answer, plain http only (e.g. http://127.0.0.1:6800/)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    /// Address that must accept TCP connections (e.g. 127.0.0.1:4000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<String>,
+    /// Shell command run in working_dir, healthy when it exits with `expect_exit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<String>,
+    /// Exit code of a healthy `cmd` (default: 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_exit: Option<i32>,
+    /// Seconds between probes (default: 10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// Seconds a probe may take (default: 3)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Failed probes in a row before the service counts as unhealthy (default: 3)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failures: Option<u32>,
+}
+
+/// Readiness of a started service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadyWhen {
+    /// Port on localhost that accepts TCP connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Regular expression an output line must match, the output is read even without capture_output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_matches: Option<String>,
+    /// Seconds to wait for it after the start (default: 30)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+/// Standard streams of a service
+/// null: discarded, inherit: AppManager's own console, file: appended to log_file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StdioMode {
+    #[default]
+    Null,
+    Inherit,
+    File,
+}
+
+/// Time-based log rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotate {
+    Hourly,
+    Daily,
+}
+
+/// A size in bytes, written as a number or with a KB / MB / GB suffix (1024-based)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().map_err(|_| format!("Invalid size \"{}\"", s))?;
+        let factor = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" => 1 << 10,
+            "M" | "MB" => 1 << 20,
+            "G" | "GB" => 1 << 30,
+            _ => return Err(format!("Invalid size unit in \"{}\", use B, KB, MB or GB", s)),
+        };
+        number.checked_mul(factor).map(ByteSize).ok_or_else(|| format!("Size \"{}\" is too large", s))
+    }
+}
+impl std::fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let n = self.0;
+        match n {
+            0 => write!(f, "0"),
+            _ if n.is_multiple_of(1 << 30) => write!(f, "{}GB", n >> 30),
+            _ if n.is_multiple_of(1 << 20) => write!(f, "{}MB", n >> 20),
+            _ if n.is_multiple_of(1 << 10) => write!(f, "{}KB", n >> 10),
+            _ => write!(f, "{}", n),
+        }
+    }
+}
+impl Serialize for ByteSize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 > 0 && self.0.is_multiple_of(1024) {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_u64(self.0)
+        }
+    }
+}
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(n) => Ok(ByteSize(n)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Lifecycle hook: a command line, or one with its own timeout and failure policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HookConfig {
+    Cmd(String),
+    Full {
+        cmd: String,
+        /// Seconds before the hook is killed (default: hook_timeout_secs)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout_secs: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_failure: Option<HookFailure>,
+    },
+}
+impl HookConfig {
+    pub fn cmd(&self) -> &str {
+        match self {
+            Self::Cmd(cmd) | Self::Full { cmd, .. } => cmd,
+        }
+    }
+    pub fn timeout_secs(&self) -> Option<u64> {
+        match self {
+            Self::Cmd(_) => None,
+            Self::Full { timeout_secs, .. } => *timeout_secs,
+        }
+    }
+    pub fn on_failure(&self) -> HookFailure {
+        match self {
+            Self::Cmd(_) => HookFailure::default(),
+            Self::Full { on_failure, .. } => on_failure.unwrap_or_default(),
+        }
+    }
+}
+
+/// What a failed or timed out hook does to the start / stop it belongs to
+/// continue: logged only. abort: the start / stop fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    #[default]
+    Continue,
+    Abort,
+}
+
+/// When keep-alive restarts a stopped service
+/// on-failure: only after a non-zero exit code or a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// What the user last asked for, keep-alive only brings back a service that should be running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DesiredState {
+    #[default]
+    Running,
+    Stopped,
+}
+
+/// Placeholder returned instead of secret env values
+pub const SECRET_MASK: &str = "***";
+
+impl ServiceConfig {
+    pub fn is_secret_env(&self, key: &str) -> bool {
+        self.secret_env
+            .as_ref()
+            .is_some_and(|keys| keys.iter().any(|k| k == key))
+    }
+    /// Whether a running process has to be restarted to pick up the other config
+    /// Cosmetic fields (name, url, tags, autorun, ...) don't count
+    pub fn process_differs(&self, other: &ServiceConfig) -> bool {
+        self.exec != other.exec
+            || self.exec_fallbacks != other.exec_fallbacks
+            || self.args != other.args
+            || self.env != other.env
+            || self.env_file != other.env_file
+            || self.env_clear != other.env_clear
+            || self.env_remove != other.env_remove
+            || self.inherited_env != other.inherited_env
+            || self.working_dir != other.working_dir
+            || self.windows != other.windows
+            || self.unix != other.unix
+            || self.shell != other.shell
+            || self.interpreter != other.interpreter
+            || self.strict_env != other.strict_env
+            || self.stdio != other.stdio
+            || self.log_file != other.log_file
+            || self.rotates_log() != other.rotates_log()
+            || self.capture_output != other.capture_output
+            || self.run_as != other.run_as
+            || self.nice != other.nice
+    }
+    /// Fill exec and args from cmd, which replaces both
+    pub fn apply_cmd(&mut self) -> Result<(), String> {
+        let Some(line) = &self.cmd else { return Ok(()) };
+        let mut parts = split_command_line(line)?.into_iter();
+        self.exec = parts.next().ok_or("cmd is empty")?;
+        self.args = parts.collect();
+        Ok(())
+    }
+    /// Config as written to the file: exec and args stay implied by cmd
+    pub fn for_file(&self) -> ServiceConfig {
+        match self.cmd {
+            Some(_) => ServiceConfig { exec: String::new(), args: Vec::new(), ..self.clone() },
+            None => self.clone(),
+        }
+    }
+    /// Runs to completion instead of staying up
+    pub fn is_task(&self) -> bool {
+        self.kind == Some(ServiceKind::Task)
+    }
+    /// log_file is rotated by AppManager (size or time limit set)
+    pub fn rotates_log(&self) -> bool {
+        self.log_max_size.is_some() || self.log_rotate.is_some()
+    }
+    /// Configs to try at start: as written, then with each fallback exec
+    pub fn exec_candidates(&self) -> Vec<ServiceConfig> {
+        let mut candidates = vec![self.clone()];
+        for exec in self.exec_fallbacks.iter().flatten() {
+            candidates.push(ServiceConfig { exec: exec.clone(), ..self.clone() });
+        }
+        candidates
+    }
+    /// Restart policy with the autorun fallback applied
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.restart.unwrap_or(if self.autorun.unwrap_or(false) {
+            RestartPolicy::Always
+        } else {
+            RestartPolicy::Never
+        })
+    }
+    /// Tags compare case-insensitively
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().flatten().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+    /// Stopped by hand, not to be started by autorun or keep-alive
+    pub fn is_held_stopped(&self) -> bool {
+        self.desired_state == Some(DesiredState::Stopped)
+    }
+    /// Groups compare case-insensitively, like tags
+    pub fn in_group(&self, group: &str) -> bool {
+        self.group.as_deref().is_some_and(|g| g.eq_ignore_ascii_case(group))
+    }
+    /// Env values with ${VAR} / %VAR% expanded and {KEY} references to the other keys filled in
+    /// The config file's env comes first, then env_file, env overrides both
+    pub fn resolved_env(&self) -> Result<HashMap<String, String>> {
+        let strict = self.strict_env.unwrap_or(false);
+        let mut merged = self.inherited_env.clone();
+        if let Some(file) = &self.env_file {
+            merged.extend(self.read_env_file(file)?);
+        }
+        merged.extend(self.env.iter().flatten().map(|(k, v)| (k.clone(), v.clone())));
+        let expanded = merged
+            .into_iter()
+            .map(|(k, v)| Ok((k, expand_system_env(&v, strict)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(expanded.iter().map(|(k, v)| (k.clone(), interpolate(v, &expanded))).collect())
+    }
+    /// Pairs of env_file, a relative path is taken from working_dir
+    fn read_env_file(&self, file: &str) -> Result<HashMap<String, String>> {
+        let strict = self.strict_env.unwrap_or(false);
+        let file = expand_system_env(unquote(file), strict)?;
+        let path = match &self.working_dir {
+            Some(dir) => Path::new(unquote(&expand_system_env(dir, strict)?)).join(&file),
+            None => PathBuf::from(&file),
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read env_file {}", path.display()))?;
+        parse_env_file(&text).with_context(|| format!("Invalid env_file {}", path.display()))
+    }
+    /// Web link with variables expanded like exec (unresolved ones and secrets left as-is)
+    pub fn resolved_url(&self) -> Option<String> {
+        let url = self.url.as_deref()?;
+        // Secret values stay out of a link shown to every API client
+        let mut env = self.resolved_env().unwrap_or_default();
+        env.retain(|k, _| !self.is_secret_env(k));
+        Some(interpolate(&expand_system_env(url, false).unwrap_or_else(|_| url.to_string()), &env))
+    }
+    /// Env map with secret values replaced by the mask
+    pub fn masked_env(&self) -> Option<HashMap<String, String>> {
+        self.env.as_ref().map(|env| {
+            env.iter()
+                .map(|(k, v)| {
+                    let v = if self.is_secret_env(k) { SECRET_MASK.to_string() } else { v.clone() };
+                    (k.clone(), v)
+                })
+                .collect()
+        })
+    }
+    /// Keep the stored secret when a client sends back the mask unchanged
+    pub fn restore_masked_env(&mut self, previous: &ServiceConfig) {
+        let (Some(env), Some(prev_env)) = (self.env.as_mut(), previous.env.as_ref()) else {
+            return;
+        };
+        for (k, v) in env.iter_mut() {
+            if v == SECRET_MASK
+                && previous.is_secret_env(k)
+                && let Some(real) = prev_env.get(k)
+            {
+                *v = real.clone();
+            }
+        }
+    }
 }
 
 /// Windows start options
 /// 0x08000000: hide
 /// 0x00000010: show
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct WindowsOptions {
     pub creation_flags: Option<u32>,
+    /// Send CTRL_BREAK on stop and wait before killing (console apps)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graceful: Option<bool>,
+    /// Priority class, replaces any class set in creation_flags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<WindowsPriority>,
+    /// CPUs the process may run on, bit 0 = CPU 0 (e.g. 3 = CPU 0 and 1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub affinity_mask: Option<u64>,
+    /// Run as Administrator: asks for consent (UAC) on every start unless AppManager is elevated itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevated: Option<bool>,
+}
+
+/// Windows process priority class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowsPriority {
+    Idle,
+    BelowNormal,
+    Normal,
+    High,
+}
+impl WindowsPriority {
+    /// Creation flag of the class: IDLE / BELOW_NORMAL / NORMAL / HIGH_PRIORITY_CLASS
+    pub fn creation_flag(self) -> u32 {
+        match self {
+            WindowsPriority::Idle => 0x00000040,
+            WindowsPriority::BelowNormal => 0x00004000,
+            WindowsPriority::Normal => 0x00000020,
+            WindowsPriority::High => 0x00000080,
+        }
+    }
+}
+
+/// Unix start and stop options
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct UnixOptions {
+    /// Own process group (default true): stop signals and kills the whole group,
+    /// and Ctrl+C in AppManager's terminal doesn't reach the service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_group: Option<bool>,
+    /// New session (setsid, implies its own group): no controlling terminal at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub setsid: Option<bool>,
+    /// Signal asking the service to exit, SIGKILL follows after stop_timeout (default 5s when set)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<UnixSignal>,
+}
+
+/// Signal sent on stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnixSignal {
+    #[default]
+    Term,
+    Int,
+    Hup,
+    Quit,
+    Usr1,
+    Usr2,
+}
+#[cfg(unix)]
+impl UnixSignal {
+    pub fn number(self) -> i32 {
+        match self {
+            UnixSignal::Term => libc::SIGTERM,
+            UnixSignal::Int => libc::SIGINT,
+            UnixSignal::Hup => libc::SIGHUP,
+            UnixSignal::Quit => libc::SIGQUIT,
+            UnixSignal::Usr1 => libc::SIGUSR1,
+            UnixSignal::Usr2 => libc::SIGUSR2,
+        }
+    }
 }
 
 /// Full config structure
 /// Includes keep_alive interval and listen address
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ServicesFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
     pub listen: Option<String>,
     pub keep_alive: Option<u64>,
+    /// Stop all services when AppManager exits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_on_exit: Option<bool>,
+    /// Older name of cors.origins, moved there on load
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors_origins: Option<Vec<String>>,
+    /// Web pages of other origins allowed to use the API, none when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsConfig>,
+    /// Access control of the API, open when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+    /// Serve the web UI and API over HTTPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Reload this file when it is edited outside AppManager
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch_config: Option<bool>,
+    /// How far CPU and memory of a running service move before GET /api/services/changes reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_threshold: Option<ChangeThreshold>,
+    /// Environment variables of every service, a service's own env overrides them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+    /// Service configs with {{param}} placeholders, see instantiate_template
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub templates: Option<HashMap<String, Value>>,
     pub services: Vec<ServiceConfig>,
 }
 
-/// Combine the args of command
-pub fn build_args(args: &[String], env: &Option<HashMap<String, String>>) -> Vec<String> {
-    args.iter().map(|arg| {
-        let mut s = arg.clone();
-        if let Some(envkv) = env {
-            for (k, v) in envkv {
-                s = s.replace(&format!("{{{}}}", k), v);
+/// API access control
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct AuthConfig {
+    /// Required as `Authorization: Bearer <token>` on every /api/ request, ${VAR} / %VAR% are expanded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Web UI logins: user name to password hash (appmanager --hash-password)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<HashMap<String, String>>,
+    /// How long a login lasts, default 12
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_hours: Option<u64>,
+}
+impl AuthConfig {
+    /// The token to check, None when unset or empty
+    pub fn resolved_token(&self) -> Result<Option<String>> {
+        let Some(token) = &self.token else { return Ok(None) };
+        let token = expand_system_env(token, true).context("auth.token")?;
+        Ok(Some(token).filter(|t| !t.is_empty()))
+    }
+}
+
+/// Cross-origin access to the API
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct CorsConfig {
+    /// Allowed origins like "http://192.168.1.10:8080", "*" for any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origins: Option<Vec<String>>,
+    /// Allowed methods, default GET, POST, PUT, DELETE
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub methods: Option<Vec<String>>,
+}
+impl CorsConfig {
+    /// Allowed methods, upper case
+    pub fn allowed_methods(&self) -> Vec<String> {
+        match &self.methods {
+            Some(methods) => methods.iter().map(|m| m.to_uppercase()).collect(),
+            None => ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+        }
+    }
+    /// Whether a page of this origin may make this request
+    pub fn allows(&self, origin: &str, method: &str) -> bool {
+        self.origins.iter().flatten().any(|allowed| allowed == "*" || allowed == origin)
+            && self.allowed_methods().iter().any(|allowed| allowed == method)
+    }
+}
+
+/// Resource usage moves smaller than this are no change for delta clients
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct ChangeThreshold {
+    /// Percentage points of one core (default: 5)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f32>,
+    /// Resident memory, bytes or with a KB / MB / GB suffix (default: 10MB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<ByteSize>,
+}
+
+/// Account a service runs as
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct RunAs {
+    /// Unix: name or uid, needs AppManager to run as root. Windows: user, DOMAIN\user or user@domain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Unix: group (name or gid) to run the process as (default: the user's primary group)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Windows: environment variable of AppManager holding the password of user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+}
+
+/// HTTPS certificate of the web server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct TlsConfig {
+    /// PEM certificate file, with the chain after it
+    pub cert: String,
+    /// PEM private key file
+    pub key: String,
+    /// Create a self-signed certificate at cert and key when neither file exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_signed: Option<bool>,
+}
+
+/// Read a config file, upgrade older layouts and warn about unknown keys
+/// Returns the parsed file and whether it was migrated (caller should rewrite it)
+pub fn load_services_file(path: &str) -> Result<(ServicesFile, bool)> {
+    let content = std::fs::read_to_string(path)
+        .context("Failed to read config file")?;
+    parse_services_file(&content)
+}
+
+/// Parse config file content, see load_services_file
+pub fn parse_services_file(content: &str) -> Result<(ServicesFile, bool)> {
+    let mut doc: Value = serde_yaml::from_str(content)
+        .context("Failed to parse YAML")?;
+    // Upgrade older config layouts before deserializing
+    let migrated = migrate_config(&mut doc)?;
+    untag_secrets(&mut doc);
+    // Collect unknown keys (e.g. typos like "autorn") instead of silently dropping them
+    let mut unknown_keys = Vec::new();
+    let mut file: ServicesFile =
+        serde_ignored::deserialize(doc, |path| unknown_keys.push(path.to_string()))
+            .context("Failed to parse YAML")?;
+    if let Some(origins) = file.cors_origins.take() {
+        if file.cors.is_some() {
+            bail!("cors_origins is the older name of cors.origins, set only cors");
+        }
+        file.cors = Some(CorsConfig { origins: Some(origins), methods: None });
+    }
+    for svc in &mut file.services {
+        if svc.cmd.is_some() && (!svc.exec.is_empty() || !svc.args.is_empty()) {
+            bail!("Service '{}': cmd replaces exec and args, set only one of them", svc.id);
+        }
+        if svc.cmd.is_none() && svc.exec.is_empty() {
+            bail!("Service '{}': exec or cmd is required", svc.id);
+        }
+        svc.apply_cmd().map_err(|e| anyhow!("Service '{}': {}", svc.id, e))?;
+    }
+    for key in &unknown_keys {
+        warn!(
+            key = %describe_config_key(&file, key),
+            "Unknown config key ignored. Check for typos.",
+        );
+    }
+    Ok((file, migrated))
+}
+
+/// Make an unknown key path readable: "services.0.autorn" -> "services[aria2].autorn"
+fn describe_config_key(file: &ServicesFile, path: &str) -> String {
+    // Drop serde_ignored's Option markers ("windows.?.flags")
+    let path = path.replace(".?", "");
+    let mut parts = path.splitn(3, '.');
+    if let (Some("services"), Some(index)) = (parts.next(), parts.next())
+        && let Some(svc) = index.parse::<usize>().ok().and_then(|i| file.services.get(i))
+    {
+        return match parts.next() {
+            Some(rest) => format!("services[{}].{}", svc.id, rest),
+            None => format!("services[{}]", svc.id),
+        };
+    }
+    path
+}
+
+/// Drop-in config files: every *.yaml / *.yml in "<config>.d" (services.yaml -> services.d)
+/// Sorted by file name, later files override earlier ones by service id
+pub fn drop_in_files(config_path: &str) -> Vec<PathBuf> {
+    let dir = Path::new(config_path).with_extension("d");
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.extension().is_some_and(|ext| ext == "yaml" || ext == "yml")
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Upgrade a raw config document to the current layout
+/// Returns true if anything was changed (caller should rewrite the file)
+/// v0 (no version): services may be a bare top-level list
+/// v1: a manual stop is `disabled: true`, now `desired_state: stopped`
+/// v2: run_as_user / run_as_group / run_as_password_env, now user / group / password_env of a run_as block
+/// v3: `match_exe_path: false` is the name strategy, now `match: name`
+pub fn migrate_config(doc: &mut Value) -> Result<bool> {
+    // v0 -> v1: a bare list of services at top level, it has no version
+    if doc.is_sequence() {
+        let services = std::mem::take(doc);
+        let mut map = serde_yaml::Mapping::new();
+        map.insert("services".into(), services);
+        *doc = Value::Mapping(map);
+    }
+    let Some(map) = doc.as_mapping_mut() else {
+        bail!("Config root must be a mapping");
+    };
+    let version = match map.get("version") {
+        None | Some(Value::Null) => 0,
+        Some(v) => match v.as_u64() {
+            Some(n) => u32::try_from(n).map_err(|_| anyhow!("Config version {} is out of range", n))?,
+            None => bail!("Config 'version' must be a non-negative integer"),
+        },
+    };
+    if version > CONFIG_VERSION {
+        bail!(
+            "Config version {} is newer than this AppManager supports (max {}). Please upgrade AppManager.",
+            version, CONFIG_VERSION
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(false);
+    }
+    // v1 -> v2
+    if version < 2
+        && let Some(Value::Sequence(services)) = map.get_mut("services")
+    {
+        for svc in services.iter_mut().filter_map(|s| s.as_mapping_mut()) {
+            if svc.remove("disabled").is_some_and(|d| d.as_bool() == Some(true)) {
+                svc.insert("desired_state".into(), "stopped".into());
             }
         }
-        s
-    }).collect()
+    }
+    // v2 -> v3
+    if version < 3 {
+        for svc in service_configs(map) {
+            let mut run_as = serde_yaml::Mapping::new();
+            for (old, new) in [("run_as_user", "user"), ("run_as_group", "group"), ("run_as_password_env", "password_env")] {
+                if let Some(value) = svc.remove(old) {
+                    run_as.insert(new.into(), value);
+                }
+            }
+            if !run_as.is_empty() {
+                svc.insert("run_as".into(), Value::Mapping(run_as));
+            }
+        }
+    }
+    // v3 -> v4, an explicit strategy or cmdline_contains already overrode match_exe_path
+    if version < 4 {
+        for svc in service_configs(map) {
+            if svc.remove("match_exe_path").and_then(|m| m.as_bool()) != Some(false) {
+                continue;
+            }
+            match svc.get_mut("match") {
+                None => {
+                    svc.insert("match".into(), "name".into());
+                }
+                Some(Value::Mapping(m)) if !m.contains_key("by") && !m.contains_key("cmdline_contains") => {
+                    m.insert("by".into(), "name".into());
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    map.insert("version".into(), CONFIG_VERSION.into());
+    Ok(true)
+}
+
+/// Services and templates of a config document, templates are service configs too
+fn service_configs(map: &mut serde_yaml::Mapping) -> impl Iterator<Item = &mut serde_yaml::Mapping> {
+    map.iter_mut()
+        .flat_map(|(key, value)| -> Box<dyn Iterator<Item = &mut Value>> {
+            match (key.as_str(), value) {
+                (Some("services"), Value::Sequence(services)) => Box::new(services.iter_mut()),
+                (Some("templates"), Value::Mapping(templates)) => Box::new(templates.values_mut()),
+                _ => Box::new(std::iter::empty()),
+            }
+        })
+        .filter_map(Value::as_mapping_mut)
+}
+
+/// Expand OS environment variables: ${VAR} and %VAR%
+/// Unresolved references are left untouched, or rejected when strict
+pub fn expand_system_env(input: &str, strict: bool) -> Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find(['$', '%']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        // ${VAR}, or %VAR% with names limited to identifier characters
+        let reference = if let Some(body) = tail.strip_prefix("${") {
+            body.find('}').map(|end| (&body[..end], end + 3))
+        } else if let Some(body) = tail.strip_prefix('%') {
+            body.find('%')
+                .map(|end| &body[..end])
+                .filter(|name| {
+                    !name.is_empty()
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_()".contains(c))
+                })
+                .map(|name| (name, name.len() + 2))
+        } else {
+            None
+        };
+        match reference {
+            Some((name, len)) => {
+                match std::env::var(name) {
+                    Ok(value) => out.push_str(&value),
+                    Err(_) if strict => bail!("Environment variable '{}' is not set", name),
+                    Err(_) => out.push_str(&tail[..len]),
+                }
+                rest = &tail[len..];
+            }
+            None => {
+                out.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Dotenv format: KEY=VALUE lines, optionally `export KEY=VALUE`
+/// Blank lines and # comments are skipped, values may be quoted ("a b" or 'a b')
+pub fn parse_env_file(text: &str) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {}: expected KEY=VALUE", n + 1);
+        };
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            bail!("Line {}: invalid key \"{}\"", n + 1, key);
+        }
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) if value.len() > 1 && value.ends_with(quote) => &value[1..value.len() - 1],
+            // Unquoted: a # after whitespace starts a comment
+            _ => value.split(" #").next().unwrap_or_default().trim_end(),
+        };
+        env.insert(key.to_string(), value.to_string());
+    }
+    Ok(env)
+}
+
+/// Turn `!secret name` env values (YAML tags) into "!secret name" strings, looked up at start
+/// Covers a config file, a list of services and a single service
+pub fn untag_secrets(doc: &mut Value) {
+    fn untag(env: Option<&mut Value>) {
+        for value in env.and_then(Value::as_mapping_mut).into_iter().flat_map(|env| env.values_mut()) {
+            let name = match value {
+                Value::Tagged(tagged) if tagged.tag == "secret" => tagged.value.as_str().map(str::to_string),
+                _ => None,
+            };
+            if let Some(name) = name {
+                *value = Value::String(format!("{}{}", crate::secrets::PREFIX, name));
+            }
+        }
+    }
+    untag(doc.get_mut("env"));
+    let templates = doc.get_mut("templates").and_then(Value::as_mapping_mut);
+    for template in templates.into_iter().flat_map(|templates| templates.values_mut()) {
+        untag(template.get_mut("env"));
+    }
+    let services = match doc {
+        Value::Sequence(services) => Some(services),
+        doc => doc.get_mut("services").and_then(Value::as_sequence_mut),
+    };
+    for svc in services.into_iter().flatten() {
+        untag(svc.get_mut("env"));
+    }
+}
+
+/// Build a service from a template: {{param}} placeholders in its strings take the parameter values
+/// `{{id}}` is the new service's id. Only string values are filled, numbers and switches stay as written
+pub fn instantiate_template(template: &Value, id: &str, params: &HashMap<String, String>) -> Result<ServiceConfig> {
+    fn fill(value: &mut Value, params: &HashMap<String, String>, missing: &mut Vec<String>) {
+        match value {
+            Value::String(text) => {
+                let mut rest = text.as_str();
+                let mut filled = String::new();
+                while let Some(start) = rest.find("{{") {
+                    let Some(len) = rest[start..].find("}}") else { break };
+                    let key = rest[start + 2..start + len].trim();
+                    filled.push_str(&rest[..start]);
+                    match params.get(key) {
+                        Some(param) => filled.push_str(param),
+                        None => {
+                            missing.push(key.to_string());
+                            filled.push_str(&rest[start..start + len + 2]);
+                        }
+                    }
+                    rest = &rest[start + len + 2..];
+                }
+                filled.push_str(rest);
+                *text = filled;
+            }
+            Value::Sequence(items) => items.iter_mut().for_each(|item| fill(item, params, missing)),
+            Value::Mapping(map) => map.values_mut().for_each(|item| fill(item, params, missing)),
+            Value::Tagged(tagged) => fill(&mut tagged.value, params, missing),
+            _ => {}
+        }
+    }
+    let mut params = params.clone();
+    params.insert("id".into(), id.to_string());
+    let mut doc = template.clone();
+    let mut missing = Vec::new();
+    fill(&mut doc, &params, &mut missing);
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        bail!("Missing template parameter(s): {}", missing.join(", "));
+    }
+    let Value::Mapping(map) = &mut doc else {
+        bail!("A template must be a service mapping");
+    };
+    map.insert("id".into(), id.into());
+    if !map.contains_key("name") {
+        map.insert("name".into(), id.into());
+    }
+    serde_yaml::from_value(doc).context("Template doesn't make a valid service")
+}
+
+/// Replace {KEY} placeholders with the values of the service's env map
+pub fn interpolate(value: &str, env: &HashMap<String, String>) -> String {
+    let mut s = value.to_string();
+    for (k, v) in env {
+        s = s.replace(&format!("{{{}}}", k), v);
+    }
+    s
 }
 
 /// Get the file name of exec
+/// Both separators are accepted on every platform, like in resolve_exec_path
 pub fn exec_file_name(exec_path: &str) -> &str {
-    Path::new(exec_path)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or(exec_path)
-}
\ No newline at end of file
+    let path = unquote(exec_path).trim_end_matches(['/', '\\']);
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// Split a command line into program and arguments
+/// Whitespace separates, quotes group ("C:\Program Files\app.exe", 'a b'), backslashes are
+/// literal (Windows paths) except for \" inside double quotes
+pub fn split_command_line(line: &str) -> Result<Vec<String>, String> {
+    let mut parts = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => parts.extend(current.take()),
+            '"' | '\'' => {
+                let part = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') if c == '"' && chars.peek() == Some(&'"') => part.push(chars.next().unwrap()),
+                        Some(inner) => part.push(inner),
+                        None => return Err(format!("Unclosed {} in \"{}\"", c, line)),
+                    }
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    parts.extend(current);
+    Ok(parts)
+}
+
+/// Drop one pair of surrounding quotes ("C:\Program Files\app.exe" as copied from Explorer)
+pub fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_file_name_handles_spaces_and_quotes() {
+        assert_eq!(exec_file_name(r"C:\Program Files\My App\app.exe"), "app.exe");
+        assert_eq!(exec_file_name(r#""C:\Program Files\My App\app.exe""#), "app.exe");
+        assert_eq!(exec_file_name("'/opt/my app/run server'"), "run server");
+        assert_eq!(exec_file_name("My App/app.exe"), "app.exe");
+        assert_eq!(exec_file_name("app"), "app");
+    }
+
+    #[test]
+    fn only_process_fields_require_a_restart() {
+        let config: ServiceConfig =
+            serde_yaml::from_str("{ id: a, name: A, exec: app, args: ['-x'] }").unwrap();
+        let mut cosmetic = config.clone();
+        cosmetic.name = "Renamed".into();
+        cosmetic.url = Some("http://localhost".into());
+        cosmetic.tags = Some(vec!["web".into()]);
+        cosmetic.autorun = Some(true);
+        assert!(!config.process_differs(&cosmetic));
+
+        let mut changed = config.clone();
+        changed.args.push("-y".into());
+        assert!(config.process_differs(&changed));
+    }
+
+    #[test]
+    fn v0_bare_lists_become_a_services_mapping() {
+        let mut doc: Value = serde_yaml::from_str("- { id: a, name: A, exec: app }\n").unwrap();
+        assert!(migrate_config(&mut doc).unwrap());
+        assert_eq!(doc["version"].as_u64(), Some(CONFIG_VERSION.into()));
+        assert_eq!(doc["services"][0]["id"].as_str(), Some("a"));
+        // Nothing left to do
+        assert!(!migrate_config(&mut doc).unwrap());
+    }
+
+    #[test]
+    fn versions_out_of_range_are_rejected() {
+        let mut doc: Value = serde_yaml::from_str("version: 4294967297\nservices: []\n").unwrap();
+        assert!(migrate_config(&mut doc).unwrap_err().to_string().contains("out of range"));
+        let mut doc: Value = serde_yaml::from_str(&format!("version: {}\nservices: []\n", CONFIG_VERSION + 1)).unwrap();
+        assert!(migrate_config(&mut doc).unwrap_err().to_string().contains("newer"));
+    }
+
+    #[test]
+    fn v1_manual_stops_become_the_desired_state() {
+        let yaml = "version: 1\nservices:\n  - { id: a, name: A, exec: app, args: [], disabled: true }\n  - { id: b, name: B, exec: app, args: [], disabled: false }\n";
+        let (file, migrated) = parse_services_file(yaml).unwrap();
+        assert!(migrated);
+        assert_eq!(file.version, Some(CONFIG_VERSION));
+        assert!(file.services[0].is_held_stopped());
+        assert_eq!(file.services[1].desired_state, None);
+        assert!(file.services.iter().all(|svc| svc.is_enabled()));
+    }
+
+    #[test]
+    fn v2_run_as_keys_move_into_a_block() {
+        let yaml = "version: 2\ntemplates: { t: { id: '{{id}}', name: T, exec: app, run_as_user: nobody } }\nservices:\n  - { id: a, name: A, exec: app, run_as_user: svc, run_as_group: staff, run_as_password_env: PW }\n  - { id: b, name: B, exec: app }\n";
+        let (file, migrated) = parse_services_file(yaml).unwrap();
+        assert!(migrated);
+        let expected = RunAs { user: Some("svc".into()), group: Some("staff".into()), password_env: Some("PW".into()) };
+        assert_eq!(file.services[0].run_as, Some(expected));
+        assert_eq!(file.services[1].run_as, None);
+        assert_eq!(file.templates.unwrap()["t"]["run_as"]["user"].as_str(), Some("nobody"));
+    }
+
+    #[test]
+    fn v3_match_exe_path_becomes_the_name_strategy() {
+        let yaml = "version: 3\nservices:\n  - { id: a, name: A, exec: app, match_exe_path: false }\n  - { id: b, name: B, exec: app, match_exe_path: true }\n  - { id: c, name: C, exec: app, match_exe_path: false, match: { cmdline_contains: app.py } }\n  - { id: d, name: D, exec: app, match_exe_path: false, match: pid }\n";
+        let (file, migrated) = parse_services_file(yaml).unwrap();
+        assert!(migrated);
+        let by = |i: usize| file.services[i].process_match.as_ref().and_then(ProcessMatch::by);
+        assert_eq!(by(0), Some(MatchStrategy::Name));
+        assert_eq!(file.services[1].process_match, None);
+        assert_eq!(by(2), None);
+        assert_eq!(file.services[2].process_match.as_ref().and_then(ProcessMatch::cmdline_contains), Some("app.py"));
+        assert_eq!(by(3), Some(MatchStrategy::Pid));
+    }
+
+    #[test]
+    fn byte_sizes_keep_their_unit() {
+        let config: ServiceConfig =
+            serde_yaml::from_str("{ id: a, name: A, exec: app, args: [], log_max_size: 10mb }").unwrap();
+        assert_eq!(config.log_max_size, Some(ByteSize(10 << 20)));
+        assert!(serde_yaml::to_string(&config).unwrap().contains("log_max_size: 10MB"));
+        assert_eq!("1500".parse::<ByteSize>(), Ok(ByteSize(1500)));
+        assert_eq!(ByteSize(1500).to_string(), "1500");
+        assert!("10 parsecs".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn unquote_strips_one_matching_pair() {
+        assert_eq!(unquote(r#" "C:\Program Files\My App" "#), r"C:\Program Files\My App");
+        assert_eq!(unquote("'a b'"), "a b");
+        assert_eq!(unquote(r#""a b'"#), r#""a b'"#);
+        assert_eq!(unquote(r#"""#), r#"""#);
+    }
+
+    #[test]
+    fn env_values_and_url_are_expanded() {
+        let config: ServiceConfig = serde_yaml::from_str(
+            "{ id: a, name: A, exec: app, args: [], url: 'http://localhost:{PORT}/?t={TOKEN}',
+               env: { PORT: '8080', DATA: '${PATH}/{PORT}', TOKEN: abc }, secret_env: [TOKEN] }",
+        )
+        .unwrap();
+        let env = config.resolved_env().unwrap();
+        assert_eq!(env["DATA"], format!("{}/8080", std::env::var("PATH").unwrap()));
+        assert_eq!(config.resolved_url().as_deref(), Some("http://localhost:8080/?t={TOKEN}"));
+    }
+
+    #[test]
+    fn env_file_lines_are_parsed_and_overridden_by_env() {
+        let text = "# comment\n\nexport A=1\nB = \"two words\" \nC='#not a comment'\nD=x # comment\n";
+        let env = parse_env_file(text).unwrap();
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["B"], "two words");
+        assert_eq!(env["C"], "#not a comment");
+        assert_eq!(env["D"], "x");
+        assert!(parse_env_file("A=1\nnot a pair").unwrap_err().to_string().contains("Line 2"));
+
+        let dir = crate::test_support::TestDir::new("env");
+        std::fs::write(dir.join(".env"), "TOKEN=from-file\nPORT=1\n").unwrap();
+        let mut config: ServiceConfig =
+            serde_yaml::from_str("{ id: a, name: A, exec: app, args: [], env_file: .env, env: { PORT: '2' } }").unwrap();
+        config.working_dir = Some(dir.to_string_lossy().into());
+        let env = config.resolved_env().unwrap();
+        assert_eq!((env["TOKEN"].as_str(), env["PORT"].as_str()), ("from-file", "2"));
+        config.env_file = Some("missing.env".into());
+        assert!(config.resolved_env().is_err());
+    }
+
+    #[test]
+    fn secret_tags_become_references() {
+        let yaml = "version: 1\nenv: { SHARED: !secret shared }\nservices:\n  - { id: a, name: A, exec: app, args: [], env: { TOKEN: !secret api_token } }\n";
+        let (file, _) = parse_services_file(yaml).unwrap();
+        assert_eq!(file.env.as_ref().unwrap()["SHARED"], "!secret shared");
+        assert_eq!(file.services[0].env.as_ref().unwrap()["TOKEN"], "!secret api_token");
+        // Written back as a plain string, read the same way
+        let (again, _) = parse_services_file(&serde_yaml::to_string(&file).unwrap()).unwrap();
+        assert_eq!(again.services[0].env, file.services[0].env);
+    }
+
+    #[test]
+    fn templates_are_filled_with_parameters() {
+        let yaml = r#"
+templates:
+  frpc:
+    exec: frpc
+    args: ["-c", "{{ dir }}/{{id}}.toml"]
+    env: { PORT: "{{port}}", TOKEN: !secret frp_token }
+services: []
+"#;
+        let (file, _) = parse_services_file(yaml).unwrap();
+        let template = &file.templates.unwrap()["frpc"];
+        let params = HashMap::from([
+            ("dir".to_string(), "/etc/frp".to_string()),
+            ("port".to_string(), "7001".to_string()),
+        ]);
+        let config = instantiate_template(template, "frpc-web", &params).unwrap();
+        assert_eq!((config.id.as_str(), config.name.as_str()), ("frpc-web", "frpc-web"));
+        assert_eq!(config.args, ["-c", "/etc/frp/frpc-web.toml"]);
+        let env = config.env.unwrap();
+        assert_eq!((env["PORT"].as_str(), env["TOKEN"].as_str()), ("7001", "!secret frp_token"));
+
+        let err = instantiate_template(template, "frpc-web", &HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Missing template parameter(s): dir, port");
+    }
+
+    #[test]
+    fn auth_token_is_expanded_and_empty_means_open() {
+        let yaml = "auth: { token: 'abc-${APPMANAGER_TEST_NO_SUCH_VAR}' }\nservices: []\n";
+        let (file, _) = parse_services_file(yaml).unwrap();
+        // A token that can't be resolved must not silently open the API
+        assert!(file.auth.unwrap().resolved_token().is_err());
+        let auth = AuthConfig { token: Some("s3cret".into()), ..Default::default() };
+        assert_eq!(auth.resolved_token().unwrap().as_deref(), Some("s3cret"));
+        assert_eq!(AuthConfig { token: Some(String::new()), ..Default::default() }.resolved_token().unwrap(), None);
+    }
+
+    #[test]
+    fn cors_origins_become_cors() {
+        let (file, _) = parse_services_file("cors_origins: ['http://nas:8080']\nservices: []\n").unwrap();
+        let cors = file.cors.unwrap();
+        assert!(file.cors_origins.is_none());
+        assert!(cors.allows("http://nas:8080", "POST"));
+        assert!(!cors.allows("http://evil.example", "GET"));
+        let read_only = CorsConfig { origins: Some(vec!["*".into()]), methods: Some(vec!["get".into()]) };
+        assert!(read_only.allows("http://evil.example", "GET"));
+        assert!(!read_only.allows("http://evil.example", "POST"));
+        assert!(!CorsConfig::default().allows("http://nas:8080", "GET"));
+        let both = "cors_origins: ['http://a']\ncors: { origins: ['http://b'] }\nservices: []\n";
+        assert!(parse_services_file(both).is_err());
+    }
+
+    #[test]
+    fn cmd_is_split_into_exec_and_args() {
+        assert_eq!(
+            split_command_line(r#""C:\Program Files\frp\frpc.exe" -c 'my conf.toml' --name="a \"b\"" x\y """#).unwrap(),
+            [r"C:\Program Files\frp\frpc.exe", "-c", "my conf.toml", r#"--name=a "b""#, r"x\y", ""]
+        );
+        assert!(split_command_line("app 'open").is_err());
+
+        let yaml = "services:\n  - { id: a, name: A, cmd: 'frpc.exe -c frpc.toml' }\n";
+        let (file, _) = parse_services_file(yaml).unwrap();
+        assert_eq!(file.services[0].exec, "frpc.exe");
+        assert_eq!(file.services[0].args, ["-c", "frpc.toml"]);
+        // Written back with cmd only
+        let written = serde_yaml::to_string(&file.services[0].for_file()).unwrap();
+        assert!(written.contains("cmd: frpc.exe -c frpc.toml") && !written.contains("exec:"), "{written}");
+
+        let both = "services:\n  - { id: a, name: A, cmd: 'app -x', exec: app, args: [] }\n";
+        assert!(parse_services_file(both).is_err());
+        assert!(parse_services_file("services:\n  - { id: a, name: A }\n").is_err());
+    }
+}
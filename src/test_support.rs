@@ -0,0 +1,45 @@
+// src/test_support.rs
+
+//! Helpers of the unit tests, shared by the library and the binary
+
+use std::path::{Path, PathBuf};
+
+/// Directory for the files of one test, removed with its content when dropped (also when the test panics)
+pub(crate) struct TestDir(PathBuf);
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("appmanager-test-{}-{}", std::process::id(), name));
+        // Left behind by a killed run of this test
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Copy of a long-running program with a unique name so that name matching
+/// can't see processes of other tests, returns the path, its arguments and the directory to keep until the end
+pub(crate) fn sleeper(name: &str) -> (String, Vec<String>, TestDir) {
+    let dir = TestDir::new(name);
+    #[cfg(unix)]
+    let (source, file, args) = ("/bin/sleep", name.to_string(), vec!["30".to_string()]);
+    #[cfg(windows)]
+    let (source, file, args) = (
+        r"C:\Windows\System32\PING.EXE",
+        format!("{}.exe", name),
+        vec!["-n".to_string(), "30".to_string(), "127.0.0.1".to_string()],
+    );
+    let path = dir.join(file);
+    std::fs::copy(source, &path).unwrap();
+    (path.to_string_lossy().into_owned(), args, dir)
+}
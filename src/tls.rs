@@ -0,0 +1,94 @@
+// src/tls.rs
+
+//! HTTPS for the web server: the certificate of `tls`, created self-signed on first run if asked to
+
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use tracing::info;
+
+use crate::service::TlsConfig;
+
+/// Server config from the certificate and key files, creating them first with self_signed
+/// A created certificate is valid for this machine and the address in `listen`
+pub async fn server_config(tls: &TlsConfig, listen: &str) -> Result<RustlsConfig> {
+    // ring is the only crypto provider built in, rustls wants it installed
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let (cert, key) = (Path::new(&tls.cert), Path::new(&tls.key));
+    if tls.self_signed.unwrap_or(false) && !cert.exists() && !key.exists() {
+        let names = certificate_names(listen);
+        create_self_signed(cert, key, names.clone())?;
+        info!(cert = %cert.display(), names = ?names, "Created a self-signed certificate, browsers will ask to trust it once");
+    }
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .with_context(|| format!("Failed to load the TLS certificate {} and key {}", tls.cert, tls.key))
+}
+
+/// Names a self-signed certificate is valid for: localhost, the host name and the listen address
+/// All addresses of the machine when listening on all of them
+fn certificate_names(listen: &str) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    names.extend(sysinfo::System::host_name());
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let networks = sysinfo::Networks::new_with_refreshed_list();
+            names.extend(networks.values().flat_map(|data| data.ip_networks()).map(|ip| ip.addr.to_string()));
+        }
+        Ok(addr) => names.push(addr.ip().to_string()),
+        // "myhost:3000"
+        Err(_) => names.extend(listen.rsplit_once(':').map(|(host, _)| host.to_string())),
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Write a new certificate and its key, the key readable by the owner only
+fn create_self_signed(cert: &Path, key: &Path, names: Vec<String>) -> Result<()> {
+    let certified = rcgen::generate_simple_self_signed(names).context("Failed to create a self-signed certificate")?;
+    for path in [cert, key] {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(key)
+        .and_then(|mut file| file.write_all(certified.signing_key.serialize_pem().as_bytes()))
+        .with_context(|| format!("Failed to write {}", key.display()))?;
+    std::fs::write(cert, certified.cert.pem()).with_context(|| format!("Failed to write {}", cert.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDir;
+
+    #[tokio::test]
+    async fn self_signed_certificate_is_created_once() {
+        let dir = TestDir::new("tls");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let tls = TlsConfig { cert: path("cert.pem"), key: path("key.pem"), self_signed: Some(true) };
+        server_config(&tls, "127.0.0.1:3000").await.unwrap();
+        let created = std::fs::read(&tls.cert).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&tls.key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // Kept on the next start
+        server_config(&tls, "127.0.0.1:3000").await.unwrap();
+        assert_eq!(std::fs::read(&tls.cert).unwrap(), created);
+
+        let missing = TlsConfig { cert: path("missing.pem"), key: path("missing.key"), self_signed: None };
+        assert!(server_config(&missing, "127.0.0.1:3000").await.is_err());
+        assert!(certificate_names("192.168.1.5:3000").contains(&"192.168.1.5".to_string()));
+        assert!(certificate_names("nas.lan:3000").contains(&"nas.lan".to_string()));
+    }
+}
@@ -0,0 +1,160 @@
+// src/watch.rs
+
+//! Restart services when files they list in `watch` change, like nodemon for any service
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::manager::{LaunchPlan, ServiceManager};
+use crate::service::unquote;
+
+/// Changes must stop for this long before the restart, a save or checkout touches many files
+pub const SETTLE: Duration = Duration::from_secs(1);
+
+/// Watched paths of all services, relative ones are taken from the service's working_dir
+pub fn watched_paths(mgr: &ServiceManager) -> Vec<(String, PathBuf)> {
+    let mut paths = Vec::new();
+    for id in &mgr.service_order {
+        let Some(config) = mgr.services.get(id).map(|svc| &svc.config) else { continue };
+        let Some(watch) = &config.watch else { continue };
+        let working_dir = LaunchPlan::resolve(config).ok().and_then(|plan| plan.working_dir);
+        for entry in watch {
+            let path = Path::new(unquote(entry));
+            let path = match &working_dir {
+                Some(dir) if path.is_relative() => Path::new(dir).join(path),
+                _ => path.to_path_buf(),
+            };
+            paths.push((id.clone(), path));
+        }
+    }
+    paths
+}
+
+/// File system watcher for the `watch` paths of the services
+pub struct PathWatcher {
+    watcher: Option<RecommendedWatcher>,
+    watched: Vec<(String, PathBuf)>,
+    tx: mpsc::UnboundedSender<PathBuf>,
+    rx: mpsc::UnboundedReceiver<PathBuf>,
+    /// Services with changes since the last restart, and when the last one came
+    pending: HashSet<String>,
+    last_change: Option<Instant>,
+}
+impl Default for PathWatcher {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self { watcher: None, watched: Vec::new(), tx, rx, pending: HashSet::new(), last_change: None }
+    }
+}
+impl PathWatcher {
+    /// Watch these paths, the watcher is only rebuilt when they changed
+    /// Directories are watched with their contents, files through their directory (editors replace them)
+    pub fn sync(&mut self, wanted: Vec<(String, PathBuf)>) {
+        if wanted == self.watched {
+            return;
+        }
+        self.watched = wanted;
+        self.watcher = None;
+        if self.watched.is_empty() {
+            return;
+        }
+        let tx = self.tx.clone();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            // Reads are not changes
+            if let Ok(event) = res
+                && (event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove())
+            {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+        });
+        let mut watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!(error = %e, "Failed to create the file watcher, watch is ignored");
+                return;
+            }
+        };
+        let mut targets = HashSet::new();
+        for (id, path) in &self.watched {
+            let target = match path.is_dir() {
+                true => (path.clone(), RecursiveMode::Recursive),
+                false => (path.parent().unwrap_or(Path::new(".")).to_path_buf(), RecursiveMode::NonRecursive),
+            };
+            if targets.insert(target.clone())
+                && let Err(e) = watcher.watch(&target.0, target.1)
+            {
+                warn!(service = %id, path = %path.display(), error = %e, "Failed to watch path");
+            }
+        }
+        self.watcher = Some(watcher);
+    }
+    /// Services a changed path belongs to
+    fn services_for(&self, changed: &Path) -> impl Iterator<Item = &String> {
+        self.watched.iter().filter(move |(_, path)| changed.starts_with(path)).map(|(id, _)| id)
+    }
+    /// Wait for the next change of a watched path (cancel safe)
+    pub async fn recv(&mut self) {
+        let Some(changed) = self.rx.recv().await else {
+            return std::future::pending().await;
+        };
+        let ids: Vec<String> = self.services_for(&changed).cloned().collect();
+        if !ids.is_empty() {
+            self.pending.extend(ids);
+            self.last_change = Some(Instant::now());
+        }
+    }
+    /// Services whose changes have settled, to be restarted now
+    pub fn take_settled(&mut self) -> Vec<String> {
+        match self.last_change {
+            Some(at) if at.elapsed() >= SETTLE => {
+                self.last_change = None;
+                self.pending.drain().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDir;
+
+    #[tokio::test]
+    async fn changes_below_watched_paths_are_reported_per_service() {
+        let dir = TestDir::new("watch");
+        std::fs::create_dir_all(dir.join("conf")).unwrap();
+        std::fs::write(dir.join("app.toml"), "a").unwrap();
+        let services = serde_json::from_value(serde_json::json!([
+            {"id": "app", "name": "app", "exec": "app", "args": [], "working_dir": &*dir, "watch": ["app.toml"]},
+            {"id": "web", "name": "web", "exec": "web", "args": [], "working_dir": &*dir, "watch": ["conf"]},
+        ]))
+        .unwrap();
+        let file = crate::service::ServicesFile { services, ..Default::default() };
+        let mgr = ServiceManager::from_config(file, None).unwrap();
+        let paths = watched_paths(&mgr);
+        assert_eq!(paths[0], ("app".to_string(), dir.join("app.toml")));
+
+        let mut watcher = PathWatcher::default();
+        watcher.sync(paths);
+        assert!(watcher.services_for(&dir.join("other.txt")).next().is_none());
+        std::fs::write(dir.join("conf").join("site.conf"), "b").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while watcher.pending.is_empty() {
+                watcher.recv().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(watcher.take_settled().is_empty());
+        watcher.last_change = Some(Instant::now() - SETTLE);
+        assert_eq!(watcher.take_settled(), ["web"]);
+    }
+}